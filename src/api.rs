@@ -0,0 +1,302 @@
+use std::{fmt::Write, path::Path, sync::Arc};
+
+use axum::{
+    Json, Router,
+    extract::{Path as UrlPath, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde_json::json;
+use tokio::{net::TcpListener, sync::RwLock};
+use tracing::{error, info};
+
+use crate::{
+    cli::{branch_status, project_status},
+    config::Config,
+    database_operator::{DatabaseOperator, EngineOperator},
+    error::AppError,
+    fiemap::get_folder_size_ignoring,
+    metrics::ProxyMetrics,
+};
+
+#[derive(Clone)]
+struct ApiState {
+    config: Arc<RwLock<Config>>,
+    metrics: Arc<ProxyMetrics>,
+}
+
+/// Serves the read-only HTTP API on `api_port` until the process exits.
+pub async fn serve(
+    config: Arc<RwLock<Config>>,
+    metrics: Arc<ProxyMetrics>,
+) -> Result<(), AppError> {
+    let addr = config.read().await.api_addr()?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| AppError::Network {
+            message: format!("Failed to bind the API to {}: {}", addr, e),
+        })?;
+    info!("📈 API listening on: {}", addr);
+
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/status", get(status_handler))
+        .route("/branches", get(branches_handler))
+        .route("/branches/{name}", get(branch_handler))
+        .with_state(ApiState { config, metrics });
+
+    axum::serve(listener, app)
+        .await
+        .map_err(|e| AppError::Network {
+            message: format!("API server failed: {}", e),
+        })
+}
+
+/// `status --json` of the project.
+async fn status_handler(State(state): State<ApiState>) -> Response {
+    let config = state.config.read().await.clone();
+    match project_status(&config).await {
+        Ok(status) => Json(status).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn branches_handler(State(state): State<ApiState>) -> Response {
+    let config = state.config.read().await.clone();
+    match project_status(&config).await {
+        Ok(status) => Json(status.branches).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn branch_handler(State(state): State<ApiState>, UrlPath(name): UrlPath<String>) -> Response {
+    let config = state.config.read().await.clone();
+    let Some(branch) = config.branches.iter().find(|b| b.name == name) else {
+        return error_response(AppError::BranchNotFound { name });
+    };
+    match branch_status(&config, branch).await {
+        Ok(status) => Json(status).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+fn error_response(e: AppError) -> Response {
+    let status = match e {
+        AppError::BranchNotFound { .. } => StatusCode::NOT_FOUND,
+        _ => {
+            error!("API request failed: {}", e.chain());
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(json!({ "error": e.chain() }))).into_response()
+}
+
+/// Gauges sampled from one branch at scrape time.
+struct BranchSample {
+    name: String,
+    running: bool,
+    logical_size: Option<u64>,
+    unique_size: Option<u64>,
+}
+
+async fn metrics_handler(State(state): State<ApiState>) -> impl IntoResponse {
+    let config = state.config.read().await.clone();
+
+    let operator = EngineOperator::for_engine(&config.engine);
+    let mut running = Vec::new();
+    for branch in &config.branches {
+        running.push(
+            operator
+                .is_container_running(&config.container_name(&branch.name))
+                .await
+                .unwrap_or(false),
+        );
+    }
+
+    // Walking the extents of every branch is blocking work
+    let samples = tokio::task::spawn_blocking(move || {
+        config
+            .branches
+            .iter()
+            .zip(running)
+            .map(|(branch, running)| {
+                let info = get_folder_size_ignoring(
+                    &Path::new(&config.mount_point)
+                        .join(&config.name)
+                        .join(&branch.name),
+                    &config.ignore_in_sizing,
+                )
+                .inspect_err(|e| error!("Failed to measure branch {}: {}", branch.name, e.chain()))
+                .ok();
+                BranchSample {
+                    name: branch.name.clone(),
+                    running,
+                    logical_size: info.as_ref().map(|i| i.logical_size),
+                    unique_size: info.as_ref().map(|i| i.unique_size()),
+                }
+            })
+            .collect::<Vec<_>>()
+    })
+    .await;
+
+    match samples {
+        Ok(samples) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            render(&state.metrics, &samples),
+        ),
+        Err(e) => {
+            error!("Failed to gather branch sizes: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                String::new(),
+            )
+        }
+    }
+}
+
+/// Renders the metrics in the Prometheus text exposition format.
+fn render(metrics: &ProxyMetrics, branches: &[BranchSample]) -> String {
+    let mut out = String::new();
+
+    let mut family = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    family(
+        "dbranch_proxy_active_connections",
+        "gauge",
+        "Connections currently proxied.",
+        &[(String::new(), metrics.active_connections())],
+    );
+    family(
+        "dbranch_proxy_connections_total",
+        "counter",
+        "Connections accepted by the proxy.",
+        &[(String::new(), metrics.total_connections())],
+    );
+
+    let mut bytes = Vec::new();
+    for (branch, sent, received) in metrics.branch_bytes() {
+        bytes.push((branch_labels(&branch, Some("client_to_server")), sent));
+        bytes.push((branch_labels(&branch, Some("server_to_client")), received));
+    }
+    family(
+        "dbranch_proxy_bytes_total",
+        "counter",
+        "Bytes proxied by branch and direction, counted when connections close.",
+        &bytes,
+    );
+
+    family(
+        "dbranch_containers_running",
+        "gauge",
+        "Branch containers currently running.",
+        &[(
+            String::new(),
+            branches.iter().filter(|b| b.running).count() as u64,
+        )],
+    );
+    family(
+        "dbranch_branch_running",
+        "gauge",
+        "Whether the container of a branch is running.",
+        &branches
+            .iter()
+            .map(|b| (branch_labels(&b.name, None), b.running as u64))
+            .collect::<Vec<_>>(),
+    );
+    family(
+        "dbranch_branch_logical_size_bytes",
+        "gauge",
+        "Logical size of a branch's data directory.",
+        &branches
+            .iter()
+            .filter_map(|b| Some((branch_labels(&b.name, None), b.logical_size?)))
+            .collect::<Vec<_>>(),
+    );
+    family(
+        "dbranch_branch_unique_size_bytes",
+        "gauge",
+        "Bytes of a branch's data not shared with other branches.",
+        &branches
+            .iter()
+            .filter_map(|b| Some((branch_labels(&b.name, None), b.unique_size?)))
+            .collect::<Vec<_>>(),
+    );
+
+    out
+}
+
+fn branch_labels(branch: &str, direction: Option<&str>) -> String {
+    let branch = branch.replace('\\', "\\\\").replace('"', "\\\"");
+    match direction {
+        Some(direction) => format!("{{branch=\"{}\",direction=\"{}\"}}", branch, direction),
+        None => format!("{{branch=\"{}\"}}", branch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unknown_branch_is_not_found() {
+        let state = ApiState {
+            config: Arc::new(RwLock::new(Config::new("project".to_string()))),
+            metrics: Arc::default(),
+        };
+
+        let response = branch_handler(State(state), UrlPath("nope".to_string())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(ProxyMetrics::default());
+        let _active = metrics.connection();
+        metrics.record("main", 10, 200);
+
+        let out = render(
+            &metrics,
+            &[
+                BranchSample {
+                    name: "main".to_string(),
+                    running: true,
+                    logical_size: Some(4096),
+                    unique_size: Some(0),
+                },
+                BranchSample {
+                    name: "feature".to_string(),
+                    running: false,
+                    logical_size: None,
+                    unique_size: None,
+                },
+            ],
+        );
+
+        for line in [
+            "# TYPE dbranch_proxy_active_connections gauge",
+            "dbranch_proxy_active_connections 1",
+            "dbranch_proxy_bytes_total{branch=\"main\",direction=\"client_to_server\"} 10",
+            "dbranch_proxy_bytes_total{branch=\"main\",direction=\"server_to_client\"} 200",
+            "dbranch_containers_running 1",
+            "dbranch_branch_running{branch=\"feature\"} 0",
+            "dbranch_branch_logical_size_bytes{branch=\"main\"} 4096",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                out
+            );
+        }
+        assert!(!out.contains("size_bytes{branch=\"feature\"}"));
+    }
+}
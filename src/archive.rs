@@ -0,0 +1,595 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use docker_wrapper::{DockerCommand, ExecCommand};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    config::{Approach, Config, Engine},
+    error::AppError,
+    lockfile::ContentHasher,
+};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes the artifacts written by `dbranch export`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub project: String,
+    pub engine: Engine,
+    pub approach: Approach,
+    pub exported_at: DateTime<Utc>,
+    pub branches: Vec<ManifestBranch>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestBranch {
+    pub name: String,
+    pub port: u16,
+    pub is_main: bool,
+    /// Branch or URL this one was created from, as recorded in the lockfile
+    /// (`None` for main and branches without provenance)
+    pub parent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// File name of the artifact, relative to the export directory
+    pub artifact: String,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> Result<Self, AppError> {
+        let path = dir.join(MANIFEST_FILE);
+        let content = fs::read_to_string(&path).map_err(|_| AppError::FileNotFound {
+            path: path.to_string_lossy().to_string(),
+        })?;
+
+        serde_json::from_str(&content).map_err(|e| AppError::ConfigParsing {
+            message: format!("Failed to parse manifest {:?}: {}", path, e),
+        })
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), AppError> {
+        let path = dir.join(MANIFEST_FILE);
+        let file = File::create(&path).map_err(|e| AppError::FileSystem {
+            message: format!("Failed to create manifest {:?}", path),
+            source: Some(e.into()),
+        })?;
+
+        serde_json::to_writer_pretty(file, self).map_err(|e| AppError::FileSystem {
+            message: format!("Failed to write manifest {:?}", path),
+            source: Some(e.into()),
+        })
+    }
+}
+
+/// File name of a branch artifact: a btrfs send stream for `NewDisk`
+/// projects, a plain SQL dump otherwise.
+pub fn artifact_name(config: &Config, branch: &str) -> String {
+    match config.approach {
+        Approach::NewDisk => format!("{}.btrfs", branch),
+        Approach::ExistingDisk => format!("{}.sql", branch),
+    }
+}
+
+/// Logical dump of the branch database in `format`, a plain SQL script or,
+/// for PostgreSQL only, a `pg_dump -Fc` archive.
+fn dump_command(config: &Config, format: DumpFormat) -> Result<Vec<String>, AppError> {
+    let (user, password) = config.credentials()?;
+
+    Ok(match (format, &config.engine) {
+        (DumpFormat::Sql, Engine::Postgres) => vec![
+            "pg_dump".into(),
+            "-U".into(),
+            user,
+            "-d".into(),
+            config.database_name(),
+            "--no-owner".into(),
+            "--clean".into(),
+            "--if-exists".into(),
+        ],
+        (DumpFormat::Custom, Engine::Postgres) => vec![
+            "pg_dump".into(),
+            "-U".into(),
+            user,
+            "-d".into(),
+            config.database_name(),
+            "--no-owner".into(),
+            "-Fc".into(),
+        ],
+        (DumpFormat::Sql, Engine::Mysql) => vec![
+            "mysqldump".into(),
+            "-u".into(),
+            user,
+            format!("-p{}", password),
+            config.database_name(),
+        ],
+        (DumpFormat::Custom, Engine::Mysql) => {
+            return Err(AppError::Config {
+                message: "Custom-format dumps are only available for PostgreSQL".to_string(),
+            });
+        }
+        (DumpFormat::Gzip, _) => {
+            return Err(AppError::Internal {
+                message: "Dumps are not written gzip-compressed".to_string(),
+            });
+        }
+    })
+}
+
+fn schema_command(config: &Config) -> Result<Vec<String>, AppError> {
+    let (user, password) = config.credentials()?;
+
+    Ok(match config.engine {
+        Engine::Postgres => vec![
+            "pg_dump".into(),
+            "-U".into(),
+            user,
+            "-d".into(),
+            config.database_name(),
+            "--schema-only".into(),
+            "--no-owner".into(),
+        ],
+        Engine::Mysql => vec![
+            "mysqldump".into(),
+            "-u".into(),
+            user,
+            format!("-p{}", password),
+            "--no-data".into(),
+            "--skip-dump-date".into(),
+            config.database_name(),
+        ],
+    })
+}
+
+fn restore_command(config: &Config) -> Result<Vec<String>, AppError> {
+    let (user, password) = config.credentials()?;
+
+    Ok(match config.engine {
+        Engine::Postgres => vec![
+            "psql".into(),
+            "-q".into(),
+            "-v".into(),
+            "ON_ERROR_STOP=1".into(),
+            "-U".into(),
+            user,
+            "-d".into(),
+            config.database_name(),
+        ],
+        Engine::Mysql => vec![
+            "mysql".into(),
+            "-u".into(),
+            user,
+            format!("-p{}", password),
+            config.database_name(),
+        ],
+    })
+}
+
+/// `pg_restore` reading a custom-format dump from stdin.
+fn pg_restore_command(config: &Config) -> Result<Vec<String>, AppError> {
+    let (user, _) = config.credentials()?;
+
+    Ok(vec![
+        "pg_restore".into(),
+        "--no-owner".into(),
+        "--exit-on-error".into(),
+        "-U".into(),
+        user,
+        "-d".into(),
+        config.database_name(),
+    ])
+}
+
+fn ready_command(config: &Config) -> Result<Vec<String>, AppError> {
+    let (user, password) = config.credentials()?;
+
+    Ok(match config.engine {
+        Engine::Postgres => vec![
+            "pg_isready".into(),
+            "-U".into(),
+            user,
+            "-d".into(),
+            config.database_name(),
+        ],
+        Engine::Mysql => vec![
+            "mysqladmin".into(),
+            "ping".into(),
+            "-u".into(),
+            user,
+            format!("-p{}", password),
+        ],
+    })
+}
+
+// docker-wrapper buffers output into a String, which does not suit dumps of
+// arbitrary size, so these stream through the docker CLI directly.
+
+/// Dumps the branch database into `dest`. The branch container must be running.
+pub fn dump_branch(config: &Config, branch: &str, dest: &Path) -> Result<(), AppError> {
+    let file = File::create(dest).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to create dump file {:?}", dest),
+        source: Some(e.into()),
+    })?;
+
+    dump_branch_to(config, branch, DumpFormat::Sql, Stdio::from(file))
+}
+
+/// Dumps the branch database in `format` into `out`, a file or the inherited
+/// stdout. The branch container must be running.
+pub fn dump_branch_to(
+    config: &Config,
+    branch: &str,
+    format: DumpFormat,
+    out: Stdio,
+) -> Result<(), AppError> {
+    let config = &config.for_branch(branch)?;
+    let container_name = config.container_name(branch);
+    debug!("Dumping {} as {:?}", container_name, format);
+
+    let output = Command::new("docker")
+        .arg("exec")
+        .arg(&container_name)
+        .args(dump_command(config, format)?)
+        .stdout(out)
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| AppError::Docker {
+            message: format!("Failed to run dump in {}", container_name),
+            source: Some(e.into()),
+        })?;
+
+    if !output.status.success() {
+        return Err(AppError::Database {
+            message: format!(
+                "Dump of {} failed: {}",
+                container_name,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            source: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// DDL of the branch database, without the lines that differ on every dump.
+/// The branch container must be running.
+pub fn dump_schema(config: &Config, branch: &str) -> Result<String, AppError> {
+    let config = &config.for_branch(branch)?;
+    let container_name = config.container_name(branch);
+    debug!("Dumping the schema of {}", container_name);
+
+    let output = Command::new("docker")
+        .arg("exec")
+        .arg(&container_name)
+        .args(schema_command(config)?)
+        .output()
+        .map_err(|e| AppError::Docker {
+            message: format!("Failed to run schema dump in {}", container_name),
+            source: Some(e.into()),
+        })?;
+
+    if !output.status.success() {
+        return Err(AppError::Database {
+            message: format!(
+                "Schema dump of {} failed: {}",
+                container_name,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            source: None,
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.starts_with("\\restrict") && !line.starts_with("\\unrestrict"))
+        .map(|line| format!("{}\n", line))
+        .collect())
+}
+
+/// Kind of dump file, as told by its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Plain SQL script
+    Sql,
+    /// `pg_dump -Fc` archive
+    Custom,
+    /// Gzip-compressed, format of the content unknown until unpacked
+    Gzip,
+}
+
+pub fn detect_format(path: &Path) -> Result<DumpFormat, AppError> {
+    let mut file = File::open(path).map_err(|_| AppError::FileNotFound {
+        path: path.to_string_lossy().to_string(),
+    })?;
+
+    let mut magic = [0u8; 5];
+    let read = file.read(&mut magic).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to read {:?}", path),
+        source: Some(e.into()),
+    })?;
+
+    Ok(match &magic[..read] {
+        [0x1f, 0x8b, ..] => DumpFormat::Gzip,
+        b"PGDMP" => DumpFormat::Custom,
+        _ => DumpFormat::Sql,
+    })
+}
+
+/// Downloads `url` into `dest` with curl, failing on HTTP errors.
+pub fn download(url: &str, dest: &Path) -> Result<(), AppError> {
+    debug!("Downloading {} into {:?}", url, dest);
+
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--output",
+        ])
+        .arg(dest)
+        .arg(url)
+        .output()
+        .map_err(|e| AppError::Network {
+            message: format!("Failed to run curl for {} (is it installed?): {}", url, e),
+        })?;
+
+    if !output.status.success() {
+        return Err(AppError::Network {
+            message: format!(
+                "Download of {} failed: {}",
+                url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+/// Decompresses the gzip file `src` into `dest`.
+pub fn gunzip(src: &Path, dest: &Path) -> Result<(), AppError> {
+    debug!("Decompressing {:?} into {:?}", src, dest);
+
+    let file = File::create(dest).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to create {:?}", dest),
+        source: Some(e.into()),
+    })?;
+
+    let output = Command::new("gzip")
+        .arg("--decompress")
+        .arg("--stdout")
+        .arg(src)
+        .stdout(Stdio::from(file))
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| AppError::FileSystem {
+            message: format!("Failed to run gzip on {:?}", src),
+            source: Some(e.into()),
+        })?;
+
+    if !output.status.success() {
+        return Err(AppError::FileSystem {
+            message: format!(
+                "Failed to decompress {:?}: {}",
+                src,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            source: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Replays a dump produced by `dump_branch` into the branch database.
+pub fn restore_branch(config: &Config, branch: &str, src: &Path) -> Result<(), AppError> {
+    restore_dump(config, branch, src, DumpFormat::Sql)
+}
+
+/// Replays a plain SQL or custom-format dump into the branch database.
+pub fn restore_dump(
+    config: &Config,
+    branch: &str,
+    src: &Path,
+    format: DumpFormat,
+) -> Result<(), AppError> {
+    let config = &config.for_branch(branch)?;
+    let container_name = config.container_name(branch);
+    debug!("Restoring {:?} ({:?}) into {}", src, format, container_name);
+
+    let command = match (format, &config.engine) {
+        (DumpFormat::Sql, _) => restore_command(config)?,
+        (DumpFormat::Custom, Engine::Postgres) => pg_restore_command(config)?,
+        (DumpFormat::Custom, Engine::Mysql) => {
+            return Err(AppError::Config {
+                message: "Custom-format dumps can only be restored into PostgreSQL".to_string(),
+            });
+        }
+        (DumpFormat::Gzip, _) => {
+            return Err(AppError::Internal {
+                message: format!("{:?} must be decompressed before restoring", src),
+            });
+        }
+    };
+
+    let file = File::open(src).map_err(|_| AppError::FileNotFound {
+        path: src.to_string_lossy().to_string(),
+    })?;
+
+    let output = Command::new("docker")
+        .arg("exec")
+        .arg("-i")
+        .arg(&container_name)
+        .args(command)
+        .stdin(Stdio::from(file))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| AppError::Docker {
+            message: format!("Failed to run restore in {}", container_name),
+            source: Some(e.into()),
+        })?;
+
+    if !output.status.success() {
+        return Err(AppError::Database {
+            message: format!(
+                "Restore into {} failed: {}",
+                container_name,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            source: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Hashes the logical dump of the branch, so that two branches holding the
+/// same data hash equally regardless of their on-disk layout. The branch
+/// container must be running.
+pub fn hash_branch(config: &Config, branch: &str) -> Result<String, AppError> {
+    let config = &config.for_branch(branch)?;
+    let container_name = config.container_name(branch);
+    debug!("Hashing the content of {}", container_name);
+
+    let mut child = Command::new("docker")
+        .arg("exec")
+        .arg(&container_name)
+        .args(dump_command(config, DumpFormat::Sql)?)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| AppError::Docker {
+            message: format!("Failed to run dump in {}", container_name),
+            source: Some(e.into()),
+        })?;
+
+    let mut hasher = ContentHasher::new();
+    let mut reader = BufReader::new(child.stdout.take().unwrap());
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to read dump of {}", container_name),
+                source: Some(e.into()),
+            })?;
+        if read == 0 {
+            break;
+        }
+
+        // Comments carry versions and `\restrict` a random per-dump key
+        if line.starts_with(b"--")
+            || line.starts_with(b"\\restrict")
+            || line.starts_with(b"\\unrestrict")
+        {
+            continue;
+        }
+        hasher.update(&line);
+    }
+
+    let output = child.wait_with_output().map_err(|e| AppError::Docker {
+        message: format!("Failed to run dump in {}", container_name),
+        source: Some(e.into()),
+    })?;
+
+    if !output.status.success() {
+        return Err(AppError::Database {
+            message: format!(
+                "Dump of {} failed: {}",
+                container_name,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            source: None,
+        });
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Polls the branch container until the database accepts connections.
+pub async fn wait_until_ready(
+    config: &Config,
+    branch: &str,
+    timeout: Duration,
+) -> Result<(), AppError> {
+    let config = &config.for_branch(branch)?;
+    let container_name = config.container_name(branch);
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let ready = ExecCommand::new(container_name.clone(), ready_command(config)?)
+            .execute()
+            .await
+            .map(|output| output.success())
+            .unwrap_or(false);
+
+        if ready {
+            debug!("{} is ready", container_name);
+            return Ok(());
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return Err(AppError::Database {
+                message: format!(
+                    "{} was not ready after {}s",
+                    container_name,
+                    timeout.as_secs()
+                ),
+                source: None,
+            });
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        let dir = std::env::temp_dir().join(format!("dbranch-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let cases: [(&str, &[u8], DumpFormat); 4] = [
+            ("plain.sql", b"CREATE TABLE t (id int);", DumpFormat::Sql),
+            ("custom.dump", b"PGDMP\x01\x0e", DumpFormat::Custom),
+            ("plain.sql.gz", &[0x1f, 0x8b, 0x08, 0x00], DumpFormat::Gzip),
+            ("empty.sql", b"", DumpFormat::Sql),
+        ];
+        for (name, content, expected) in cases {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            assert_eq!(detect_format(&path).unwrap(), expected, "{}", name);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dump_command_formats() {
+        let mut config = Config::new("project".to_string());
+        config.postgres_config.as_mut().unwrap().password = "secret".to_string();
+
+        let plain = dump_command(&config, DumpFormat::Sql).unwrap();
+        assert_eq!(plain[0], "pg_dump");
+        assert!(plain.contains(&"--clean".to_string()));
+        let custom = dump_command(&config, DumpFormat::Custom).unwrap();
+        assert_eq!(custom.last().unwrap(), "-Fc");
+
+        config.engine = Engine::Mysql;
+        assert!(dump_command(&config, DumpFormat::Sql).is_ok());
+        assert!(dump_command(&config, DumpFormat::Custom).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    config::{Config, config_path},
+    error::AppError,
+};
+
+pub const AUDIT_LOG: &str = ".dbranch.audit.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Created,
+    Attached,
+    Used,
+    Stopped,
+    Detached,
+    Deleted,
+    Reset,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Operation::Created => "created",
+            Operation::Attached => "attached",
+            Operation::Used => "used",
+            Operation::Stopped => "stopped",
+            Operation::Detached => "detached",
+            Operation::Deleted => "deleted",
+            Operation::Reset => "reset",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub at: DateTime<Utc>,
+    pub project: String,
+    pub branch: String,
+    pub operation: Operation,
+}
+
+/// The audit log lives next to the config file.
+pub fn audit_path() -> PathBuf {
+    config_path()
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(AUDIT_LOG)
+}
+
+/// Appends an operation to the audit log. Failing to write it never fails
+/// the operation itself.
+pub fn record(config: &Config, branch: &str, operation: Operation) {
+    if config.dry_run {
+        return;
+    }
+
+    let entry = AuditRecord {
+        at: Utc::now(),
+        project: config.name.clone(),
+        branch: branch.to_string(),
+        operation,
+    };
+
+    if let Err(e) = append(&audit_path(), &entry) {
+        debug!("Failed to write audit record: {}", e.chain());
+    }
+}
+
+fn append(path: &Path, entry: &AuditRecord) -> Result<(), AppError> {
+    let mut line = serde_json::to_string(entry).map_err(|e| AppError::Internal {
+        message: format!("Failed to serialize audit record: {}", e),
+    })?;
+    line.push('\n');
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| AppError::FileSystem {
+            message: format!("Failed to append to audit log {:?}", path),
+            source: Some(e.into()),
+        })
+}
+
+/// Reads every record of the audit log, oldest first. Lines that do not
+/// parse are skipped.
+pub fn load() -> Result<Vec<AuditRecord>, AppError> {
+    let path = audit_path();
+    debug!("Loading audit log from {:?}", path);
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(_) => {
+            debug!("Audit log doesn't exist yet");
+            return Ok(Vec::new());
+        }
+    };
+
+    let mut records: Vec<AuditRecord> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                debug!("Skipping malformed audit record: {}", e);
+                None
+            }
+        })
+        .collect();
+    records.sort_by_key(|r| r.at);
+
+    Ok(records)
+}
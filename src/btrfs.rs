@@ -1,5 +1,5 @@
 use crate::cli::Project;
-use crate::config::Config;
+use crate::config::{Approach, Config, compression_algorithm, skip_for_dry_run};
 use crate::error;
 use crate::error::AppError;
 use anyhow::Result;
@@ -12,13 +12,15 @@ use std::path::PathBuf;
 
 use tracing::debug;
 use tracing::info;
+use tracing::warn;
 
 #[derive(Debug, Clone)]
 pub struct SubvolumeInfo {
     pub name: String,
     pub path: String,
     pub referenced_size: u64,
-    pub exclusive_size: u64,
+    /// Unknown without qgroups, as `du` can't tell shared extents apart
+    pub exclusive_size: Option<u64>,
 }
 
 fn find_device_by_path(input: &str, target_path: &str) -> Option<String> {
@@ -41,25 +43,157 @@ fn find_device_by_path(input: &str, target_path: &str) -> Option<String> {
     None
 }
 
+/// Subvolume ID in the output of `btrfs subvolume show`.
+fn parse_subvolume_id(output: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("Subvolume ID:")
+            .and_then(|id| id.trim().parse().ok())
+    })
+}
+
+/// Referenced and exclusive bytes of subvolume `id` in the output of
+/// `btrfs qgroup show --raw`, whose rows start with the `0/<id>` qgroup.
+fn parse_qgroup_sizes(output: &str, id: u64) -> Option<(u64, u64)> {
+    let qgroup = format!("0/{}", id);
+    output.lines().find_map(|line| {
+        let mut columns = line.split_whitespace();
+        if columns.next()? != qgroup {
+            return None;
+        }
+        let referenced = columns.next()?.parse().ok()?;
+        let exclusive = columns.next()?.parse().ok()?;
+        Some((referenced, exclusive))
+    })
+}
+
+/// Name of the read-only snapshot used to send/receive `subvolume_name`.
+fn send_name(subvolume_name: &str) -> String {
+    format!(".{}.send", subvolume_name)
+}
+
+/// Name of the subvolume holding snapshot `name` of `branch`.
+fn named_snapshot_name(branch: &str, name: &str) -> String {
+    format!("{}@{}", branch, name)
+}
+
+fn run_btrfs(args: &[&str]) -> Result<(), AppError> {
+    debug!("Running command: sudo btrfs {}", args.join(" "));
+
+    let output = std::process::Command::new("sudo")
+        .arg("btrfs")
+        .args(args)
+        .output()
+        .map_err(|e| AppError::Btrfs {
+            message: format!("Failed to run btrfs {}", args.join(" ")),
+            source: Some(e.into()),
+        })?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(AppError::Btrfs {
+            message: format!(
+                "btrfs {} failed: stderr={} stdout={}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr),
+                String::from_utf8_lossy(&output.stdout)
+            ),
+            source: None,
+        })
+    }
+}
+
+/// Where the filesystem of project `name` lives under the config's
+/// `mount_point`; branches are the subvolumes right below it.
+pub fn project_mount_point(mount_point: &str, name: &str) -> String {
+    format!("{}/{}", mount_point, name)
+}
+
+/// Where `move` puts the image of NewDisk project `name`, beside the
+/// directory it is mounted at under `mount_point`.
+pub fn moved_image_path(mount_point: &str, name: &str) -> PathBuf {
+    Path::new(mount_point).join(format!("{}.btrfs.img", name))
+}
+
+/// Renames the image `from` to `to`, or copies it keeping its holes and
+/// removes the original when they are on different filesystems.
+fn move_image(from: &Path, to: &Path) -> Result<(), AppError> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            debug!("Copying {:?} to {:?} across filesystems", from, to);
+        }
+        result => {
+            return result.map_err(|e| AppError::FileSystem {
+                message: format!("Failed to move {:?} to {:?}", from, to),
+                source: Some(e.into()),
+            });
+        }
+    }
+
+    let output = std::process::Command::new("cp")
+        .arg("--sparse=always")
+        .arg(from)
+        .arg(to)
+        .output()
+        .map_err(|e| AppError::FileSystem {
+            message: format!("Failed to copy {:?} to {:?}", from, to),
+            source: Some(e.into()),
+        })?;
+    if !output.status.success() {
+        let _ = fs::remove_file(to);
+        return Err(AppError::FileSystem {
+            message: format!(
+                "Failed to copy {:?} to {:?}: {}",
+                from,
+                to,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            source: None,
+        });
+    }
+
+    fs::remove_file(from).map_err(|e| AppError::FileSystem {
+        message: format!("Copied to {:?} but failed to remove {:?}", to, from),
+        source: Some(e.into()),
+    })
+}
+
 #[derive(Debug)]
 pub struct BtrfsOperator {
+    project_name: String,
     // Img file path (e.g., /path/to/project/btrfs.img)
     img_path: PathBuf,
     // Mount point for the cow like filesystem (e.g., /mnt/projects/project_name)
     mount_point: String,
     size: u64,
+    // ExistingDisk uses `mount_point` on an already mounted Btrfs filesystem
+    // instead of a loop-mounted image
+    approach: Approach,
+    // `btrfs_compression` of the config, e.g. "zstd:3" or "none"
+    compression: String,
+    // Set by `--dry-run`: log the operations instead of running them
+    dry_run: bool,
 }
 
 impl BtrfsOperator {
     pub fn new(project: Project, config: Config) -> Self {
         let project_name = project.name.clone();
 
-        let project_mount_point = format!("{}/{}", config.mount_point, project_name);
+        let project_mount_point = project_mount_point(&config.mount_point, &project_name);
 
         Self {
-            img_path: project.path.join("btrfs.img"),
+            project_name,
+            img_path: config
+                .image_path
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| project.path.join("btrfs.img")),
             mount_point: project_mount_point.clone(),
-            size: 1 * 1024 * 1024 * 1024 * 1024, // 1TB per project (adjustable)
+            size: config.image_size,
+            approach: config.approach.clone(),
+            compression: config.btrfs_compression.clone(),
+            dry_run: config.dry_run,
         }
     }
 
@@ -107,7 +241,18 @@ impl BtrfsOperator {
     }
 
     pub fn reserve_space(&self) -> Result<()> {
+        if self.approach == Approach::ExistingDisk {
+            debug!("ExistingDisk approach, no image to reserve");
+            return Ok(());
+        }
         info!("Reserving disk space of {} bytes for image", self.size);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("reserve {} bytes at {:?}", self.size, self.img_path),
+        ) {
+            return Ok(());
+        }
         debug!("Image path: {:?}", self.img_path);
 
         match fs::create_dir(Path::new(self.img_path.parent().unwrap().as_os_str())) {
@@ -135,17 +280,61 @@ impl BtrfsOperator {
         Ok(())
     }
 
-    pub fn delete_img(&self) -> Result<()> {
+    pub fn image_path(&self) -> &Path {
+        &self.img_path
+    }
+
+    /// Whether the image file of a NewDisk project exists.
+    pub fn has_image(&self) -> bool {
+        self.img_path.exists()
+    }
+
+    pub fn delete_img(&self) -> Result<(), error::AppError> {
         info!("Releasing disk space for image at {:?}", self.img_path);
-        let file = File::options().write(true).open(&self.img_path).unwrap();
-        file.set_len(0).unwrap();
-        fs::remove_file(&self.img_path)?;
+
+        if !self.img_path.exists() {
+            return Err(AppError::FileNotFound {
+                path: self.img_path.to_string_lossy().to_string(),
+            });
+        }
+
+        if skip_for_dry_run(self.dry_run, &format!("delete image {:?}", self.img_path)) {
+            return Ok(());
+        }
+        let file = File::options()
+            .write(true)
+            .open(&self.img_path)
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to open disk image {:?}", self.img_path),
+                source: Some(e.into()),
+            })?;
+        file.set_len(0).map_err(|e| AppError::FileSystem {
+            message: format!("Failed to truncate disk image {:?}", self.img_path),
+            source: Some(e.into()),
+        })?;
+        fs::remove_file(&self.img_path).map_err(|e| AppError::FileSystem {
+            message: format!("Failed to remove disk image {:?}", self.img_path),
+            source: Some(e.into()),
+        })?;
         debug!("Disk space released successfully");
         Ok(())
     }
 
     pub fn mount_disk(&mut self) -> Result<(), error::AppError> {
+        if self.approach == Approach::ExistingDisk {
+            return self.use_existing_disk();
+        }
         info!("Starting disk mount process for {:?}", self.img_path);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!(
+                "format and mount {:?} at {}",
+                self.img_path, self.mount_point
+            ),
+        ) {
+            return Ok(());
+        }
         Self::prompt_sudo_password().unwrap();
 
         debug!("Creating loop device for image");
@@ -160,6 +349,7 @@ impl BtrfsOperator {
                     "Failed to create loop device: {}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
 
@@ -178,6 +368,7 @@ impl BtrfsOperator {
                     "Failed to format loop device as Btrfs: {}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
 
@@ -193,13 +384,16 @@ impl BtrfsOperator {
                     "Failed to create mount point directory: {}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
         debug!("Mount point directory created successfully");
 
         debug!("Mounting {} to {}", loop_device, self.mount_point);
         let output = std::process::Command::new("sudo")
-            .args(&["mount", &loop_device, self.mount_point.as_str()])
+            .arg("mount")
+            .args(self.mount_options())
+            .args([&loop_device, self.mount_point.as_str()])
             .output()
             .unwrap();
 
@@ -209,10 +403,90 @@ impl BtrfsOperator {
                     "Failed to mount loop device: {}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
+            });
+        }
+
+        self.enable_quotas();
+        self.create_main_subvolume()?;
+
+        info!(
+            "Successfully mounted disk at {} with main subvolume",
+            self.mount_point
+        );
+        Ok(())
+    }
+
+    /// Prepares `mount_point` on an already mounted Btrfs filesystem: the
+    /// directory is created if needed and must live on Btrfs.
+    fn use_existing_disk(&self) -> Result<(), error::AppError> {
+        info!("Using existing Btrfs filesystem at {}", self.mount_point);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("create main subvolume in {}", self.mount_point),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        let output = std::process::Command::new("sudo")
+            .args(["mkdir", "-p", self.mount_point.as_str()])
+            .output()
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to create {}", self.mount_point),
+                source: Some(e.into()),
+            })?;
+        if !output.status.success() {
+            return Err(AppError::FileSystem {
+                message: format!(
+                    "Failed to create {}: {}",
+                    self.mount_point,
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                source: None,
+            });
+        }
+
+        let output = std::process::Command::new("stat")
+            .args(["-f", "-c", "%T", self.mount_point.as_str()])
+            .output()
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to check the filesystem of {}", self.mount_point),
+                source: Some(e.into()),
+            })?;
+        let filesystem = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if filesystem != "btrfs" {
+            return Err(AppError::Btrfs {
+                message: format!(
+                    "{} is on {} rather than Btrfs; use the NewDisk approach instead",
+                    self.mount_point, filesystem
+                ),
+                source: None,
             });
         }
 
-        debug!("Creating main subvolume after mount");
+        self.enable_quotas();
+        if self.subvolume_exists("main")? {
+            debug!("Main subvolume already exists in {}", self.mount_point);
+            return Ok(());
+        }
+        self.create_main_subvolume()
+    }
+
+    /// Qgroups are the only way to tell how much of a snapshot is exclusive.
+    /// They stay enabled on the filesystem, so this is done once, on init.
+    fn enable_quotas(&self) {
+        if let Err(e) = run_btrfs(&["quota", "enable", &self.mount_point]) {
+            warn!(
+                "Could not enable quotas, exclusive sizes will be unknown: {}",
+                e
+            );
+        }
+    }
+
+    fn create_main_subvolume(&self) -> Result<(), error::AppError> {
+        debug!("Creating main subvolume");
         let main_subvolume = format!("{}/main", &self.mount_point);
         let output = std::process::Command::new("sudo")
             .args(&["btrfs", "subvolume", "create", &main_subvolume])
@@ -225,10 +499,17 @@ impl BtrfsOperator {
                     "Failed to create main subvolume: {}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
         debug!("Main subvolume created successfully: {}", main_subvolume);
 
+        // Branches are snapshots of main and inherit the property
+        if let Some(algorithm) = self.compression_algorithm() {
+            debug!("Setting {} compression on {}", algorithm, main_subvolume);
+            run_btrfs(&["property", "set", &main_subvolume, "compression", algorithm])?;
+        }
+
         let data_dir = format!("{}/data", &main_subvolume);
         debug!("Creating data directory: {}", data_dir);
         let mkdir_output = std::process::Command::new("sudo")
@@ -237,7 +518,8 @@ impl BtrfsOperator {
             .arg(&data_dir)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to create data directory: {}", e),
+                message: "Failed to create data directory".to_string(),
+                source: Some(e.into()),
             })?;
 
         if !mkdir_output.status.success() {
@@ -247,18 +529,39 @@ impl BtrfsOperator {
                     String::from_utf8_lossy(&mkdir_output.stderr),
                     String::from_utf8_lossy(&mkdir_output.stdout)
                 ),
+                source: None,
             });
         }
 
-        info!(
-            "Successfully mounted disk at {} with main subvolume",
-            self.mount_point
-        );
         Ok(())
     }
 
+    /// `-o compress=...` when the image is mounted with compression.
+    fn mount_options(&self) -> Vec<String> {
+        match self.compression_algorithm() {
+            Some(_) => vec!["-o".to_string(), format!("compress={}", self.compression)],
+            None => Vec::new(),
+        }
+    }
+
+    fn compression_algorithm(&self) -> Option<&str> {
+        // Checked when the config is loaded
+        compression_algorithm(&self.compression).ok().flatten()
+    }
+
     pub fn unmount_disk(&self) -> Result<(), error::AppError> {
+        if self.approach == Approach::ExistingDisk {
+            debug!(
+                "ExistingDisk approach, leaving {} mounted",
+                self.mount_point
+            );
+            return Ok(());
+        }
         info!("Starting disk unmount process for {}", self.mount_point);
+
+        if skip_for_dry_run(self.dry_run, &format!("unmount {}", self.mount_point)) {
+            return Ok(());
+        }
         Self::prompt_sudo_password().unwrap();
 
         debug!("Unmounting {}", self.mount_point);
@@ -279,6 +582,7 @@ impl BtrfsOperator {
                         "Failed to unmount loop device: {}",
                         String::from_utf8_lossy(&output.stderr)
                     ),
+                    source: None,
                 });
             }
         }
@@ -294,6 +598,7 @@ impl BtrfsOperator {
                     "Failed to list loop devices: {}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
 
@@ -314,6 +619,7 @@ impl BtrfsOperator {
                     "Failed to detach loop device: {}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
         debug!("Loop device detached successfully");
@@ -323,6 +629,232 @@ impl BtrfsOperator {
         Ok(())
     }
 
+    /// Grows or shrinks the image to `new_size` bytes: the sparse file, its
+    /// loop device and the btrfs filesystem on it. Shrinking below the space
+    /// the filesystem currently uses is refused.
+    pub fn resize(&mut self, new_size: u64) -> Result<(), error::AppError> {
+        info!(
+            "Resizing image {:?} from {} to {} bytes",
+            self.img_path, self.size, new_size
+        );
+
+        if !self.img_path.exists() {
+            return Err(AppError::FileNotFound {
+                path: self.img_path.to_string_lossy().to_string(),
+            });
+        }
+
+        let (_, used, _) = self.get_filesystem_info()?;
+        if new_size < used {
+            return Err(AppError::Btrfs {
+                message: format!(
+                    "Cannot resize {:?} to {} bytes, {} bytes are in use",
+                    self.img_path, new_size, used
+                ),
+                source: None,
+            });
+        }
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("resize {:?} to {} bytes", self.img_path, new_size),
+        ) {
+            return Ok(());
+        }
+
+        let current_size = fs::metadata(&self.img_path)
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to read disk image {:?}", self.img_path),
+                source: Some(e.into()),
+            })?
+            .len();
+        let size = new_size.to_string();
+
+        // Shrink the filesystem before the device under it, grow it after
+        if new_size < current_size {
+            run_btrfs(&["filesystem", "resize", &size, &self.mount_point])?;
+        }
+
+        File::options()
+            .write(true)
+            .open(&self.img_path)
+            .and_then(|file| file.set_len(new_size))
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to resize disk image {:?}", self.img_path),
+                source: Some(e.into()),
+            })?;
+
+        let loop_device = self.loop_device()?;
+        debug!("Refreshing the size of loop device {}", loop_device);
+        let output = std::process::Command::new("sudo")
+            .args(["losetup", "-c", loop_device.as_str()])
+            .output()
+            .map_err(|e| AppError::DiskMount {
+                message: format!("Failed to run losetup -c {}", loop_device),
+                source: Some(e.into()),
+            })?;
+        if !output.status.success() {
+            return Err(AppError::DiskMount {
+                message: format!(
+                    "Failed to refresh loop device {}: {}",
+                    loop_device,
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                source: None,
+            });
+        }
+
+        if new_size >= current_size {
+            run_btrfs(&["filesystem", "resize", "max", &self.mount_point])?;
+        }
+
+        self.size = new_size;
+        info!("Image resized to {} bytes", new_size);
+        Ok(())
+    }
+
+    /// Loop device currently backed by the image.
+    fn loop_device(&self) -> Result<String, error::AppError> {
+        let output = std::process::Command::new("sudo")
+            .arg("losetup")
+            .output()
+            .map_err(|e| AppError::DiskMount {
+                message: "Failed to list loop devices".to_string(),
+                source: Some(e.into()),
+            })?;
+
+        find_device_by_path(
+            &String::from_utf8_lossy(&output.stdout),
+            &self.img_path.to_string_lossy(),
+        )
+        .ok_or_else(|| AppError::DiskMount {
+            message: format!("No loop device is attached to {:?}", self.img_path),
+            source: None,
+        })
+    }
+
+    /// Moves the project to the config mount point `new_mount_point`: the
+    /// image is unmounted, moved to `new_img_path` and mounted again at the
+    /// project's directory under `new_mount_point`.
+    pub fn remount_disk(
+        &mut self,
+        new_mount_point: &str,
+        new_img_path: &Path,
+    ) -> Result<(), error::AppError> {
+        let target = project_mount_point(new_mount_point, &self.project_name);
+        info!(
+            "Moving {:?} to {:?} and remounting it from {} to {}",
+            self.img_path, new_img_path, self.mount_point, target
+        );
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!(
+                "move {:?} to {:?} and mount it at {}",
+                self.img_path, new_img_path, target
+            ),
+        ) {
+            return Ok(());
+        }
+        self.unmount_disk()?;
+        move_image(&self.img_path, new_img_path)?;
+        self.img_path = new_img_path.to_path_buf();
+        self.attach_and_mount(&target)?;
+
+        self.mount_point = target;
+        info!("Disk remounted at {}", self.mount_point);
+        Ok(())
+    }
+
+    /// Mounts the existing image at its mount point without formatting it,
+    /// e.g. after `unmount_disk`. Nothing is done when it is already mounted.
+    pub fn mount_existing_disk(&self) -> Result<(), error::AppError> {
+        if self.approach == Approach::ExistingDisk {
+            debug!(
+                "ExistingDisk approach, {} is mounted by the system",
+                self.mount_point
+            );
+            return Ok(());
+        }
+
+        if self.is_mounted() {
+            debug!("{} is already mounted", self.mount_point);
+            return Ok(());
+        }
+        info!("Mounting {:?} at {}", self.img_path, self.mount_point);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("mount {:?} at {}", self.img_path, self.mount_point),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        self.attach_and_mount(&self.mount_point)?;
+        info!("Disk mounted at {}", self.mount_point);
+        Ok(())
+    }
+
+    /// Whether a filesystem is mounted at the mount point.
+    pub fn is_mounted(&self) -> bool {
+        std::process::Command::new("mountpoint")
+            .args(["-q", self.mount_point.as_str()])
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// Attaches the image to a loop device and mounts it at `mount_point`.
+    fn attach_and_mount(&self, mount_point: &str) -> Result<(), error::AppError> {
+        let output = std::process::Command::new("sudo")
+            .args(["losetup", "-f", "--show", &self.img_path.to_string_lossy()])
+            .output()
+            .map_err(|e| AppError::DiskMount {
+                message: "Failed to create loop device".to_string(),
+                source: Some(e.into()),
+            })?;
+
+        if !output.status.success() {
+            return Err(AppError::DiskMount {
+                message: format!(
+                    "Failed to create loop device: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                source: None,
+            });
+        }
+
+        let loop_device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        debug!("Loop device created: {}", loop_device);
+
+        let mount_options = self.mount_options();
+        let mut mount = vec!["mount"];
+        mount.extend(mount_options.iter().map(String::as_str));
+        mount.extend([loop_device.as_str(), mount_point]);
+        for args in [vec!["mkdir", "-p", mount_point], mount] {
+            let output = std::process::Command::new("sudo")
+                .args(&args)
+                .output()
+                .map_err(|e| AppError::DiskMount {
+                    message: format!("Failed to run {}", args.join(" ")),
+                    source: Some(e.into()),
+                })?;
+
+            if !output.status.success() {
+                return Err(AppError::DiskMount {
+                    message: format!(
+                        "Failed to run {}: {}",
+                        args.join(" "),
+                        String::from_utf8_lossy(&output.stderr)
+                    ),
+                    source: None,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn check_btrfs(&self) -> Result<(), String> {
         debug!("Checking for Btrfs installation");
         let output = std::process::Command::new("btrfs")
@@ -341,6 +873,13 @@ impl BtrfsOperator {
 
     pub fn cleanup_project_subvolume(&self, project_name: &str) -> Result<(), error::AppError> {
         info!("Starting cleanup of project subvolume: {}", project_name);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("delete subvolume {}/{}", self.mount_point, project_name),
+        ) {
+            return Ok(());
+        }
         Self::prompt_sudo_password().unwrap();
 
         let subvolume_path = format!("{}/{}", &self.mount_point, project_name);
@@ -362,7 +901,8 @@ impl BtrfsOperator {
             .arg(&subvolume_path)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to delete subvolume: {}", e),
+                message: "Failed to delete subvolume".to_string(),
+                source: Some(e.into()),
             })?;
 
         if output.status.success() {
@@ -376,13 +916,25 @@ impl BtrfsOperator {
                     String::from_utf8_lossy(&output.stderr),
                     String::from_utf8_lossy(&output.stdout)
                 ),
+                source: None,
             })
         }
     }
 
     pub fn cleanup_disk(&self) -> Result<(), error::AppError> {
+        if self.approach == Approach::ExistingDisk {
+            debug!("ExistingDisk approach, no image to clean up");
+            return Ok(());
+        }
         info!("Starting disk cleanup process for {:?}", self.img_path);
 
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("unmount and remove {:?}", self.img_path),
+        ) {
+            return Ok(());
+        }
+
         // First try to unmount the disk if it's mounted
         match self.unmount_disk() {
             Ok(_) => {
@@ -404,7 +956,8 @@ impl BtrfsOperator {
                 Err(e) => {
                     debug!("Failed to remove disk image file: {}", e);
                     return Err(AppError::FileSystem {
-                        message: format!("Failed to remove disk image file: {}", e),
+                        message: "Failed to remove disk image file".to_string(),
+                        source: Some(e.into()),
                     });
                 }
             }
@@ -416,20 +969,40 @@ impl BtrfsOperator {
         Ok(())
     }
 
-    pub fn create_snapshot(&self, snapshot_name: &str) -> Result<(), error::AppError> {
-        debug!("Creating Btrfs snapshot: {}", snapshot_name);
-        Self::prompt_sudo_password().unwrap();
+    /// Snapshots the `source` branch subvolume as `snapshot_name`, read-only
+    /// with `read_only`.
+    pub fn create_snapshot(
+        &self,
+        source: &str,
+        snapshot_name: &str,
+        read_only: bool,
+    ) -> Result<(), error::AppError> {
+        debug!(
+            "Creating Btrfs snapshot: {} from {} (read-only: {})",
+            snapshot_name, source, read_only
+        );
 
-        // Source is always the main subvolume of this version
-        // TODO: change to snapshot from branches
-        let source_subvolume = format!("{}/main", &self.mount_point);
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!(
+                "snapshot {}{} to {}/{}",
+                source,
+                if read_only { " read-only" } else { "" },
+                self.mount_point,
+                snapshot_name
+            ),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        let source_subvolume = format!("{}/{}", &self.mount_point, source);
 
         let target_snapshot = format!("{}/{}", &self.mount_point, snapshot_name);
 
-        if !self.subvolume_exists("main")? {
-            return Err(AppError::FileSystem {
-                message: "Main subvolume not found - project may not be properly initialized"
-                    .to_string(),
+        if !self.subvolume_exists(source)? {
+            return Err(AppError::FileNotFound {
+                path: source_subvolume,
             });
         }
 
@@ -438,20 +1011,26 @@ impl BtrfsOperator {
             source_subvolume, target_snapshot
         );
 
-        let output = std::process::Command::new("sudo")
-            .arg("btrfs")
-            .arg("subvolume")
-            .arg("snapshot")
+        let mut command = std::process::Command::new("sudo");
+        command.arg("btrfs").arg("subvolume").arg("snapshot");
+        if read_only {
+            command.arg("-r");
+        }
+        let output = command
             .arg(source_subvolume)
             .arg(&target_snapshot)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to create Btrfs snapshot: {}", e),
+                message: "Failed to create Btrfs snapshot".to_string(),
+                source: Some(e.into()),
             })?;
 
         if output.status.success() {
             debug!("Btrfs snapshot created successfully: {}", snapshot_name);
-            info!("Snapshot '{}' created from main subvolume", snapshot_name);
+            info!(
+                "Snapshot '{}' created from {} subvolume",
+                snapshot_name, source
+            );
             Ok(())
         } else {
             Err(AppError::FileSystem {
@@ -460,11 +1039,252 @@ impl BtrfsOperator {
                     String::from_utf8_lossy(&output.stderr),
                     String::from_utf8_lossy(&output.stdout)
                 ),
+                source: None,
             })
         }
     }
 
-    fn subvolume_exists(&self, subvolume_name: &str) -> Result<bool, error::AppError> {
+    /// Deletes the branch subvolume `name` created by `create_snapshot`, or the
+    /// plain directory of a branch that is not a subvolume (attached, restored
+    /// or copied), doing nothing when it is already gone. The main subvolume is
+    /// never deleted.
+    ///
+    /// Btrfs reclaims a subvolume's space asynchronously; pass
+    /// `wait_for_commit` to block until the deletion is committed so that a
+    /// following `get_filesystem_info` reports the freed space.
+    pub fn delete_snapshot(
+        &self,
+        name: &str,
+        wait_for_commit: bool,
+    ) -> Result<(), error::AppError> {
+        if name == "main" {
+            return Err(AppError::Btrfs {
+                message: "Refusing to delete the main subvolume".to_string(),
+                source: None,
+            });
+        }
+        info!("Deleting snapshot {}/{}", self.mount_point, name);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("delete subvolume {}/{}", self.mount_point, name),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        let path = format!("{}/{}", self.mount_point, name);
+        if self.subvolume_exists(name)? {
+            return if wait_for_commit {
+                run_btrfs(&["subvolume", "delete", "--commit-after", &path])
+            } else {
+                run_btrfs(&["subvolume", "delete", &path])
+            };
+        }
+
+        match fs::symlink_metadata(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("{} does not exist, nothing to delete", path);
+                return Ok(());
+            }
+            // Unreadable without sudo, still there
+            _ => debug!("{} is not a subvolume, removing the directory", path),
+        }
+
+        let output = std::process::Command::new("sudo")
+            .args(["rm", "-rf", "--", &path])
+            .output()
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to remove {}", path),
+                source: Some(e.into()),
+            })?;
+        if !output.status.success() {
+            return Err(AppError::FileSystem {
+                message: format!(
+                    "Failed to remove {}: {}",
+                    path,
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                source: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Replaces the branch subvolume `name` with a fresh snapshot of
+    /// `source`. Unlike `delete_snapshot` this also works on main, so callers
+    /// must guard it.
+    pub fn reset_snapshot(
+        &self,
+        name: &str,
+        source: &str,
+        read_only: bool,
+    ) -> Result<(), error::AppError> {
+        info!("Resetting {}/{} from {}", self.mount_point, name, source);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!(
+                "replace subvolume {}/{} with a snapshot of {}",
+                self.mount_point, name, source
+            ),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        // Check before deleting anything, so a bad source leaves the branch as is
+        if !self.subvolume_exists(source)? {
+            return Err(AppError::FileNotFound {
+                path: format!("{}/{}", self.mount_point, source),
+            });
+        }
+
+        if self.subvolume_exists(name)? {
+            run_btrfs(&[
+                "subvolume",
+                "delete",
+                &format!("{}/{}", self.mount_point, name),
+            ])?;
+        }
+        self.create_snapshot(source, name, read_only)
+    }
+
+    /// Takes a read-only snapshot of `branch` named `name`, next to the branches.
+    pub fn create_named_snapshot(&self, branch: &str, name: &str) -> Result<(), error::AppError> {
+        let target = named_snapshot_name(branch, name);
+        info!("Snapshotting {} to {}", branch, target);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!(
+                "snapshot {} read-only to {}/{}",
+                branch, self.mount_point, target
+            ),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        if !self.subvolume_exists(branch)? {
+            return Err(AppError::FileNotFound {
+                path: format!("{}/{}", self.mount_point, branch),
+            });
+        }
+        if self.subvolume_exists(&target)? {
+            return Err(AppError::Btrfs {
+                message: format!("Subvolume '{}' already exists", target),
+                source: None,
+            });
+        }
+
+        run_btrfs(&[
+            "subvolume",
+            "snapshot",
+            "-r",
+            &format!("{}/{}", self.mount_point, branch),
+            &format!("{}/{}", self.mount_point, target),
+        ])
+    }
+
+    /// Deletes the snapshot taken by `create_named_snapshot`.
+    pub fn delete_named_snapshot(&self, branch: &str, name: &str) -> Result<(), error::AppError> {
+        let target = named_snapshot_name(branch, name);
+        info!("Deleting snapshot {}", target);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("delete subvolume {}/{}", self.mount_point, target),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        run_btrfs(&[
+            "subvolume",
+            "delete",
+            &format!("{}/{}", self.mount_point, target),
+        ])
+    }
+
+    /// Size of a snapshot taken by `create_named_snapshot`.
+    pub fn named_snapshot_info(
+        &self,
+        branch: &str,
+        name: &str,
+    ) -> Result<SubvolumeInfo, error::AppError> {
+        self.get_subvolume_info(&named_snapshot_name(branch, name))
+    }
+
+    /// Writes a `btrfs send` stream of the subvolume to `dest`. Send needs a
+    /// read-only source, so a temporary read-only snapshot is streamed instead.
+    pub fn send_subvolume(&self, subvolume_name: &str, dest: &Path) -> Result<(), error::AppError> {
+        info!("Sending subvolume {} to {:?}", subvolume_name, dest);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("send subvolume {} to {:?}", subvolume_name, dest),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        if !self.subvolume_exists(subvolume_name)? {
+            return Err(AppError::Btrfs {
+                message: format!("Subvolume '{}' not found", subvolume_name),
+                source: None,
+            });
+        }
+
+        let source = format!("{}/{}", &self.mount_point, subvolume_name);
+        let read_only = format!("{}/{}", &self.mount_point, send_name(subvolume_name));
+
+        run_btrfs(&["subvolume", "snapshot", "-r", &source, &read_only])?;
+        let sent = run_btrfs(&["send", "-f", &dest.to_string_lossy(), &read_only]);
+        let cleaned = run_btrfs(&["subvolume", "delete", &read_only]);
+
+        sent?;
+        cleaned?;
+        Ok(())
+    }
+
+    /// Recreates a subvolume from a stream written by `send_subvolume`.
+    pub fn receive_subvolume(
+        &self,
+        subvolume_name: &str,
+        src: &Path,
+    ) -> Result<(), error::AppError> {
+        info!("Receiving subvolume {} from {:?}", subvolume_name, src);
+
+        if skip_for_dry_run(
+            self.dry_run,
+            &format!("receive subvolume {} from {:?}", subvolume_name, src),
+        ) {
+            return Ok(());
+        }
+        Self::prompt_sudo_password()?;
+
+        if self.subvolume_exists(subvolume_name)? {
+            return Err(AppError::Btrfs {
+                message: format!("Subvolume '{}' already exists", subvolume_name),
+                source: None,
+            });
+        }
+
+        let received = format!("{}/{}", &self.mount_point, send_name(subvolume_name));
+        let target = format!("{}/{}", &self.mount_point, subvolume_name);
+
+        run_btrfs(&["receive", "-f", &src.to_string_lossy(), &self.mount_point])?;
+        // Received subvolumes are read-only; take a writable snapshot of it
+        let snapshotted = run_btrfs(&["subvolume", "snapshot", &received, &target]);
+        let cleaned = run_btrfs(&["subvolume", "delete", &received]);
+
+        snapshotted?;
+        cleaned?;
+        Ok(())
+    }
+
+    pub fn subvolume_exists(&self, subvolume_name: &str) -> Result<bool, error::AppError> {
         let subvolume_path = format!("{}/{}", &self.mount_point, subvolume_name);
         debug!("Checking if subvolume exists: {}", subvolume_path);
 
@@ -475,7 +1295,8 @@ impl BtrfsOperator {
             .arg(&subvolume_path)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to check subvolume existence: {}", e),
+                message: "Failed to check subvolume existence".to_string(),
+                source: Some(e.into()),
             })?;
 
         Ok(output.status.success())
@@ -491,7 +1312,8 @@ impl BtrfsOperator {
             .arg(&self.mount_point)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to list subvolumes: {}", e),
+                message: "Failed to list subvolumes".to_string(),
+                source: Some(e.into()),
             })?;
 
         if !output.status.success() {
@@ -500,6 +1322,7 @@ impl BtrfsOperator {
                     "Failed to list subvolumes: stderr={}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
 
@@ -520,64 +1343,52 @@ impl BtrfsOperator {
         subvolume_name: &str,
     ) -> Result<SubvolumeInfo, error::AppError> {
         debug!("Getting info for subvolume: {}", subvolume_name);
-        Self::prompt_sudo_password().unwrap();
+        Self::prompt_sudo_password()?;
 
         let subvolume_path = format!("{}/{}", &self.mount_point, subvolume_name);
 
-        // Get quota info for the subvolume
         let output = std::process::Command::new("sudo")
-            .arg("btrfs")
-            .arg("qgroup")
-            .arg("show")
-            .arg("-r")
-            .arg("-e")
-            .arg("--raw")
-            .arg(&self.mount_point)
+            .args(["btrfs", "subvolume", "show", &subvolume_path])
             .output()
-            .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to get subvolume quota info: {}", e),
+            .map_err(|e| AppError::Btrfs {
+                message: format!("Failed to show subvolume {}", subvolume_path),
+                source: Some(e.into()),
             })?;
-
-        if !output.status.success() {
-            // If qgroups are not enabled, try to enable them first
-            debug!("Qgroups might not be enabled, attempting to enable them");
-            let _ = std::process::Command::new("sudo")
-                .arg("btrfs")
-                .arg("quota")
-                .arg("enable")
-                .arg(&self.mount_point)
-                .output();
-
-            // Try to get the sizes using du as a fallback
-            return self.get_subvolume_size_fallback(subvolume_name);
-        }
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let mut referenced_size: u64 = 0;
-        let mut exclusive_size: u64 = 0;
-
-        // Parse the qgroup output to find our subvolume
-        for line in stdout.lines() {
-            if line.contains(subvolume_name) || line.contains(&subvolume_path) {
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                if parts.len() >= 3 {
-                    referenced_size = parts[1].parse().unwrap_or(0);
-                    exclusive_size = parts[2].parse().unwrap_or(0);
-                    break;
-                }
+        let id = parse_subvolume_id(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+            AppError::Btrfs {
+                message: format!(
+                    "Failed to read the ID of subvolume {}: {}",
+                    subvolume_path,
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                source: None,
             }
-        }
+        })?;
 
-        // If we couldn't find it in qgroup output, use fallback
-        if referenced_size == 0 && exclusive_size == 0 {
+        // Fails when quotas are disabled
+        let output = std::process::Command::new("sudo")
+            .args(["btrfs", "qgroup", "show", "--raw", &self.mount_point])
+            .output()
+            .map_err(|e| AppError::FileSystem {
+                message: "Failed to get subvolume quota info".to_string(),
+                source: Some(e.into()),
+            })?;
+
+        let sizes = output
+            .status
+            .success()
+            .then(|| parse_qgroup_sizes(&String::from_utf8_lossy(&output.stdout), id))
+            .flatten();
+        let Some((referenced_size, exclusive_size)) = sizes else {
+            debug!("No qgroup for subvolume {} ({})", subvolume_name, id);
             return self.get_subvolume_size_fallback(subvolume_name);
-        }
+        };
 
         Ok(SubvolumeInfo {
             name: subvolume_name.to_string(),
             path: subvolume_path,
             referenced_size,
-            exclusive_size,
+            exclusive_size: Some(exclusive_size),
         })
     }
 
@@ -599,7 +1410,8 @@ impl BtrfsOperator {
             .arg(&subvolume_path)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to get subvolume size using du: {}", e),
+                message: "Failed to get subvolume size using du".to_string(),
+                source: Some(e.into()),
             })?;
 
         if !output.status.success() {
@@ -608,6 +1420,7 @@ impl BtrfsOperator {
                     "Failed to get subvolume size: stderr={}",
                     String::from_utf8_lossy(&output.stderr)
                 ),
+                source: None,
             });
         }
 
@@ -622,7 +1435,7 @@ impl BtrfsOperator {
             name: subvolume_name.to_string(),
             path: subvolume_path,
             referenced_size: size,
-            exclusive_size: size, // In fallback mode, we can't determine exclusive size
+            exclusive_size: None,
         })
     }
 
@@ -636,7 +1449,8 @@ impl BtrfsOperator {
             .arg(&self.mount_point)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to get filesystem info: {}", e),
+                message: "Failed to get filesystem info".to_string(),
+                source: Some(e.into()),
             })?;
 
         if !output.status.success() {
@@ -676,7 +1490,8 @@ impl BtrfsOperator {
             .arg(&self.mount_point)
             .output()
             .map_err(|e| AppError::FileSystem {
-                message: format!("Failed to get filesystem usage with du: {}", e),
+                message: "Failed to get filesystem usage with du".to_string(),
+                source: Some(e.into()),
             })?;
 
         let used_bytes = if output.status.success() {
@@ -719,3 +1534,54 @@ impl BtrfsOperator {
         Ok(infos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_move_paths() {
+        let operator = |config: Config| {
+            BtrfsOperator::new(
+                Project {
+                    name: "proj".to_string(),
+                    path: PathBuf::from("/home/me/proj"),
+                },
+                config,
+            )
+        };
+        let mut config = Config::new("proj".to_string());
+        config.mount_point = "/mnt/old".to_string();
+        let before = operator(config.clone());
+        assert_eq!(before.image_path(), Path::new("/home/me/proj/btrfs.img"));
+
+        // Branches resolve to `{mount_point}/{name}/{branch}`, so that is
+        // where the filesystem is mounted after moving to a new mount point
+        config.mount_point = "/mnt/big".to_string();
+        config.image_path = Some(
+            moved_image_path("/mnt/big", "proj")
+                .to_string_lossy()
+                .to_string(),
+        );
+        let after = operator(config);
+        assert_eq!(project_mount_point("/mnt/big", "proj"), "/mnt/big/proj");
+        assert_eq!(after.mount_point, "/mnt/big/proj");
+        assert_eq!(after.image_path(), Path::new("/mnt/big/proj.btrfs.img"));
+    }
+
+    #[test]
+    fn test_parse_qgroup_sizes() {
+        let show = "main\n\tName: \t\t\tmain\n\tSubvolume ID: \t\t257\n\tGeneration: \t\t12\n";
+        assert_eq!(parse_subvolume_id(show), Some(257));
+
+        let qgroups = "\
+Qgroupid    Referenced    Exclusive   Path
+--------    ----------    ---------   ----
+0/5              16384        16384   <toplevel>
+0/256          1048576        65536   main
+0/257          1064960         4096   feature@release
+";
+        assert_eq!(parse_qgroup_sizes(qgroups, 257), Some((1064960, 4096)));
+        assert_eq!(parse_qgroup_sizes(qgroups, 25), None);
+    }
+}
@@ -1,30 +1,147 @@
-use crate::config::DEFAULT_CONFIG_PATH;
+use crate::archive::{self, DumpFormat, Manifest, ManifestBranch};
+use crate::audit::{self, AuditRecord, Operation};
+use crate::btrfs::{BtrfsOperator, moved_image_path};
+use crate::config::{Approach, Engine, RestartPolicy, config_path};
+use crate::copy_ref::CopyRefOperator;
 use crate::error::AppError;
-use crate::fiemap::{FolderInfo, get_folder_size};
+use crate::fiemap::{
+    FolderInfo, exclusive_size, get_folder_size, get_folder_size_ignoring, get_physical_extents,
+    overlap_size,
+};
+use crate::lockfile::{LOCK_FILE, LockEntry, LockFile};
+use crate::runtime::RuntimeState;
 use crate::snapshot;
+use crate::validate::{Severity, validate_project};
 use crate::{
-    config::Config,
-    database_operator::{DatabaseOperator, PostgresOperator},
+    config::{
+        Branch, Config, MIN_IMAGE_SIZE, Snapshot, get_valid_port_excluding, parse_size,
+        validate_name,
+    },
+    database_operator::{
+        Activity, ContainerHealth, DatabaseOperator, EngineOperator, StopOutcome, container_health,
+        container_logs, database_activity, exec_args, image_digest, ping, remove_dangling_volumes,
+        remove_stopped_containers, sql_command, start_container,
+    },
 };
 use anyhow::Result;
 use chrono::Utc;
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand, ValueEnum};
 use prettytable::{Attr, Cell, Row, Table};
 use rustix::path::Arg;
+use serde::{Deserialize, Serialize};
 use size::Size;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use tracing::{debug, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{io::AsyncBufReadExt, sync::Semaphore, task::JoinSet};
+use tracing::{debug, info, warn};
+
+/// How long `use --bounce` waits for the proxy to pick up the new branch.
+const BOUNCE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Parser)]
 #[command(name = "dbranch")]
 #[command(about = "🌿 dBranch 🌿 - PostgreSQL Database Branching System")]
 #[command(version)]
 pub struct Cli {
+    /// Override the configured port range for this invocation (e.g. 8000:8099)
+    #[arg(long, global = true, value_name = "MIN:MAX", value_parser = parse_port_range)]
+    pub port_range: Option<PortRange>,
+
+    /// Project config file to use instead of `DBRANCH_CONFIG`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Log what mutating commands would do without doing it
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Restart policy of containers created by this invocation
+    #[arg(long, global = true, value_enum, value_name = "POLICY")]
+    pub restart: Option<RestartPolicy>,
+
+    /// Log more: -v for debug, -vv for trace (overrides RUST_LOG)
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only log warnings and errors (overrides RUST_LOG)
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+fn parse_port_range(s: &str) -> Result<PortRange, String> {
+    let (min, max) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected MIN:MAX, got '{}'", s))?;
+    let min: u16 = min
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid MIN '{}': {}", min, e))?;
+    let max: u16 = max
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid MAX '{}': {}", max, e))?;
+
+    if min > max {
+        return Err(format!(
+            "MIN ({}) must not be greater than MAX ({})",
+            min, max
+        ));
+    }
+
+    Ok(PortRange { min, max })
+}
+
+/// Parses an age like `7d`, `12h` or `30m`, the units `format_age` prints.
+fn parse_age(s: &str) -> Result<chrono::Duration, String> {
+    let s = s.trim();
+    let (amount, unit) = s.split_at(s.len() - s.chars().last().map_or(0, char::len_utf8));
+    let amount: u32 = amount
+        .parse()
+        .map_err(|_| format!("expected an age like 7d, 12h or 30m, got '{}'", s))?;
+
+    match unit {
+        "d" => Ok(chrono::Duration::days(amount.into())),
+        "h" => Ok(chrono::Duration::hours(amount.into())),
+        "m" => Ok(chrono::Duration::minutes(amount.into())),
+        _ => Err(format!("unknown unit in '{}', use d, h or m", s)),
+    }
+}
+
+fn deserialize_age<'de, D>(deserializer: D) -> Result<chrono::Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_age(&s).map_err(serde::de::Error::custom)
+}
+
+impl Cli {
+    /// Log level asked for with `-v` or `-q`, `None` to defer to `RUST_LOG`.
+    pub fn log_level(&self) -> Option<&'static str> {
+        match (self.quiet, self.verbose) {
+            (true, _) => Some("warn"),
+            (false, 0) => None,
+            (false, 1) => Some("debug"),
+            (false, _) => Some("trace"),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Commands {
     #[clap(about = "Start dBranch proxy")]
     Start,
@@ -38,64 +155,611 @@ pub enum Commands {
     List,
     #[clap(about = "Delete a branch project")]
     Delete(DeleteArgs),
+    #[clap(about = "Delete branches created longer ago than a given age")]
+    Prune(PruneArgs),
     #[clap(about = "Delete a project")]
     DeleteProject(DeleteProjectArgs),
     #[clap(about = "Show details of a branch project")]
     Show(ShowArgs),
     #[clap(about = "Show the status of a project")]
-    Status,
+    Status(StatusArgs),
     #[clap(about = "Use a specific branch")]
     Use(UseArgs),
     #[clap(about = "Stop all branches and containers")]
-    Stop,
+    Stop(StopArgs),
     #[clap(about = "Resume stopped branches and containers")]
     Resume,
+    #[clap(about = "Check the project's on-disk consistency")]
+    Validate,
+    #[clap(about = "Print the effective configuration")]
+    DumpConfig,
+    #[clap(about = "Remove stopped containers, dangling volumes and empty data directories")]
+    Clean,
+    #[clap(about = "Attach an existing PostgreSQL data directory as a branch")]
+    Attach(AttachArgs),
+    #[clap(about = "Detach a branch, keeping its data directory outside dBranch")]
+    Detach(DetachArgs),
+    #[clap(about = "Create several branches concurrently")]
+    CreateMany(CreateManyArgs),
+    #[clap(about = "Export branches to a directory for cold storage")]
+    Export(ExportArgs),
+    #[clap(about = "Import branches from an export directory")]
+    Import(ImportArgs),
+    #[clap(about = "Check branches against the provenance recorded in .dbranch.lock")]
+    Verify,
+    #[clap(
+        about = "Print the active project and branch on one line",
+        alias = "whoami"
+    )]
+    Context(ContextArgs),
+    #[clap(about = "Move the project to a new mount point")]
+    Move(MoveArgs),
+    #[clap(about = "Show the history of branch operations")]
+    History(HistoryArgs),
+    #[clap(about = "Take a named read-only snapshot of a branch")]
+    Snapshot(SnapshotArgs),
+    #[clap(about = "List or delete the named snapshots of the project")]
+    Snapshots(SnapshotsArgs),
+    #[clap(about = "Protect the project from destructive commands")]
+    Lock,
+    #[clap(about = "Allow destructive commands on the project again")]
+    Unlock,
+    #[clap(about = "Run newline-delimited JSON commands read from stdin")]
+    Batch,
+    #[clap(about = "Block until a branch accepts connections")]
+    Wait(WaitArgs),
+    #[clap(about = "Compare two branches on disk, or their schemas with --schema")]
+    Diff(DiffArgs),
+    #[clap(about = "Grow or shrink the disk image of a NewDisk project")]
+    Resize(ResizeArgs),
+    #[clap(about = "Show the logs of a branch's database container")]
+    Logs(LogsArgs),
+    #[clap(about = "Open psql on a branch (the active branch by default)")]
+    Psql(PsqlArgs),
+    #[clap(about = "Print the connection URL of a branch (the active branch by default)")]
+    ConnectionString(ConnectionStringArgs),
+    #[clap(about = "Run a command, or SQL with --sql, in a branch's database container")]
+    Exec(ExecArgs),
+    #[clap(about = "Write a logical dump of a branch to a file or stdout")]
+    Dump(DumpArgs),
+    #[clap(about = "Create a branch from a dump written by `dump` or pg_dump")]
+    Restore(RestoreArgs),
+    #[clap(about = "Discard a branch's changes by re-snapshotting it from its source")]
+    Reset(ResetArgs),
+    #[clap(about = "Print the name of the active branch")]
+    Current(CurrentArgs),
+}
+
+impl Commands {
+    /// Why the command can't run inside `batch`, if it can't: it would
+    /// replace the batch process, never return, or read answers from stdin.
+    fn batch_conflict(&self) -> Option<&'static str> {
+        match self {
+            Commands::Start | Commands::Batch => Some("start and batch cannot run inside a batch"),
+            Commands::Psql(_) | Commands::Exec(_) => {
+                Some("psql and exec replace the process and cannot run inside a batch")
+            }
+            Commands::Logs(LogsArgs { follow: true, .. }) => {
+                Some("logs --follow never returns and cannot run inside a batch")
+            }
+            // stdin holds the batch commands, not answers
+            Commands::Diff(DiffArgs { start: false, .. }) => {
+                Some("diff may ask before starting a branch; pass \"start\": true inside a batch")
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the command saves the config or the lockfile, and so must hold
+    /// the `ConfigLock` while it runs.
+    pub fn writes_config(&self) -> bool {
+        matches!(
+            self,
+            Commands::Init(_)
+                | Commands::InitPostgres
+                | Commands::Create(_)
+                | Commands::CreateMany(_)
+                | Commands::Delete(_)
+                | Commands::Prune(_)
+                | Commands::DeleteProject(_)
+                | Commands::Use(_)
+                | Commands::Attach(_)
+                | Commands::Detach(_)
+                | Commands::Import(_)
+                | Commands::Move(_)
+                | Commands::Snapshot(_)
+                | Commands::Snapshots(_)
+                | Commands::Lock
+                | Commands::Unlock
+                | Commands::Batch
+                | Commands::Resize(_)
+                | Commands::Restore(_)
+                | Commands::Reset(_)
+        )
+    }
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Deserialize)]
 pub struct InitArgs {
-    #[arg(short, long, default_value = "dbranch_postgres")]
+    #[arg(short, long, default_value_t = default_init_name())]
+    #[serde(default = "default_init_name")]
     name: String,
 
-    #[arg(short, long, default_value = "5432")]
+    #[arg(short, long, default_value_t = default_init_port())]
+    #[serde(default = "default_init_port")]
     port: u16,
 }
 
-#[derive(Args, Debug)]
+// Defaults shared by clap and by the JSON commands of `batch`
+
+fn default_init_name() -> String {
+    String::from("dbranch_postgres")
+}
+
+fn default_init_port() -> u16 {
+    5432
+}
+
+fn default_jobs() -> usize {
+    4
+}
+
+fn default_attach_mode() -> TransferMode {
+    TransferMode::Reflink
+}
+
+fn default_detach_mode() -> TransferMode {
+    TransferMode::Move
+}
+
+fn default_dump_format() -> LogicalFormat {
+    LogicalFormat::Plain
+}
+
+fn default_wait_timeout() -> u64 {
+    60
+}
+
+#[derive(Args, Debug, Deserialize)]
 pub struct SetDefaultArgs {
     name: String,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Deserialize)]
 pub struct CreateArgs {
     name: String,
 
+    /// Branch to snapshot from, main by default
+    #[arg(short, long)]
+    source: Option<String>,
+
+    /// Seed the branch from a dump at this http(s) URL instead of main
+    /// (plain SQL or pg_dump custom format, optionally gzipped)
+    #[arg(long, value_name = "URL", conflicts_with = "source")]
+    from_url: Option<String>,
+
+    /// Take a read-only snapshot, kept for reference without a database container
+    #[arg(long, conflicts_with = "from_url")]
+    #[serde(default)]
+    read_only: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct CreateManyArgs {
+    #[arg(required = true)]
+    names: Vec<String>,
+
+    /// Maximum number of snapshots running at the same time
+    #[arg(short, long, default_value_t = default_jobs())]
+    #[serde(default = "default_jobs")]
+    jobs: usize,
+}
+
+#[derive(Args, Debug, Deserialize)]
+#[command(group(ArgGroup::new("target").required(true).args(["all", "branch"])))]
+pub struct ExportArgs {
+    /// Directory to write the artifacts and manifest to
+    dir: PathBuf,
+
+    /// Export every branch of the project
+    #[arg(long)]
+    #[serde(default)]
+    all: bool,
+
+    /// Export a single branch
+    #[arg(short, long)]
+    branch: Option<String>,
+}
+
+#[derive(Args, Debug, Deserialize)]
+#[command(group(ArgGroup::new("target").required(true).args(["all", "branch"])))]
+pub struct ImportArgs {
+    /// Directory previously written by `export`
+    dir: PathBuf,
+
+    /// Import every branch listed in the manifest
+    #[arg(long)]
+    #[serde(default)]
+    all: bool,
+
+    /// Import a single branch from the manifest
+    #[arg(short, long)]
+    branch: Option<String>,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct ContextArgs {
+    /// Print the context as JSON
+    #[arg(long)]
+    #[serde(default)]
+    json: bool,
+}
+
+/// Output of `dbranch context`.
+#[derive(Debug, Serialize)]
+pub struct Context {
+    pub project: String,
+    pub branch: String,
+    pub port: u16,
+    pub running: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct MoveArgs {
+    /// Mount point to relocate the project to (e.g. /mnt/bigger-disk)
+    new_mount_point: String,
+
+    /// Run even if the project is locked
+    #[arg(long)]
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct StatusArgs {
+    /// Also query running branches for connections and database size
+    #[arg(long)]
+    #[serde(default)]
+    live: bool,
+
+    /// Print the status as JSON, with sizes in bytes
+    #[arg(long, conflicts_with = "live")]
+    #[serde(default)]
+    json: bool,
+}
+
+/// Output of `dbranch status --json`, also served by the HTTP API.
+#[derive(Debug, Serialize)]
+pub struct ProjectStatus {
+    pub project: String,
+    pub active_branch: Option<String>,
+    /// Running proxy, as recorded by `dbranch start`
+    pub proxy: Option<RuntimeState>,
+    pub branches: Vec<BranchStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BranchStatus {
+    pub name: String,
+    pub port: u16,
+    pub logical_size: u64,
+    /// Bytes actually on disk, without the holes of sparse files
+    pub allocated_size: u64,
+    pub unique_size: u64,
+    pub running: bool,
+    pub health: ContainerHealth,
+    pub age_seconds: i64,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct HistoryArgs {
+    /// Only show operations on this branch
+    #[arg(short, long)]
+    branch: Option<String>,
+
+    /// Only show the most recent N operations
+    #[arg(short, long)]
+    limit: Option<usize>,
+
+    /// Print the records as JSON
+    #[arg(long)]
+    #[serde(default)]
+    json: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct SnapshotArgs {
+    /// Branch to snapshot
+    branch: String,
+
+    /// Name of the snapshot, unique per branch (e.g. before-migration)
+    name: String,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct SnapshotsArgs {
+    /// Only show (or delete from) the snapshots of this branch
+    #[arg(short, long)]
+    branch: Option<String>,
+
+    /// Delete the snapshot with this name
+    #[arg(long, value_name = "NAME")]
+    delete: Option<String>,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct WaitArgs {
+    name: String,
+
+    /// Seconds to wait before giving up
+    #[arg(short, long, default_value_t = default_wait_timeout())]
+    #[serde(default = "default_wait_timeout")]
+    timeout: u64,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct DiffArgs {
+    a: String,
+
+    b: String,
+
+    /// Print a unified diff of the DDL of both branches
+    #[arg(long)]
+    #[serde(default)]
+    schema: bool,
+
+    /// Start stopped branches for the comparison without asking
+    #[arg(long)]
+    #[serde(default)]
+    start: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct ResizeArgs {
+    /// New size of the image (e.g. 2TiB, 500GB, 200G)
+    new_size: String,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct LogsArgs {
+    branch: String,
+
+    /// Keep streaming new lines until Ctrl-C
+    #[arg(short, long)]
+    #[serde(default)]
+    follow: bool,
+
+    /// Number of lines to show from the end of the logs
+    #[arg(short = 'n', long, default_value_t = default_logs_tail())]
+    #[serde(default = "default_logs_tail")]
+    tail: usize,
+}
+
+fn default_logs_tail() -> usize {
+    100
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct ConnectionStringArgs {
+    /// Branch to connect to; the active branch, or main, by default
+    branch: Option<String>,
+
+    /// Leave the password out, e.g. for logs
+    #[arg(long)]
+    #[serde(default)]
+    no_password: bool,
+
+    /// Address the container on the dbranch Docker network instead of localhost
+    #[arg(long)]
+    #[serde(default)]
+    docker_network: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct ExecArgs {
+    branch: String,
+
+    /// SQL to run with the database client, as the configured user
+    #[arg(long, conflicts_with = "command")]
+    #[serde(default)]
+    sql: Option<String>,
+
+    /// Command to run in the container, after `--`
+    #[arg(last = true, required_unless_present = "sql")]
+    #[serde(default)]
+    command: Vec<String>,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct DumpArgs {
+    branch: String,
+
+    /// File to write the dump to; stdout when omitted
     #[arg(short, long)]
+    #[serde(default)]
+    out: Option<PathBuf>,
+
+    #[arg(short, long, value_enum, default_value_t = default_dump_format())]
+    #[serde(default = "default_dump_format")]
+    format: LogicalFormat,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct CurrentArgs {
+    /// Print the port of the active branch instead of its name
+    #[arg(long)]
+    #[serde(default)]
+    port: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct ResetArgs {
+    name: String,
+
+    /// Branch to snapshot again; the one the branch was created from, or main
+    #[arg(long)]
+    #[serde(default)]
     source: Option<String>,
+
+    /// Run even if the project is locked, and allow resetting main (with --source)
+    #[arg(long)]
+    #[serde(default)]
+    force: bool,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Deserialize)]
+pub struct RestoreArgs {
+    /// Name of the new branch
+    name: String,
+
+    /// Dump to load, plain SQL or pg_dump custom format, optionally gzipped
+    #[arg(long, value_name = "FILE")]
+    from: PathBuf,
+
+    /// Format of the dump; detected from the file when omitted
+    #[arg(short, long, value_enum)]
+    #[serde(default)]
+    format: Option<LogicalFormat>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogicalFormat {
+    /// SQL script, restorable with psql
+    Plain,
+    /// `pg_dump -Fc` archive, restorable with pg_restore (PostgreSQL only)
+    Custom,
+}
+
+impl LogicalFormat {
+    fn dump_format(self) -> DumpFormat {
+        match self {
+            LogicalFormat::Plain => DumpFormat::Sql,
+            LogicalFormat::Custom => DumpFormat::Custom,
+        }
+    }
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct PsqlArgs {
+    /// Branch to connect to; the active branch, or main, by default
+    branch: Option<String>,
+}
+
+#[derive(Args, Debug, Deserialize)]
 pub struct DeleteArgs {
     id: String,
+
+    /// Run even if the project is locked, and allow deleting the active branch
+    #[arg(long)]
+    #[serde(default)]
+    force: bool,
+
+    /// Wait until btrfs commits the deletion, so that `df` right after
+    /// reports the freed space
+    #[arg(long)]
+    #[serde(default)]
+    commit_after: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct PruneArgs {
+    /// Age of the branches to delete, e.g. 7d, 12h or 30m
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    #[serde(deserialize_with = "deserialize_age")]
+    older_than: chrono::Duration,
+
+    /// Run even if the project is locked
+    #[arg(long)]
+    #[serde(default)]
+    force: bool,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Deserialize)]
 pub struct DeleteProjectArgs {
     name: String,
+
+    /// Run even if the project is locked
+    #[arg(long)]
+    #[serde(default)]
+    force: bool,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Deserialize)]
 pub struct UseArgs {
+    /// Branch to switch to, or `-` for the previously active one
+    name: String,
+
+    /// Close the connections open through a running proxy, so that clients
+    /// reconnect to the new branch
+    #[arg(long)]
+    #[serde(default)]
+    bounce: bool,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct StopArgs {
+    /// Seconds to wait before force-killing a container (defaults to config `stop_timeout`)
+    #[arg(short, long)]
+    timeout: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransferMode {
+    /// Reflink the files, leaving the original in place
+    Reflink,
+    /// Move the directory (must be on the same filesystem)
+    Move,
+    /// Copy the files without sharing extents, leaving the original in place
+    Copy,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct AttachArgs {
+    name: String,
+
+    /// Path to an existing PostgreSQL data directory (the one holding PG_VERSION)
+    path: PathBuf,
+
+    #[arg(short, long, value_enum, default_value_t = default_attach_mode())]
+    #[serde(default = "default_attach_mode")]
+    mode: TransferMode,
+}
+
+#[derive(Args, Debug, Deserialize)]
+pub struct DetachArgs {
     name: String,
+
+    /// Where to place the branch's PostgreSQL data directory
+    dest: PathBuf,
+
+    #[arg(short, long, value_enum, default_value_t = default_detach_mode())]
+    #[serde(default = "default_detach_mode")]
+    mode: TransferMode,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Deserialize)]
 pub struct ShowArgs {
     id: String,
 }
 
+/// A dBranch project as seen by the storage layer.
+#[derive(Debug, Clone)]
+pub struct Project {
+    pub name: String,
+    /// Directory holding the project's files (e.g. the btrfs image)
+    pub path: PathBuf,
+}
+
 pub struct AppState {
     pub config: Config,
+    /// `--port-range` override; takes precedence over the config for this run only
+    pub port_range: Option<PortRange>,
+    /// `--dry-run`; also copied into the config so operators see it
+    pub dry_run: bool,
+    /// `--restart`; copied into the config like `dry_run`
+    pub restart: Option<RestartPolicy>,
 }
 
 pub struct CliHandler {
@@ -103,7 +767,9 @@ pub struct CliHandler {
 }
 
 impl CliHandler {
-    pub fn new(state: AppState) -> Self {
+    pub fn new(mut state: AppState) -> Self {
+        state.config.dry_run = state.dry_run;
+        state.config.restart_override = state.restart;
         Self { state }
     }
 
@@ -118,13 +784,53 @@ impl CliHandler {
             }
             Commands::List => {
                 info!("Listing all branch projects");
-                Err(AppError::NotImplemented {
-                    command: "list".into(),
-                })
+
+                let containers = self
+                    .database_operator()
+                    .list_databases(self.state.config.clone())
+                    .await?;
+
+                let mut table = Table::new();
+                table.add_row(Row::new(vec![
+                    Cell::new("Branch").with_style(Attr::Bold),
+                    Cell::new("Port").with_style(Attr::Bold),
+                    Cell::new("Created").with_style(Attr::Bold),
+                    Cell::new("Tracked").with_style(Attr::Bold),
+                ]));
+                for branch in &containers {
+                    let tracked = self
+                        .state
+                        .config
+                        .branches
+                        .iter()
+                        .any(|b| b.name == branch.name);
+                    table.add_row(Row::new(vec![
+                        Cell::new(branch.name.as_str()),
+                        Cell::new(branch.port.to_string().as_str()),
+                        Cell::new(
+                            branch
+                                .created_at
+                                .format("%Y-%m-%d %H:%M")
+                                .to_string()
+                                .as_str(),
+                        ),
+                        Cell::new(if tracked {
+                            "✅"
+                        } else {
+                            "⚠️ Not in config"
+                        }),
+                    ]));
+                }
+                let _ = table.print_tty(true);
+                Ok(())
             }
             Commands::Init(args) => {
                 info!("Initializing dBranch instance: {}", args.name);
                 debug!("Init args: name={}, port={}", args.name, args.port);
+                validate_name(&args.name)?;
+
+                debug!("Adding project to configuration");
+                self.state.config.name = args.name.clone();
 
                 // Initialize individual BTRFS filesystem for this project
                 {
@@ -133,13 +839,24 @@ impl CliHandler {
                         args.name
                     );
 
+                    let mut btrfs = self.btrfs_operator();
+                    if self.state.config.approach == Approach::NewDisk && btrfs.has_image() {
+                        // Formatting it again would wipe the branches of an earlier init
+                        btrfs.mount_existing_disk()?;
+                    } else {
+                        btrfs.reserve_space().map_err(|e| AppError::DiskMount {
+                            message: "Failed to reserve the disk image".to_string(),
+                            source: Some(e.into()),
+                        })?;
+                        btrfs.mount_disk()?;
+                    }
+
                     info!("Project '{}' initialized with main subvolume", args.name);
                 }
 
-                debug!("Adding project to configuration");
-                self.state.config.name = args.name.clone();
+                self.state.config.generate_password()?;
 
-                self.state.config.save_config();
+                self.state.config.save_config()?;
 
                 info!("Project {} initialized successfully", args.name);
                 Ok(())
@@ -147,57 +864,152 @@ impl CliHandler {
             Commands::InitPostgres => {
                 info!("Initializing standalone PostgreSQL database");
 
-                self.create_postgres(None, self.state.config.get_valid_port().unwrap())
-                    .await;
+                self.create_postgres(None, self.get_valid_port()?).await?;
 
                 info!("Standalone PostgreSQL database initialized successfully");
                 Ok(())
             }
             Commands::Create(args) => {
                 info!("Creating new branch project: {}", args.name.clone());
-                if let Some(ref source) = args.source {
-                    debug!("Creating from source: {}", source);
+                validate_name(&args.name)?;
+                // Before any snapshot work, which would clobber the branch's data
+                if self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .any(|b| b.name == args.name)
+                {
+                    return Err(AppError::BranchAlreadyExists { name: args.name });
+                }
+                if !args.read_only {
+                    self.database_operator().ensure_available().await?;
+                }
+                if let Some(url) = &args.from_url {
+                    return self.create_from_url(&args.name, url).await;
                 }
 
-                let project_name = self.state.config.name.clone();
+                let source = args.source.clone().unwrap_or_else(|| String::from("main"));
+                if !self.state.config.branches.iter().any(|b| b.name == source) {
+                    return Err(AppError::BranchNotFound { name: source });
+                }
+                debug!("Creating from source: {}", source);
+
+                let valid_port = self.get_valid_port()?;
+                create_one(
+                    self.state.config.clone(),
+                    args.name.clone(),
+                    source.clone(),
+                    valid_port,
+                    args.read_only,
+                )
+                .await?;
+
+                if args.read_only {
+                    println!(
+                        "📖 Read-only branch {} created without a database container",
+                        args.name
+                    );
+                }
 
-                let src_path = Path::new(&self.state.config.mount_point)
-                    .join(&project_name.clone())
-                    .join("main/data");
+                self.state
+                    .config
+                    .create_branch(args.name.clone(), valid_port, args.read_only)?;
+                audit::record(&self.state.config, &args.name, Operation::Created);
 
-                let dest_path = Path::new(&self.state.config.mount_point)
-                    .join(&project_name.clone())
-                    .join(&args.name)
-                    .join("data");
+                self.record_provenance(&args.name, &source, args.read_only)
+                    .await;
+                Ok(())
+            }
+
+            Commands::Delete(args) => {
+                info!("Deleting branch project: {}", args.id);
+                self.ensure_unlocked(args.force, "delete")?;
+
+                let branch = self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .find(|b| b.name == args.id)
+                    .cloned()
+                    .ok_or(AppError::BranchNotFound {
+                        name: args.id.clone(),
+                    })?;
+
+                if branch.is_main {
+                    return Err(AppError::Config {
+                        message: format!("Cannot delete the main branch '{}'", branch.name),
+                    });
+                }
+
+                let is_active = self.state.config.active_branch.as_deref() == Some(&branch.name);
+                if is_active && !args.force {
+                    return Err(AppError::Config {
+                        message: format!(
+                            "Branch '{}' is active; switch with `dbranch use` or pass --force",
+                            branch.name
+                        ),
+                    });
+                }
+
+                if is_active {
+                    info!("Clearing active branch {}", branch.name);
+                    self.state.config.active_branch = None;
+                }
+                self.delete_branch(&branch, args.commit_after).await?;
 
+                println!("🗑️  Branch {} deleted", branch.name);
+                Ok(())
+            }
+            Commands::Prune(args) => {
                 info!(
-                    "Copying data from {:?} to {:?}",
-                    src_path.clone(),
-                    dest_path.clone()
+                    "Pruning branches older than {}",
+                    format_age(args.older_than)
                 );
+                self.ensure_unlocked(args.force, "prune")?;
 
-                snapshot::snapshot(&src_path, &dest_path).unwrap();
+                let cutoff = Utc::now() - args.older_than;
+                let active = self.state.config.active_branch.as_deref();
+                let stale: Vec<Branch> = self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .filter(|b| !b.is_main && Some(b.name.as_str()) != active)
+                    .filter(|b| b.created_at < cutoff)
+                    .cloned()
+                    .collect();
 
-                let valid_port = self.state.config.get_valid_port().unwrap();
+                if stale.is_empty() {
+                    println!("✅ No branch is older than {}", format_age(args.older_than));
+                    return Ok(());
+                }
 
-                // Create PostgreSQL database
-                self.create_postgres(Some(args.name.clone()), valid_port)
-                    .await;
+                let mut failed = 0;
+                for branch in &stale {
+                    let age = format_age(Utc::now() - branch.created_at);
+                    if self.state.dry_run {
+                        println!("🔍 {} would be deleted ({} old)", branch.name, age);
+                        continue;
+                    }
 
-                self.state
-                    .config
-                    .create_branch(args.name.clone(), valid_port);
+                    match self.delete_branch(branch, false).await {
+                        Ok(()) => println!("🗑️  Branch {} deleted ({} old)", branch.name, age),
+                        Err(e) => {
+                            failed += 1;
+                            println!("❌ {} failed to delete: {}", branch.name, e.chain())
+                        }
+                    }
+                }
 
+                if failed > 0 {
+                    return Err(AppError::Internal {
+                        message: format!("{} of {} branches failed", failed, stale.len()),
+                    });
+                }
                 Ok(())
             }
-
-            Commands::Delete(args) => {
-                info!("Deleting branch project: {}", args.id);
-                debug!("Delete command not yet implemented");
-                Err(AppError::NotImplemented {
-                    command: "delete".into(),
-                })
-            }
             Commands::DeleteProject(args) => {
                 info!("Deleting project: {}", args.name);
 
@@ -205,8 +1017,9 @@ impl CliHandler {
                     debug!("Project {} not found in config", args.name);
                     return Err(AppError::ProjectNotFound { name: args.name });
                 }
+                self.ensure_unlocked(args.force, "delete-project")?;
 
-                let postgres_operator = PostgresOperator::new();
+                let db_operator = self.database_operator();
 
                 for branch in self
                     .state
@@ -218,14 +1031,15 @@ impl CliHandler {
                 {
                     debug!("Deleting branch: {}", branch.name);
 
-                    let _ = postgres_operator
+                    let _ = db_operator
                         .delete_database(self.state.config.clone(), branch.name.as_str())
                         .await;
+                    audit::record(&self.state.config, &branch.name, Operation::Deleted);
                 }
 
                 self.state.config.branches.clear();
 
-                self.state.config.save_config();
+                self.state.config.save_config()?;
 
                 info!("Project {} deleted successfully", args.name);
                 Ok(())
@@ -238,29 +1052,70 @@ impl CliHandler {
                 })
             }
             Commands::Use(args) => {
-                info!("Switching to branch: {}", args.name);
+                // `-` switches back, like `git checkout -`
+                let name = if args.name == "-" {
+                    self.state
+                        .config
+                        .previous_branch
+                        .clone()
+                        .ok_or(AppError::Config {
+                            message: "No previous branch to switch back to".to_string(),
+                        })?
+                } else {
+                    args.name
+                };
+                info!("Switching to branch: {}", name);
 
-                self.state
+                if let Some(branch) = self
+                    .state
                     .config
-                    .set_active_branch(args.name.clone())
-                    .unwrap();
+                    .branches
+                    .iter()
+                    .find(|b| b.name == name && b.read_only)
+                {
+                    return Err(AppError::Config {
+                        message: format!(
+                            "Branch '{}' is read-only and has no database to use",
+                            branch.name
+                        ),
+                    });
+                }
 
-                info!("Switched to branch: {} successfully", args.name);
-                Ok(())
+                self.state.config.set_active_branch(name.clone())?;
+                audit::record(&self.state.config, &name, Operation::Used);
+
+                match RuntimeState::load() {
+                    Some(proxy) if args.bounce => self.bounce_proxy(proxy, &name).await?,
+                    Some(proxy) => warn!(
+                        "The proxy on {} (pid {}) routes only new connections to {}; open \
+                         connections stay on {}, pass --bounce to close them",
+                        proxy.proxy_addr,
+                        proxy.pid,
+                        name,
+                        proxy.active_branch.as_deref().unwrap_or("their branch")
+                    ),
+                    None if args.bounce => debug!("No proxy running, nothing to bounce"),
+                    None => {}
+                }
+
+                info!("Switched to branch: {} successfully", name);
+                Ok(())
             }
-            Commands::Status => {
+            Commands::Status(args) => {
                 info!("Showing status of the project");
 
-                let postgres_operator = PostgresOperator::new();
-
-                println!("{}", String::from("=").repeat(80));
-                println!("PROJECT: {}", self.state.config.name);
-                println!("{}", String::from("-").repeat(80));
-                println!("Path: {}", DEFAULT_CONFIG_PATH.to_string_lossy());
-                println!(
-                    "🌿 Active Branch: {}",
-                    self.state.config.active_branch.as_deref().unwrap_or("none")
-                );
+                if args.json {
+                    let status = project_status(&self.state.config).await?;
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&status).map_err(|e| {
+                            AppError::Internal {
+                                message: format!("Failed to serialize status: {}", e),
+                            }
+                        })?
+                    );
+                    return Ok(());
+                }
 
                 let main_branch = self
                     .state
@@ -269,17 +1124,17 @@ impl CliHandler {
                     .iter()
                     .find(|p| p.is_main)
                     .map(|b| {
-                        (
+                        Ok::<_, AppError>((
                             Path::new(&self.state.config.mount_point).join(&b.name),
-                            get_folder_size(
+                            get_folder_size_ignoring(
                                 &Path::new(&self.state.config.mount_point)
                                     .join(self.state.config.name.clone())
                                     .join(&b.name),
-                            )
-                            .unwrap(),
-                        )
+                                &self.state.config.ignore_in_sizing,
+                            )?,
+                        ))
                     })
-                    .unwrap();
+                    .unwrap()?;
 
                 let branches: Vec<(PathBuf, FolderInfo)> = self
                     .state
@@ -288,55 +1143,80 @@ impl CliHandler {
                     .iter()
                     .filter(|p| !p.is_main)
                     .map(|b| {
-                        (
+                        Ok((
                             Path::new(&self.state.config.mount_point).join(&b.name),
-                            get_folder_size(
+                            get_folder_size_ignoring(
                                 &Path::new(&self.state.config.mount_point)
                                     .join(self.state.config.name.clone())
                                     .join(&b.name),
-                            )
-                            .unwrap(),
-                        )
+                                &self.state.config.ignore_in_sizing,
+                            )?,
+                        ))
                     })
-                    .collect();
+                    .collect::<Result<_, AppError>>()?;
+
+                println!("{}", String::from("=").repeat(80));
+                println!("PROJECT: {}", self.state.config.name);
+                println!("{}", String::from("-").repeat(80));
+                println!("Path: {}", config_path().display());
+                println!(
+                    "🌿 Active Branch: {}",
+                    self.state.config.active_branch.as_deref().unwrap_or("none")
+                );
+                println!(
+                    "{}",
+                    if self.state.config.locked {
+                        "🔒 Locked"
+                    } else {
+                        "🔓 Unlocked"
+                    }
+                );
+                match RuntimeState::load() {
+                    Some(proxy) => println!(
+                        "📡 Proxy: running on {} (pid {}), serving {}",
+                        proxy.proxy_addr,
+                        proxy.pid,
+                        proxy.active_branch.as_deref().unwrap_or("no branch")
+                    ),
+                    None => println!("📡 Proxy: not running"),
+                }
 
                 println!("{}", String::from("-").repeat(80));
 
                 let mut table = Table::new();
 
-                table.add_row(Row::new(vec![
+                let mut header = Row::new(vec![
                     Cell::new("Branch").with_style(Attr::Bold),
                     Cell::new("Logical Size").with_style(Attr::Bold),
+                    Cell::new("Data Only").with_style(Attr::Bold),
+                    Cell::new("On Disk").with_style(Attr::Bold),
                     Cell::new("Unique Data").with_style(Attr::Bold),
+                    Cell::new("Shared w/ main").with_style(Attr::Bold),
+                    Cell::new("Compressed").with_style(Attr::Bold),
                     Cell::new("Container").with_style(Attr::Bold),
                     Cell::new("Age").with_style(Attr::Bold),
-                ]));
+                ]);
+                if args.live {
+                    header.add_cell(Cell::new("Connections").with_style(Attr::Bold));
+                    header.add_cell(Cell::new("DB Size").with_style(Attr::Bold));
+                }
+                table.add_row(header);
 
-                let main_container_status = postgres_operator
-                    .is_container_running(format!("{}_main", self.state.config.name).as_str())
-                    .await
-                    .unwrap_or(false);
+                let main_health = container_health(&self.state.config.container_name("main")).await;
+                let main_container_status = main_health.is_running();
 
                 let main_age = {
                     let duration = Utc::now() - self.state.config.created_at;
-                    if duration.num_days() > 0 {
-                        format!("{}d", duration.num_days())
-                    } else if duration.num_hours() > 0 {
-                        format!("{}h", duration.num_hours())
-                    } else {
-                        format!("{}m", duration.num_minutes())
-                    }
+                    format_age(duration)
                 };
 
-                // table.add_row(Row::new(vec![
-                //     Cell::new("📦 Shared Base"),
-                //     Cell::new(&Size::from_bytes(main_branch.1.shared_size).to_string()),
-                //     Cell::new("-"),
-                //     Cell::new("🔗 Shared"),
-                //     Cell::new("-"),
-                // ]));
+                let main_extents = get_physical_extents(
+                    &Path::new(&self.state.config.mount_point)
+                        .join(&self.state.config.name)
+                        .join("main"),
+                );
 
-                table.add_row(Row::new(vec![
+                let mut main_row = Row::new(vec![
                     Cell::new("main").with_style(Attr::Bold),
                     Cell::new(
                         Size::from_bytes(main_branch.1.logical_size)
@@ -344,27 +1224,43 @@ impl CliHandler {
                             .as_str(),
                     ),
                     Cell::new(
-                        Size::from_bytes(main_branch.1.logical_size - main_branch.1.shared_size)
+                        Size::from_bytes(main_branch.1.logical_size - main_branch.1.ignored_size)
                             .to_string()
                             .as_str(),
                     ),
-                    Cell::new(if main_container_status {
-                        "✅ Running"
-                    } else {
-                        "❌ Stopped"
-                    }),
+                    Cell::new(
+                        Size::from_bytes(main_branch.1.allocated_size)
+                            .to_string()
+                            .as_str(),
+                    ),
+                    Cell::new(
+                        Size::from_bytes(main_branch.1.unique_size())
+                            .to_string()
+                            .as_str(),
+                    ),
+                    Cell::new("-"),
+                    Cell::new(format_ratio(main_branch.1.compression_ratio()).as_str()),
+                    Cell::new(main_health.label()),
                     Cell::new(main_age.as_str()),
-                ]));
+                ]);
+                if args.live {
+                    let activity = if main_container_status {
+                        database_activity(&self.state.config, "main").await
+                    } else {
+                        None
+                    };
+                    for cell in activity_cells(activity) {
+                        main_row.add_cell(cell);
+                    }
+                }
+                table.add_row(main_row);
 
                 for branch in branches {
                     let branch_name = branch.0.file_name().unwrap().to_string_lossy().to_string();
 
-                    let container_status = postgres_operator
-                        .is_container_running(
-                            format!("{}_{}", self.state.config.name, branch_name).as_str(),
-                        )
-                        .await
-                        .unwrap_or(false);
+                    let health =
+                        container_health(&self.state.config.container_name(&branch_name)).await;
+                    let container_status = health.is_running();
 
                     let age = {
                         let duration = Utc::now()
@@ -376,30 +1272,52 @@ impl CliHandler {
                                 .find(|b| b.name == branch_name)
                                 .unwrap()
                                 .created_at;
-                        if duration.num_days() > 0 {
-                            format!("{}d", duration.num_days())
-                        } else if duration.num_hours() > 0 {
-                            format!("{}h", duration.num_hours())
-                        } else {
-                            format!("{}m", duration.num_minutes())
-                        }
+                        format_age(duration)
                     };
 
-                    table.add_row(Row::new(vec![
+                    let shared_with_main = overlap_size(
+                        &main_extents,
+                        &get_physical_extents(
+                            &Path::new(&self.state.config.mount_point)
+                                .join(&self.state.config.name)
+                                .join(&branch_name),
+                        ),
+                    );
+
+                    let mut row = Row::new(vec![
                         Cell::new(branch_name.as_str()),
                         Cell::new(Size::from_bytes(branch.1.logical_size).to_string().as_str()),
                         Cell::new(
-                            Size::from_bytes(branch.1.logical_size - branch.1.shared_size)
+                            Size::from_bytes(branch.1.logical_size - branch.1.ignored_size)
                                 .to_string()
                                 .as_str(),
                         ),
-                        Cell::new(if container_status {
-                            "✅ Running"
-                        } else {
-                            "❌ Stopped"
-                        }),
+                        Cell::new(
+                            Size::from_bytes(branch.1.allocated_size)
+                                .to_string()
+                                .as_str(),
+                        ),
+                        Cell::new(
+                            Size::from_bytes(branch.1.unique_size())
+                                .to_string()
+                                .as_str(),
+                        ),
+                        Cell::new(Size::from_bytes(shared_with_main).to_string().as_str()),
+                        Cell::new(format_ratio(branch.1.compression_ratio()).as_str()),
+                        Cell::new(health.label()),
                         Cell::new(age.as_str()),
-                    ]));
+                    ]);
+                    if args.live {
+                        let activity = if container_status {
+                            database_activity(&self.state.config, &branch_name).await
+                        } else {
+                            None
+                        };
+                        for cell in activity_cells(activity) {
+                            row.add_cell(cell);
+                        }
+                    }
+                    table.add_row(row);
                 }
 
                 let _ = table.print_tty(true);
@@ -407,7 +1325,7 @@ impl CliHandler {
                 println!("{}", String::from("=").repeat(80));
                 Ok(())
             }
-            Commands::Stop => {
+            Commands::Stop(args) => {
                 info!("Stopping all branches and containers");
 
                 debug!(
@@ -415,22 +1333,63 @@ impl CliHandler {
                     self.state.config.name
                 );
 
-                let postgres_operator = PostgresOperator::new();
+                let db_operator = self.database_operator();
+                db_operator.ensure_available().await?;
+                let timeout = Duration::from_secs(
+                    args.timeout.unwrap_or(self.state.config.stop_timeout) as u64,
+                );
 
+                let mut failed = 0;
                 for branch in &self.state.config.branches {
                     debug!("Stopping branch container: {}", branch.name);
-                    let _ = postgres_operator
-                        .stop_database(self.state.config.clone(), &branch.name)
-                        .await;
+                    match db_operator
+                        .stop_database(self.state.config.clone(), &branch.name, timeout)
+                        .await
+                    {
+                        Ok(StopOutcome::Graceful) => {
+                            println!("✅ {} stopped", branch.name);
+                            audit::record(&self.state.config, &branch.name, Operation::Stopped);
+                        }
+                        Ok(StopOutcome::Forced) => {
+                            println!("⚠️  {} did not stop in time and was killed", branch.name);
+                            audit::record(&self.state.config, &branch.name, Operation::Stopped);
+                        }
+                        Ok(StopOutcome::NotRunning) => {
+                            println!("➖ {} was not running", branch.name)
+                        }
+                        Ok(StopOutcome::Skipped) => {
+                            println!("🔍 {} would be stopped", branch.name)
+                        }
+                        Err(e) => {
+                            failed += 1;
+                            println!("❌ {} failed to stop: {}", branch.name, e.chain())
+                        }
+                    }
                 }
-                let _ = postgres_operator
-                    .stop_database(self.state.config.clone(), &self.state.config.name)
+                let _ = db_operator
+                    .stop_database(self.state.config.clone(), &self.state.config.name, timeout)
                     .await;
 
+                // A container still running would keep writing to the disk
+                if failed > 0 {
+                    warn!("Some containers are still running, leaving the disk mounted");
+                    return Err(AppError::Internal {
+                        message: format!(
+                            "{} of {} branches failed to stop",
+                            failed,
+                            self.state.config.branches.len()
+                        ),
+                    });
+                }
+
                 debug!(
                     "Unmounting BTRFS filesystem for project: {}",
                     self.state.config.name
                 );
+                let btrfs = self.btrfs_operator();
+                if btrfs.is_mounted() {
+                    btrfs.unmount_disk()?;
+                }
 
                 info!("All branches and containers stopped successfully");
                 Ok(())
@@ -440,42 +1399,2447 @@ impl CliHandler {
 
                 debug!("Resuming project: {}", self.state.config.name);
 
-                let postgres_operator = PostgresOperator::new();
-                let _ = postgres_operator
-                    .create_database(
-                        self.state.config.clone(),
-                        self.state.config.get_valid_port().unwrap(),
-                        "main",
-                    )
-                    .await;
+                let db_operator = self.database_operator();
+                db_operator.ensure_available().await?;
+
+                debug!(
+                    "Mounting BTRFS filesystem for project: {}",
+                    self.state.config.name
+                );
+                self.btrfs_operator().mount_existing_disk()?;
+
+                // Main is listed among the branches
+                let (mut started, mut skipped, mut failed) = (0, 0, 0);
+                for branch in self.state.config.branches.iter().filter(|b| !b.read_only) {
+                    let container_name = self.state.config.container_name(&branch.name);
+                    if db_operator.is_container_running(&container_name).await? {
+                        println!("➖ {} was already running", branch.name);
+                        skipped += 1;
+                        continue;
+                    }
 
-                for branch in &self.state.config.branches {
                     debug!("Starting branch container: {}", branch.name);
-                    let _ = postgres_operator
-                        .create_database(self.state.config.clone(), branch.port, &branch.name)
+                    // Reuse a stopped container, so that its settings are kept
+                    let result = match start_container(&self.state.config, &branch.name).await {
+                        Err(AppError::ContainerNotFound { .. }) => {
+                            db_operator
+                                .create_database(
+                                    self.state.config.clone(),
+                                    branch.port,
+                                    &branch.name,
+                                )
+                                .await
+                        }
+                        result => result,
+                    };
+
+                    match result {
+                        Ok(()) => {
+                            println!("✅ {} started on port {}", branch.name, branch.port);
+                            started += 1;
+                        }
+                        Err(e) => {
+                            println!("❌ {} failed to start: {}", branch.name, e.chain());
+                            failed += 1;
+                        }
+                    }
+                }
+
+                println!(
+                    "{} started, {} already running, {} failed",
+                    started, skipped, failed
+                );
+                if failed > 0 {
+                    return Err(AppError::Internal {
+                        message: format!(
+                            "{} of {} branches failed to start",
+                            failed,
+                            started + skipped + failed
+                        ),
+                    });
+                }
+                info!("Resumed project {}", self.state.config.name);
+                Ok(())
+            }
+            Commands::Validate => {
+                info!("Validating project: {}", self.state.config.name);
+
+                let report = validate_project(&self.state.config);
+
+                if report.problems.is_empty() {
+                    println!("✅ Project '{}' is consistent", self.state.config.name);
+                    return Ok(());
+                }
+
+                let mut table = Table::new();
+
+                table.add_row(Row::new(vec![
+                    Cell::new("Severity").with_style(Attr::Bold),
+                    Cell::new("Subject").with_style(Attr::Bold),
+                    Cell::new("Problem").with_style(Attr::Bold),
+                ]));
+
+                for problem in &report.problems {
+                    table.add_row(Row::new(vec![
+                        Cell::new(match problem.severity {
+                            Severity::Error => "❌ Error",
+                            Severity::Warning => "⚠️ Warning",
+                        }),
+                        Cell::new(problem.subject.as_str()),
+                        Cell::new(problem.message.as_str()),
+                    ]));
+                }
+
+                let _ = table.print_tty(true);
+
+                println!(
+                    "{} error(s), {} warning(s)",
+                    report.error_count(),
+                    report.warning_count()
+                );
+
+                if report.error_count() > 0 {
+                    return Err(AppError::ValidationFailed {
+                        errors: report.error_count(),
+                    });
+                }
+
+                Ok(())
+            }
+            Commands::DumpConfig => {
+                debug!("Dumping effective configuration");
+
+                let json =
+                    serde_json::to_string_pretty(&self.state.config.redacted()).map_err(|e| {
+                        AppError::Internal {
+                            message: format!("Failed to serialize configuration: {}", e),
+                        }
+                    })?;
+
+                println!("{}", json);
+                Ok(())
+            }
+            Commands::Clean => {
+                info!("Cleaning artifacts of project: {}", self.state.config.name);
+
+                let db_operator = self.database_operator();
+                let mut reclaimed: u64 = 0;
+
+                let removed_containers = remove_stopped_containers(&self.state.config).await?;
+                for (container_name, size) in &removed_containers {
+                    println!("🗑️  Removed container {}", container_name);
+                    reclaimed += size;
+                }
+
+                let removed_volumes = remove_dangling_volumes(&self.state.config).await?;
+                for volume in &removed_volumes {
+                    println!("🗑️  Removed volume {}", volume);
+                }
+
+                let project_path =
+                    Path::new(&self.state.config.mount_point).join(&self.state.config.name);
+                let mut removed_dirs = 0;
+
+                if let Ok(entries) = std::fs::read_dir(&project_path) {
+                    for entry in entries.flatten() {
+                        let branch_name = entry.file_name().to_string_lossy().to_string();
+                        let data_dir = entry.path().join("data");
+
+                        let is_empty = std::fs::read_dir(&data_dir)
+                            .map(|mut d| d.next().is_none())
+                            .unwrap_or(false);
+                        if !is_empty {
+                            continue;
+                        }
+
+                        let is_running = db_operator
+                            .is_container_running(
+                                self.state.config.container_name(&branch_name).as_str(),
+                            )
+                            .await
+                            .unwrap_or(false);
+                        if is_running {
+                            debug!("Skipping empty data dir of running branch {}", branch_name);
+                            continue;
+                        }
+
+                        debug!("Removing empty data directory {:?}", data_dir);
+                        if self
+                            .state
+                            .config
+                            .skip_for_dry_run(&format!("remove {:?}", data_dir))
+                        {
+                            continue;
+                        }
+                        std::fs::remove_dir(&data_dir).map_err(|e| AppError::FileSystem {
+                            message: format!("Failed to remove directory {:?}", data_dir),
+                            source: Some(e.into()),
+                        })?;
+                        // Only drop the branch directory too if nothing else lives there
+                        let _ = std::fs::remove_dir(entry.path());
+
+                        println!("🗑️  Removed empty data directory {:?}", data_dir);
+                        removed_dirs += 1;
+                    }
+                }
+
+                println!(
+                    "🧹 Removed {} container(s), {} volume(s) and {} empty data dir(s), reclaimed {}",
+                    removed_containers.len(),
+                    removed_volumes.len(),
+                    removed_dirs,
+                    Size::from_bytes(reclaimed)
+                );
+                Ok(())
+            }
+            Commands::Attach(args) => {
+                info!("Attaching {:?} as branch {}", args.path, args.name);
+                validate_name(&args.name)?;
+
+                if self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .any(|b| b.name == args.name)
+                {
+                    return Err(AppError::BranchAlreadyExists { name: args.name });
+                }
+
+                if !args.path.join("PG_VERSION").is_file() {
+                    return Err(AppError::FileSystem {
+                        message: format!(
+                            "{:?} is not a PostgreSQL data directory (missing PG_VERSION)",
+                            args.path
+                        ),
+                        source: None,
+                    });
+                }
+
+                // The container uses <branch>/data as volume and data/pgdata as PGDATA
+                let dest_path = Path::new(&self.state.config.mount_point)
+                    .join(&self.state.config.name)
+                    .join(&args.name)
+                    .join("data")
+                    .join("pgdata");
+
+                if dest_path.exists() {
+                    return Err(AppError::FileSystem {
+                        message: format!("Destination {:?} already exists", dest_path),
+                        source: None,
+                    });
+                }
+
+                // Before moving the data, so that a full range leaves it in place
+                let valid_port = self.get_valid_port()?;
+
+                if !self.state.config.skip_for_dry_run(&format!(
+                    "{:?} {:?} to {:?}",
+                    args.mode, args.path, dest_path
+                )) {
+                    transfer_dir(&args.path, &dest_path, args.mode)?;
+                }
+
+                self.create_postgres(Some(args.name.clone()), valid_port)
+                    .await?;
+
+                self.state
+                    .config
+                    .create_branch(args.name.clone(), valid_port, false)?;
+                audit::record(&self.state.config, &args.name, Operation::Attached);
+
+                info!("Branch {} attached on port {}", args.name, valid_port);
+                Ok(())
+            }
+            Commands::Detach(args) => {
+                info!("Detaching branch {} to {:?}", args.name, args.dest);
+
+                let branch = self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .find(|b| b.name == args.name)
+                    .cloned()
+                    .ok_or(AppError::BranchNotFound {
+                        name: args.name.clone(),
+                    })?;
+
+                if branch.is_main {
+                    return Err(AppError::Permission {
+                        message: "The main branch cannot be detached".to_string(),
+                    });
+                }
+
+                if args.dest.exists() {
+                    return Err(AppError::FileSystem {
+                        message: format!("Destination {:?} already exists", args.dest),
+                        source: None,
+                    });
+                }
+
+                let branch_path = Path::new(&self.state.config.mount_point)
+                    .join(&self.state.config.name)
+                    .join(&branch.name);
+                let data_path = branch_path.join("data").join("pgdata");
+
+                if !data_path.is_dir() {
+                    return Err(AppError::FileSystem {
+                        message: format!("Branch data directory {:?} not found", data_path),
+                        source: None,
+                    });
+                }
+
+                self.remove_container(&branch.name).await?;
+
+                if !self.state.config.skip_for_dry_run(&format!(
+                    "{:?} {:?} to {:?}",
+                    args.mode, data_path, args.dest
+                )) {
+                    transfer_dir(&data_path, &args.dest, args.mode)?;
+
+                    if let TransferMode::Reflink = args.mode {
+                        // The data now lives at dest, drop the copy dBranch no longer manages
+                        let mut keep: Vec<PathBuf> = self
+                            .state
+                            .config
+                            .branches
+                            .iter()
+                            .filter(|b| b.name != branch.name)
+                            .map(|b| {
+                                Path::new(&self.state.config.mount_point)
+                                    .join(&self.state.config.name)
+                                    .join(&b.name)
+                            })
+                            .collect();
+                        keep.push(args.dest.clone());
+
+                        let reclaimed = snapshot::remove(&branch_path, &keep)?;
+                        println!("🗑️  Reclaimed {}", Size::from_bytes(reclaimed));
+                    } else {
+                        let _ = std::fs::remove_dir(branch_path.join("data"));
+                        let _ = std::fs::remove_dir(&branch_path);
+                    }
+                }
+
+                if self.state.config.active_branch.as_deref() == Some(branch.name.as_str()) {
+                    debug!("Detached branch was active, switching back to main");
+                    self.state.config.active_branch = None;
+                }
+                self.state.config.remove_branch(&branch.name)?;
+                audit::record(&self.state.config, &branch.name, Operation::Detached);
+
+                info!("Branch {} detached to {:?}", branch.name, args.dest);
+                Ok(())
+            }
+            Commands::CreateMany(args) => {
+                info!("Creating {} branches", args.names.len());
+                self.database_operator().ensure_available().await?;
+
+                let mut results: Vec<(String, Result<u16, String>)> = Vec::new();
+                let mut reserved: Vec<u16> =
+                    self.state.config.branches.iter().map(|b| b.port).collect();
+                let mut pending: Vec<(String, u16)> = Vec::new();
+
+                // Reserve every port up front so concurrent creations never collide
+                for name in args.names {
+                    if let Err(e) = validate_name(&name) {
+                        results.push((name, Err(e.to_string())));
+                        continue;
+                    }
+                    if self.state.config.branches.iter().any(|b| b.name == name)
+                        || pending.iter().any(|(n, _)| *n == name)
+                    {
+                        results.push((
+                            name.clone(),
+                            Err(format!("Branch '{}' already exists", name)),
+                        ));
+                        continue;
+                    }
+
+                    let port_range = self.port_range();
+                    match get_valid_port_excluding(port_range.min, port_range.max, &reserved) {
+                        Some(port) => {
+                            reserved.push(port);
+                            pending.push((name, port));
+                        }
+                        None => results.push((
+                            name,
+                            Err(AppError::NoPortAvailable {
+                                min: port_range.min,
+                                max: port_range.max,
+                            }
+                            .to_string()),
+                        )),
+                    }
+                }
+
+                let semaphore = Arc::new(Semaphore::new(args.jobs.max(1)));
+                let mut tasks = JoinSet::new();
+
+                let mut task_names = HashMap::new();
+
+                for (name, port) in pending {
+                    let task_name = name.clone();
+                    let semaphore = semaphore.clone();
+                    let config = self.state.config.clone();
+
+                    let handle = tasks.spawn(async move {
+                        let outcome = async {
+                            let _permit =
+                                semaphore.acquire_owned().await.map_err(|e| e.to_string())?;
+
+                            create_one(config, name.clone(), String::from("main"), port, false)
+                                .await
+                                .map_err(|e| e.chain())
+                        }
                         .await;
+
+                        (name, outcome.map(|_| port))
+                    });
+                    task_names.insert(handle.id(), task_name);
+                }
+
+                while let Some(joined) = tasks.join_next_with_id().await {
+                    match joined {
+                        Ok((_, result)) => results.push(result),
+                        Err(e) => {
+                            debug!("Branch creation task failed: {}", e);
+                            let name = task_names.remove(&e.id()).unwrap_or_default();
+                            results.push((name, Err(format!("Task failed: {}", e))));
+                        }
+                    }
+                }
+
+                // Commit every successful branch with a single config write
+                for (name, result) in &results {
+                    if let Ok(port) = result {
+                        self.state.config.branches.push(Branch {
+                            name: name.clone(),
+                            port: *port,
+                            is_main: false,
+                            created_at: Utc::now(),
+                            read_only: false,
+                            postgres_config: None,
+                        });
+                    }
+                }
+                self.state.config.save_config()?;
+                for (name, result) in &results {
+                    if result.is_ok() {
+                        audit::record(&self.state.config, name, Operation::Created);
+                        self.record_provenance(name, "main", false).await;
+                    }
+                }
+
+                let mut table = Table::new();
+                table.add_row(Row::new(vec![
+                    Cell::new("Branch").with_style(Attr::Bold),
+                    Cell::new("Result").with_style(Attr::Bold),
+                ]));
+                for (name, result) in &results {
+                    table.add_row(Row::new(vec![
+                        Cell::new(name.as_str()),
+                        Cell::new(
+                            match result {
+                                Ok(port) => format!("✅ Created on port {}", port),
+                                Err(e) => format!("❌ {}", e),
+                            }
+                            .as_str(),
+                        ),
+                    ]));
+                }
+                let _ = table.print_tty(true);
+
+                let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+                if failed > 0 {
+                    return Err(AppError::Internal {
+                        message: format!("{} of {} branches failed", failed, results.len()),
+                    });
                 }
 
-                info!("All branches and containers resumed successfully");
                 Ok(())
             }
-        }
-    }
+            Commands::Verify => {
+                info!("Verifying branches of project {}", self.state.config.name);
 
-    async fn create_postgres(&mut self, name: Option<String>, valid_port: u16) {
-        debug!("Initializing PostgreSQL database creation");
-        let postgres_operator = PostgresOperator::new();
-        debug!(
-            "Finding available port in range {:?}, {:?}",
-            self.state.config.port_min, self.state.config.port_max
-        );
-        info!("Found available port: {}", valid_port);
-        let db_name = name.unwrap_or_else(|| "main".to_string());
-        debug!("Creating PostgreSQL database: {}", db_name);
-        postgres_operator
-            .create_database(self.state.config.clone(), valid_port, db_name.as_str())
-            .await
-            .unwrap();
-        info!("PostgreSQL database created successfully");
+                let lock = LockFile::load()?;
+                let db_operator = self.database_operator();
+
+                let mut table = Table::new();
+                table.add_row(Row::new(vec![
+                    Cell::new("Branch").with_style(Attr::Bold),
+                    Cell::new("Source").with_style(Attr::Bold),
+                    Cell::new("Created").with_style(Attr::Bold),
+                    Cell::new("Image").with_style(Attr::Bold),
+                    Cell::new("Status").with_style(Attr::Bold),
+                ]));
+
+                for branch in &self.state.config.branches {
+                    let Some(entry) = lock.branches.get(&branch.name) else {
+                        if !branch.is_main {
+                            table.add_row(Row::new(vec![
+                                Cell::new(branch.name.as_str()),
+                                Cell::new("-"),
+                                Cell::new("-"),
+                                Cell::new("-"),
+                                Cell::new("⚠️ Not recorded"),
+                            ]));
+                        }
+                        continue;
+                    };
+
+                    let container_name = self.state.config.container_name(&branch.name);
+                    let status = if let Some(content_hash) = &entry.content_hash {
+                        if !db_operator.is_container_running(&container_name).await? {
+                            String::from("⚠️ Stopped, cannot hash")
+                        } else {
+                            match archive::hash_branch(&self.state.config, &branch.name) {
+                                Ok(hash) if hash == *content_hash => String::from("✅ Pristine"),
+                                Ok(_) => String::from("✏️ Modified"),
+                                Err(e) => format!("❌ {}", e.chain()),
+                            }
+                        }
+                    } else {
+                        String::from("📖 Read-only, not hashed")
+                    };
+
+                    table.add_row(Row::new(vec![
+                        Cell::new(branch.name.as_str()),
+                        Cell::new(entry.source.as_str()),
+                        Cell::new(
+                            entry
+                                .created_at
+                                .format("%Y-%m-%d %H:%M")
+                                .to_string()
+                                .as_str(),
+                        ),
+                        Cell::new(entry.image_digest.as_deref().unwrap_or(&entry.image)),
+                        Cell::new(status.as_str()),
+                    ]));
+                }
+
+                let _ = table.print_tty(true);
+                Ok(())
+            }
+            Commands::Context(args) => {
+                let branch = self.resolve_branch(None)?;
+
+                let context = Context {
+                    project: self.state.config.name.clone(),
+                    branch: branch.name.clone(),
+                    port: branch.port,
+                    running: self
+                        .database_operator()
+                        .is_container_running(&self.state.config.container_name(&branch.name))
+                        .await?,
+                };
+
+                if args.json {
+                    println!(
+                        "{}",
+                        serde_json::to_string(&context).map_err(|e| AppError::Internal {
+                            message: format!("Failed to serialize context: {}", e),
+                        })?
+                    );
+                } else {
+                    println!(
+                        "{}/{} :{} {}",
+                        context.project,
+                        context.branch,
+                        context.port,
+                        if context.running { "✅" } else { "❌" }
+                    );
+                }
+
+                Ok(())
+            }
+            Commands::Move(args) => {
+                self.ensure_unlocked(args.force, "move")?;
+
+                let old_project_path =
+                    Path::new(&self.state.config.mount_point).join(&self.state.config.name);
+                let new_project_path =
+                    Path::new(&args.new_mount_point).join(&self.state.config.name);
+
+                info!(
+                    "Moving project {} from {:?} to {:?}",
+                    self.state.config.name, old_project_path, new_project_path
+                );
+
+                if old_project_path == new_project_path {
+                    return Err(AppError::Config {
+                        message: format!("Project is already at {:?}", new_project_path),
+                    });
+                }
+
+                if new_project_path.exists()
+                    && std::fs::read_dir(&new_project_path)
+                        .map(|mut entries| entries.next().is_some())
+                        .unwrap_or(true)
+                {
+                    return Err(AppError::FileSystem {
+                        message: format!("Destination {:?} is not empty", new_project_path),
+                        source: None,
+                    });
+                }
+
+                let new_image_path =
+                    moved_image_path(&args.new_mount_point, &self.state.config.name);
+                if self.state.config.approach == Approach::NewDisk && new_image_path.exists() {
+                    return Err(AppError::FileSystem {
+                        message: format!("Destination {:?} already exists", new_image_path),
+                        source: None,
+                    });
+                }
+
+                if self.state.config.skip_for_dry_run(&format!(
+                    "recreate the containers of {} branch(es) after moving {:?} to {:?}",
+                    self.state.config.branches.len(),
+                    old_project_path,
+                    new_project_path
+                )) {
+                    return Ok(());
+                }
+
+                std::fs::create_dir_all(&new_project_path).map_err(|e| AppError::FileSystem {
+                    message: format!("Failed to create directory {:?}", new_project_path),
+                    source: Some(e.into()),
+                })?;
+
+                // What actually moves: the image of a NewDisk project, whose
+                // mounted filesystem is always another device, or the branches
+                let source_path = match self.state.config.approach {
+                    Approach::NewDisk => self.btrfs_operator().image_path().to_path_buf(),
+                    Approach::ExistingDisk => old_project_path.clone(),
+                };
+                let same_device = {
+                    use std::os::unix::fs::MetadataExt;
+                    let dev = |p: &Path| std::fs::metadata(p).map(|m| m.dev()).ok();
+                    dev(&source_path).is_some() && dev(&source_path) == dev(&new_project_path)
+                };
+
+                if !same_device {
+                    let mut target_config = self.state.config.clone();
+                    target_config.mount_point = args.new_mount_point.clone();
+                    let (_, _, available) = BtrfsOperator::new(
+                        Project {
+                            name: target_config.name.clone(),
+                            path: new_project_path.clone(),
+                        },
+                        target_config,
+                    )
+                    .get_filesystem_info()?;
+                    let needed = match self.state.config.approach {
+                        // Copied sparse, so only the allocated blocks count
+                        Approach::NewDisk => {
+                            use std::os::unix::fs::MetadataExt;
+                            std::fs::metadata(&source_path)
+                                .map(|m| m.blocks() * 512)
+                                .unwrap_or(0)
+                        }
+                        Approach::ExistingDisk => get_folder_size(&old_project_path)
+                            .map(|f| f.logical_size)
+                            .unwrap_or(0),
+                    };
+
+                    if needed > available {
+                        let _ = std::fs::remove_dir(&new_project_path);
+                        return Err(AppError::FileSystem {
+                            message: format!(
+                                "Not enough space at {}: {} needed, {} available",
+                                args.new_mount_point,
+                                Size::from_bytes(needed),
+                                Size::from_bytes(available)
+                            ),
+                            source: None,
+                        });
+                    }
+                }
+
+                // Containers bind-mount the old paths, so they have to be recreated
+                let db_operator = self.database_operator();
+                for branch in &self.state.config.branches {
+                    println!("⏹️  Removing container of {}", branch.name);
+                    self.remove_container(&branch.name).await?;
+                }
+
+                match self.state.config.approach {
+                    Approach::NewDisk => {
+                        self.btrfs_operator()
+                            .remount_disk(&args.new_mount_point, &new_image_path)?;
+                        self.state.config.image_path =
+                            Some(new_image_path.to_string_lossy().to_string());
+                    }
+                    Approach::ExistingDisk if same_device => {
+                        std::fs::rename(&old_project_path, &new_project_path).map_err(|e| {
+                            AppError::FileSystem {
+                                message: format!(
+                                    "Failed to move {:?} to {:?}",
+                                    old_project_path, new_project_path
+                                ),
+                                source: Some(e.into()),
+                            }
+                        })?;
+                    }
+                    Approach::ExistingDisk => {
+                        copy_dir_contents(&old_project_path, &new_project_path)?;
+                        std::fs::remove_dir_all(&old_project_path).map_err(|e| {
+                            AppError::FileSystem {
+                                message: format!(
+                                    "Copied to {:?} but failed to remove {:?}",
+                                    new_project_path, old_project_path
+                                ),
+                                source: Some(e.into()),
+                            }
+                        })?;
+                    }
+                }
+
+                self.state.config.mount_point = args.new_mount_point.clone();
+                self.state.config.save_config()?;
+
+                for branch in self.state.config.branches.iter().filter(|b| !b.read_only) {
+                    println!("▶️  Starting {} on port {}", branch.name, branch.port);
+                    db_operator
+                        .create_database(self.state.config.clone(), branch.port, &branch.name)
+                        .await?;
+                }
+
+                println!(
+                    "✅ Project {} moved to {}",
+                    self.state.config.name, args.new_mount_point
+                );
+                Ok(())
+            }
+            Commands::Export(args) => {
+                info!(
+                    "Exporting project {} to {:?}",
+                    self.state.config.name, args.dir
+                );
+
+                let branches: Vec<Branch> = match &args.branch {
+                    Some(name) => vec![
+                        self.state
+                            .config
+                            .branches
+                            .iter()
+                            .find(|b| &b.name == name)
+                            .cloned()
+                            .ok_or(AppError::BranchNotFound { name: name.clone() })?,
+                    ],
+                    None => self.state.config.branches.clone(),
+                };
+
+                if self.state.config.skip_for_dry_run(&format!(
+                    "export {} branch(es) to {:?}",
+                    branches.len(),
+                    args.dir
+                )) {
+                    return Ok(());
+                }
+
+                std::fs::create_dir_all(&args.dir).map_err(|e| AppError::FileSystem {
+                    message: format!("Failed to create directory {:?}", args.dir),
+                    source: Some(e.into()),
+                })?;
+
+                let mut manifest = Manifest {
+                    project: self.state.config.name.clone(),
+                    engine: self.state.config.engine.clone(),
+                    approach: self.state.config.approach.clone(),
+                    exported_at: Utc::now(),
+                    branches: Vec::new(),
+                };
+                let mut results: Vec<(String, Result<(), AppError>)> = Vec::new();
+                let mut lock = LockFile::load()?;
+
+                // Keep going on failure so one broken branch does not block the rest
+                for branch in branches {
+                    let artifact = archive::artifact_name(&self.state.config, &branch.name);
+                    let result = self.export_branch(&branch, &args.dir.join(&artifact)).await;
+
+                    if result.is_ok() {
+                        manifest.branches.push(ManifestBranch {
+                            name: branch.name.clone(),
+                            port: branch.port,
+                            is_main: branch.is_main,
+                            parent: lock
+                                .branches
+                                .remove(&branch.name)
+                                .map(|entry| entry.source)
+                                .filter(|_| !branch.is_main),
+                            created_at: branch.created_at,
+                            artifact,
+                        });
+                    }
+                    results.push((branch.name, result));
+                }
+
+                manifest.save(&args.dir)?;
+
+                print_transfer_results(&results, "Exported");
+                check_transfer_results(&results)
+            }
+            Commands::Import(args) => {
+                info!("Importing from {:?}", args.dir);
+
+                let manifest = Manifest::load(&args.dir)?;
+
+                if manifest.approach != self.state.config.approach
+                    || manifest.engine != self.state.config.engine
+                {
+                    return Err(AppError::Config {
+                        message: format!(
+                            "Export of '{}' uses {:?}/{:?}, but this project uses {:?}/{:?}",
+                            manifest.project,
+                            manifest.approach,
+                            manifest.engine,
+                            self.state.config.approach,
+                            self.state.config.engine
+                        ),
+                    });
+                }
+
+                let mut branches: Vec<ManifestBranch> = match &args.branch {
+                    Some(name) => vec![
+                        manifest
+                            .branches
+                            .iter()
+                            .find(|b| &b.name == name)
+                            .cloned()
+                            .ok_or(AppError::BranchNotFound { name: name.clone() })?,
+                    ],
+                    None => manifest.branches.clone(),
+                };
+                // Parents first
+                branches.sort_by_key(|b| !b.is_main);
+
+                let mut results: Vec<(String, Result<(), AppError>)> = Vec::new();
+                for branch in branches {
+                    let result = self.import_branch(&branch, &args.dir).await;
+                    results.push((branch.name, result));
+                }
+
+                print_transfer_results(&results, "Imported");
+                check_transfer_results(&results)
+            }
+            Commands::History(args) => {
+                debug!("Showing history of project {}", self.state.config.name);
+
+                let mut records: Vec<AuditRecord> = audit::load()?
+                    .into_iter()
+                    .filter(|r| r.project == self.state.config.name)
+                    .filter(|r| args.branch.as_ref().is_none_or(|b| &r.branch == b))
+                    .collect();
+                if let Some(limit) = args.limit {
+                    records.drain(..records.len().saturating_sub(limit));
+                }
+
+                if args.json {
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&records).map_err(|e| {
+                            AppError::Internal {
+                                message: format!("Failed to serialize history: {}", e),
+                            }
+                        })?
+                    );
+                    return Ok(());
+                }
+
+                if records.is_empty() {
+                    println!("📭 No recorded operations");
+                    return Ok(());
+                }
+
+                let mut table = Table::new();
+                table.add_row(Row::new(vec![
+                    Cell::new("When").with_style(Attr::Bold),
+                    Cell::new("Age").with_style(Attr::Bold),
+                    Cell::new("Branch").with_style(Attr::Bold),
+                    Cell::new("Operation").with_style(Attr::Bold),
+                ]));
+                for record in &records {
+                    table.add_row(Row::new(vec![
+                        Cell::new(
+                            record
+                                .at
+                                .with_timezone(&chrono::Local)
+                                .format("%Y-%m-%d %H:%M:%S")
+                                .to_string()
+                                .as_str(),
+                        ),
+                        Cell::new(format!("{} ago", format_age(Utc::now() - record.at)).as_str()),
+                        Cell::new(record.branch.as_str()),
+                        Cell::new(record.operation.to_string().as_str()),
+                    ]));
+                }
+                let _ = table.print_tty(true);
+                Ok(())
+            }
+            Commands::Snapshot(args) => {
+                info!("Snapshotting branch {} as {}", args.branch, args.name);
+
+                if !self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .any(|b| b.name == args.branch)
+                {
+                    return Err(AppError::BranchNotFound { name: args.branch });
+                }
+
+                if args.name.is_empty() || args.name.contains(['/', '@']) {
+                    return Err(AppError::Config {
+                        message: format!(
+                            "Invalid snapshot name '{}': must be non-empty without '/' or '@'",
+                            args.name
+                        ),
+                    });
+                }
+
+                if self
+                    .state
+                    .config
+                    .snapshots
+                    .iter()
+                    .any(|s| s.branch == args.branch && s.name == args.name)
+                {
+                    return Err(AppError::Config {
+                        message: format!(
+                            "Branch '{}' already has a snapshot named '{}'",
+                            args.branch, args.name
+                        ),
+                    });
+                }
+
+                self.btrfs_operator()
+                    .create_named_snapshot(&args.branch, &args.name)?;
+
+                self.state.config.snapshots.push(Snapshot {
+                    name: args.name.clone(),
+                    branch: args.branch.clone(),
+                    created_at: Utc::now(),
+                });
+                self.state.config.save_config()?;
+
+                println!("📸 Snapshot {}@{} created", args.branch, args.name);
+                Ok(())
+            }
+            Commands::Snapshots(args) => {
+                if let Some(name) = args.delete {
+                    let matching: Vec<Snapshot> = self
+                        .state
+                        .config
+                        .snapshots
+                        .iter()
+                        .filter(|s| s.name == name)
+                        .filter(|s| args.branch.as_ref().is_none_or(|b| &s.branch == b))
+                        .cloned()
+                        .collect();
+
+                    let snapshot = match matching.as_slice() {
+                        [snapshot] => snapshot.clone(),
+                        [] => {
+                            return Err(AppError::FileNotFound {
+                                path: format!("{}@{}", args.branch.as_deref().unwrap_or("*"), name),
+                            });
+                        }
+                        _ => {
+                            return Err(AppError::Config {
+                                message: format!(
+                                    "Several branches have a snapshot named '{}', pass --branch",
+                                    name
+                                ),
+                            });
+                        }
+                    };
+
+                    self.btrfs_operator()
+                        .delete_named_snapshot(&snapshot.branch, &snapshot.name)?;
+
+                    self.state.config.snapshots.retain(|s| *s != snapshot);
+                    self.state.config.save_config()?;
+
+                    println!("🗑️  Snapshot {}@{} deleted", snapshot.branch, snapshot.name);
+                    return Ok(());
+                }
+
+                let snapshots: Vec<&Snapshot> = self
+                    .state
+                    .config
+                    .snapshots
+                    .iter()
+                    .filter(|s| args.branch.as_ref().is_none_or(|b| &s.branch == b))
+                    .collect();
+
+                if snapshots.is_empty() {
+                    println!("📭 No snapshots");
+                    return Ok(());
+                }
+
+                let btrfs_operator = self.btrfs_operator();
+                let mut table = Table::new();
+                table.add_row(Row::new(vec![
+                    Cell::new("Branch").with_style(Attr::Bold),
+                    Cell::new("Snapshot").with_style(Attr::Bold),
+                    Cell::new("Created").with_style(Attr::Bold),
+                    Cell::new("Size").with_style(Attr::Bold),
+                    Cell::new("Exclusive").with_style(Attr::Bold),
+                ]));
+                for snapshot in snapshots {
+                    let (size, exclusive) = match btrfs_operator
+                        .named_snapshot_info(&snapshot.branch, &snapshot.name)
+                    {
+                        Ok(info) => (
+                            Size::from_bytes(info.referenced_size).to_string(),
+                            info.exclusive_size
+                                .map(|size| Size::from_bytes(size).to_string())
+                                .unwrap_or_else(|| String::from("unknown")),
+                        ),
+                        Err(e) => {
+                            debug!("Failed to size snapshot {}: {}", snapshot.name, e);
+                            (String::from("-"), String::from("-"))
+                        }
+                    };
+
+                    table.add_row(Row::new(vec![
+                        Cell::new(snapshot.branch.as_str()),
+                        Cell::new(snapshot.name.as_str()),
+                        Cell::new(
+                            snapshot
+                                .created_at
+                                .format("%Y-%m-%d %H:%M")
+                                .to_string()
+                                .as_str(),
+                        ),
+                        Cell::new(size.as_str()),
+                        Cell::new(exclusive.as_str()),
+                    ]));
+                }
+                let _ = table.print_tty(true);
+                Ok(())
+            }
+            Commands::Lock => {
+                self.state.config.locked = true;
+                self.state.config.save_config()?;
+                println!("🔒 Project {} locked", self.state.config.name);
+                Ok(())
+            }
+            Commands::Unlock => {
+                self.state.config.locked = false;
+                self.state.config.save_config()?;
+                println!("🔓 Project {} unlocked", self.state.config.name);
+                Ok(())
+            }
+            Commands::Batch => {
+                info!("Reading batch commands from stdin");
+
+                let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+                let mut results = StdoutToStderr::redirect()?;
+                let mut failed = 0;
+                let mut index = 0;
+
+                while let Some(line) =
+                    lines.next_line().await.map_err(|e| AppError::FileSystem {
+                        message: "Failed to read batch commands from stdin".to_string(),
+                        source: Some(e.into()),
+                    })?
+                {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    index += 1;
+
+                    let result = match serde_json::from_str::<Commands>(&line) {
+                        Ok(cmd) if let Some(message) = cmd.batch_conflict() => {
+                            Err(AppError::Config {
+                                message: message.to_string(),
+                            })
+                        }
+                        Ok(cmd) => Box::pin(self.handle_command(cmd)).await,
+                        Err(e) => Err(AppError::ConfigParsing {
+                            message: format!("Invalid batch command: {}", e),
+                        }),
+                    };
+
+                    if result.is_err() {
+                        failed += 1;
+                    }
+                    results.write_line(&serde_json::json!({
+                        "index": index,
+                        "ok": result.is_ok(),
+                        "error": result.err().map(|e| e.chain()),
+                    }))?;
+                }
+
+                if failed > 0 {
+                    return Err(AppError::Internal {
+                        message: format!("{} of {} batch commands failed", failed, index),
+                    });
+                }
+                Ok(())
+            }
+            Commands::Wait(args) => {
+                let branch = self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .find(|b| b.name == args.name)
+                    .cloned()
+                    .ok_or(AppError::BranchNotFound {
+                        name: args.name.clone(),
+                    })?;
+
+                info!("Waiting for {} on port {}", branch.name, branch.port);
+                let timeout = Duration::from_secs(args.timeout);
+                let deadline = tokio::time::Instant::now() + timeout;
+
+                while !ping(&self.state.config.engine, branch.port).await {
+                    if tokio::time::Instant::now() >= deadline {
+                        return Err(AppError::Database {
+                            message: format!(
+                                "{} did not accept connections on port {} after {}s",
+                                branch.name,
+                                branch.port,
+                                timeout.as_secs()
+                            ),
+                            source: None,
+                        });
+                    }
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+
+                // The server answers the handshake while still starting up
+                archive::wait_until_ready(
+                    &self.state.config,
+                    &branch.name,
+                    deadline.saturating_duration_since(tokio::time::Instant::now()),
+                )
+                .await?;
+
+                println!("✅ {} is ready on port {}", branch.name, branch.port);
+                Ok(())
+            }
+            Commands::Diff(args) => {
+                for name in [&args.a, &args.b] {
+                    if !self.state.config.branches.iter().any(|b| &b.name == name) {
+                        return Err(AppError::BranchNotFound { name: name.clone() });
+                    }
+                }
+
+                if !args.schema {
+                    let project_path =
+                        Path::new(&self.state.config.mount_point).join(&self.state.config.name);
+                    let extents_a = get_physical_extents(&project_path.join(&args.a));
+                    let extents_b = get_physical_extents(&project_path.join(&args.b));
+
+                    let mut table = Table::new();
+                    table.add_row(Row::new(vec![
+                        Cell::new("Branch").with_style(Attr::Bold),
+                        Cell::new("Logical Size").with_style(Attr::Bold),
+                        Cell::new("Only in this branch").with_style(Attr::Bold),
+                    ]));
+                    for (name, own, other) in [
+                        (&args.a, &extents_a, &extents_b),
+                        (&args.b, &extents_b, &extents_a),
+                    ] {
+                        let logical = get_folder_size(&project_path.join(name))
+                            .map(|f| f.logical_size)
+                            .unwrap_or(0);
+                        table.add_row(Row::new(vec![
+                            Cell::new(name.as_str()),
+                            Cell::new(Size::from_bytes(logical).to_string().as_str()),
+                            Cell::new(
+                                Size::from_bytes(exclusive_size(own, other))
+                                    .to_string()
+                                    .as_str(),
+                            ),
+                        ]));
+                    }
+                    let _ = table.print_tty(true);
+                    println!(
+                        "🔗 Shared on disk: {}",
+                        Size::from_bytes(overlap_size(&extents_a, &extents_b))
+                    );
+                    return Ok(());
+                }
+
+                let db_operator = self.database_operator();
+                let mut started = Vec::new();
+                for name in [&args.a, &args.b] {
+                    let container_name = self.state.config.container_name(name);
+                    if db_operator.is_container_running(&container_name).await? {
+                        continue;
+                    }
+                    if !args.start
+                        && !confirm(&format!("{} is stopped. Start it temporarily?", name))
+                    {
+                        return Err(AppError::Database {
+                            message: format!("{} is not running", name),
+                            source: None,
+                        });
+                    }
+
+                    println!("▶️  Starting {}", name);
+                    start_container(&self.state.config, name).await?;
+                    started.push(name.clone());
+                    archive::wait_until_ready(&self.state.config, name, Duration::from_secs(60))
+                        .await?;
+                }
+
+                let diff = schema_diff(&self.state.config, &args.a, &args.b);
+
+                let timeout = Duration::from_secs(self.state.config.stop_timeout as u64);
+                for name in &started {
+                    println!("⏹️  Stopping {}", name);
+                    let _ = db_operator
+                        .stop_database(self.state.config.clone(), name, timeout)
+                        .await;
+                }
+
+                match diff? {
+                    Some(diff) => print!("{}", diff),
+                    None => println!("✅ {} and {} have the same schema", args.a, args.b),
+                }
+                Ok(())
+            }
+            Commands::Resize(args) => {
+                if self.state.config.approach != Approach::NewDisk {
+                    return Err(AppError::Config {
+                        message: "Only NewDisk projects have an image to resize".to_string(),
+                    });
+                }
+
+                let new_size =
+                    parse_size(&args.new_size).map_err(|message| AppError::Config { message })?;
+                if new_size < MIN_IMAGE_SIZE {
+                    return Err(AppError::Config {
+                        message: format!(
+                            "The image must be at least {}",
+                            Size::from_bytes(MIN_IMAGE_SIZE)
+                        ),
+                    });
+                }
+
+                self.btrfs_operator().resize(new_size)?;
+
+                self.state.config.image_size = new_size;
+                self.state.config.save_config()?;
+                println!(
+                    "📏 Resized the {} image to {}",
+                    self.state.config.name,
+                    Size::from_bytes(new_size)
+                );
+                Ok(())
+            }
+            Commands::Psql(args) => {
+                let branch = self.resolve_branch(args.branch.as_deref())?;
+                let config = &self.state.config.for_branch(&branch.name)?;
+                if config.engine != Engine::Postgres {
+                    return Err(AppError::Config {
+                        message: "psql needs a project using the postgres engine".to_string(),
+                    });
+                }
+                let postgres = config.postgres_config.as_ref().ok_or(AppError::Config {
+                    message: "postgres_config is not set".to_string(),
+                })?;
+                let password = postgres.password()?;
+
+                info!("Opening psql on {} (port {})", branch.name, branch.port);
+                // exec only returns when psql could not be started
+                let err = std::process::Command::new("psql")
+                    .args(["-h", "localhost", "-p", &branch.port.to_string()])
+                    .args(["-U", &postgres.user, "-d", &config.database_name()])
+                    .env("PGPASSWORD", &password)
+                    .exec();
+
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    return Err(AppError::Internal {
+                        message: "psql was not found on PATH; install the PostgreSQL client"
+                            .to_string(),
+                    });
+                }
+                Err(AppError::Internal {
+                    message: format!("Failed to run psql: {}", err),
+                })
+            }
+            Commands::ConnectionString(args) => {
+                let branch = self.resolve_branch(args.branch.as_deref())?;
+                let config = &self.state.config.for_branch(&branch.name)?;
+
+                let (host, port) = if args.docker_network {
+                    (
+                        config.container_name(&branch.name),
+                        config.engine.container_port(),
+                    )
+                } else {
+                    (String::from("localhost"), branch.port)
+                };
+                println!(
+                    "{}",
+                    config.connection_string(&host, port, !args.no_password)?
+                );
+                Ok(())
+            }
+            Commands::Exec(args) => {
+                let branch = self.resolve_branch(Some(&args.branch))?;
+                let config = &self.state.config.for_branch(&branch.name)?;
+                let container_name = config.container_name(&branch.name);
+                self.ensure_running(&branch.name).await?;
+
+                let (command, password_env) = match &args.sql {
+                    Some(sql) => {
+                        let sql = sql_command(config, sql)?;
+                        (sql.command, Some(sql.password_env))
+                    }
+                    None => (args.command, None),
+                };
+                let tty = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+
+                debug!("Running {:?} in {}", command, container_name);
+                let mut docker = std::process::Command::new("docker");
+                let env: Vec<&str> = password_env.iter().map(|(name, _)| *name).collect();
+                docker.args(exec_args(config, &branch.name, tty, &env, &command));
+                if let Some((name, password)) = &password_env {
+                    docker.env(name, password);
+                }
+                // exec only returns when docker could not be started, so the
+                // output and exit code are the command's own
+                let err = docker.exec();
+
+                Err(AppError::Docker {
+                    message: "Failed to run docker exec".to_string(),
+                    source: Some(err.into()),
+                })
+            }
+            Commands::Dump(args) => {
+                let branch = self.resolve_branch(Some(&args.branch))?;
+                self.ensure_running(&branch.name).await?;
+
+                let format = args.format.dump_format();
+                match &args.out {
+                    Some(path) => {
+                        let file =
+                            std::fs::File::create(path).map_err(|e| AppError::FileSystem {
+                                message: format!("Failed to create dump file {:?}", path),
+                                source: Some(e.into()),
+                            })?;
+                        archive::dump_branch_to(
+                            &self.state.config,
+                            &branch.name,
+                            format,
+                            file.into(),
+                        )?;
+                        println!("📦 Dumped {} into {:?}", branch.name, path);
+                    }
+                    None => {
+                        if format == DumpFormat::Custom && std::io::stdout().is_terminal() {
+                            return Err(AppError::Config {
+                                message: "Refusing to write a custom-format dump to a terminal; \
+                                          pass --out or redirect stdout"
+                                    .to_string(),
+                            });
+                        }
+                        archive::dump_branch_to(
+                            &self.state.config,
+                            &branch.name,
+                            format,
+                            std::process::Stdio::inherit(),
+                        )?;
+                    }
+                }
+                Ok(())
+            }
+            Commands::Restore(args) => {
+                validate_name(&args.name)?;
+                self.create_from_file(&args.name, &args.from, args.format)
+                    .await
+            }
+            Commands::Reset(args) => {
+                info!("Resetting branch {}", args.name);
+                self.ensure_unlocked(args.force, "reset")?;
+
+                let branch = self
+                    .state
+                    .config
+                    .branches
+                    .iter()
+                    .find(|b| b.name == args.name)
+                    .cloned()
+                    .ok_or(AppError::BranchNotFound {
+                        name: args.name.clone(),
+                    })?;
+
+                if branch.is_main && !(args.force && args.source.is_some()) {
+                    return Err(AppError::Config {
+                        message: "Resetting main discards the project's data; pass --force and \
+                                  --source"
+                            .to_string(),
+                    });
+                }
+
+                let is_branch =
+                    |name: &str| self.state.config.branches.iter().any(|b| b.name == name);
+                let source = match args.source {
+                    Some(source) => source,
+                    // Branches created from a URL or a deleted branch fall back to main
+                    None => LockFile::load()?
+                        .branches
+                        .remove(&branch.name)
+                        .map(|entry| entry.source)
+                        .filter(|source| is_branch(source))
+                        .unwrap_or_else(|| String::from("main")),
+                };
+                if !is_branch(&source) {
+                    return Err(AppError::BranchNotFound { name: source });
+                }
+                if source == branch.name {
+                    return Err(AppError::Config {
+                        message: format!("Cannot reset {} from itself", branch.name),
+                    });
+                }
+
+                let db_operator = self.database_operator();
+                if !branch.read_only {
+                    let timeout = Duration::from_secs(self.state.config.stop_timeout as u64);
+                    db_operator
+                        .stop_database(self.state.config.clone(), &branch.name, timeout)
+                        .await?;
+                }
+
+                let btrfs = self.btrfs_operator();
+                let is_subvolume = btrfs.subvolume_exists(&source).unwrap_or_else(|e| {
+                    debug!("Could not check for a {} subvolume: {}", source, e.chain());
+                    false
+                });
+                if is_subvolume {
+                    btrfs.reset_snapshot(&branch.name, &source, branch.read_only)?;
+                } else if branch.read_only {
+                    return Err(AppError::Btrfs {
+                        message: format!(
+                            "Resetting a read-only branch needs {} to be a btrfs subvolume",
+                            source
+                        ),
+                        source: None,
+                    });
+                } else {
+                    let branch_path = |name: &str| {
+                        Path::new(&self.state.config.mount_point)
+                            .join(&self.state.config.name)
+                            .join(name)
+                            .join("data")
+                    };
+                    let (src_path, dest_path) = (branch_path(&source), branch_path(&branch.name));
+                    if !self
+                        .state
+                        .config
+                        .skip_for_dry_run(&format!("snapshot {:?} over {:?}", src_path, dest_path))
+                    {
+                        std::fs::remove_dir_all(&dest_path).map_err(|e| AppError::FileSystem {
+                            message: format!("Failed to remove {:?}", dest_path),
+                            source: Some(e.into()),
+                        })?;
+                        snapshot::snapshot(&src_path, &dest_path)?;
+                    }
+                }
+
+                if !branch.read_only {
+                    start_container(&self.state.config, &branch.name).await?;
+                }
+                audit::record(&self.state.config, &branch.name, Operation::Reset);
+
+                println!(
+                    "🔄 Branch {} reset from {} (port {})",
+                    branch.name, source, branch.port
+                );
+                Ok(())
+            }
+            Commands::Current(args) => {
+                if args.port {
+                    println!("{}", self.resolve_branch(None)?.port);
+                } else {
+                    let name = self.state.config.active_branch.as_deref();
+                    println!("{}", name.unwrap_or("main"));
+                }
+                Ok(())
+            }
+            Commands::Logs(args) => {
+                info!("Showing logs of branch {}", args.branch);
+                container_logs(&self.state.config, &args.branch, args.follow, args.tail).await
+            }
+        }
+    }
+
+    /// Removes a branch: its container, its snapshot and its config entry.
+    /// `wait_for_commit` is passed on to `BtrfsOperator::delete_snapshot`.
+    async fn delete_branch(
+        &mut self,
+        branch: &Branch,
+        wait_for_commit: bool,
+    ) -> Result<(), AppError> {
+        if !branch.read_only {
+            self.database_operator().ensure_available().await?;
+            self.remove_container(&branch.name).await?;
+        }
+        self.btrfs_operator()
+            .delete_snapshot(&branch.name, wait_for_commit)?;
+
+        self.state.config.remove_branch(&branch.name)?;
+        audit::record(&self.state.config, &branch.name, Operation::Deleted);
+        Ok(())
+    }
+
+    /// Refuses destructive commands on a locked project unless `--force` is given.
+    fn ensure_unlocked(&self, force: bool, command: &str) -> Result<(), AppError> {
+        if !self.state.config.locked {
+            return Ok(());
+        }
+        if force {
+            info!(
+                "Project {} is locked, continuing with --force",
+                self.state.config.name
+            );
+            return Ok(());
+        }
+
+        Err(AppError::Permission {
+            message: format!(
+                "Project '{}' is locked; run `dbranch unlock` or pass --force to {}",
+                self.state.config.name, command
+            ),
+        })
+    }
+
+    /// Waits for the running proxy to route new connections to `branch`,
+    /// then has it close the open ones so that their clients follow.
+    async fn bounce_proxy(&self, mut proxy: RuntimeState, branch: &str) -> Result<(), AppError> {
+        if self
+            .state
+            .config
+            .skip_for_dry_run(&format!("close the connections of proxy {}", proxy.pid))
+        {
+            return Ok(());
+        }
+
+        // Closing first would let clients reconnect to the old branch
+        let deadline = tokio::time::Instant::now() + BOUNCE_TIMEOUT;
+        while proxy.active_branch.as_deref() != Some(branch) {
+            if tokio::time::Instant::now() >= deadline {
+                return Err(AppError::Internal {
+                    message: format!(
+                        "Proxy {} did not switch to {} within {:?}; connections left open",
+                        proxy.pid, branch, BOUNCE_TIMEOUT
+                    ),
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            proxy = RuntimeState::load().ok_or(AppError::Internal {
+                message: format!("Proxy {} exited", proxy.pid),
+            })?;
+        }
+
+        proxy.bounce()?;
+        println!(
+            "🔁 Closed the connections of the proxy on {}; clients reconnect to {}",
+            proxy.proxy_addr, branch
+        );
+        Ok(())
+    }
+
+    /// Removes the container of branch `name`, which may already be gone,
+    /// e.g. after `docker rm` or `clean`.
+    async fn remove_container(&self, name: &str) -> Result<(), AppError> {
+        match self
+            .database_operator()
+            .delete_database(self.state.config.clone(), name)
+            .await
+        {
+            Err(AppError::ContainerNotFound { name }) => {
+                info!("Container {} is already gone", name);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Fails unless the container of branch `name` is running.
+    async fn ensure_running(&self, name: &str) -> Result<(), AppError> {
+        let container_name = self.state.config.container_name(name);
+        if !self
+            .database_operator()
+            .is_container_running(&container_name)
+            .await?
+        {
+            return Err(AppError::Docker {
+                message: format!(
+                    "Container {} is not running, run `dbranch resume` first",
+                    container_name
+                ),
+                source: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Branch called `name`, or the active branch (main when none is
+    /// active) without a name.
+    fn resolve_branch(&self, name: Option<&str>) -> Result<&Branch, AppError> {
+        let name = name
+            .or(self.state.config.active_branch.as_deref())
+            .unwrap_or("main");
+
+        self.state
+            .config
+            .branches
+            .iter()
+            .find(|b| b.name == name)
+            .ok_or(AppError::BranchNotFound {
+                name: name.to_string(),
+            })
+    }
+
+    /// Port range used for allocation: the `--port-range` flag if given, the config otherwise.
+    fn port_range(&self) -> PortRange {
+        self.state.port_range.unwrap_or(PortRange {
+            min: self.state.config.port_min,
+            max: self.state.config.port_max,
+        })
+    }
+
+    fn get_valid_port(&self) -> Result<u16, AppError> {
+        let port_range = self.port_range();
+        get_valid_port_excluding(
+            port_range.min,
+            port_range.max,
+            &self.state.config.branch_ports(),
+        )
+        .ok_or(AppError::NoPortAvailable {
+            min: port_range.min,
+            max: port_range.max,
+        })
+    }
+
+    /// Records where a freshly created branch came from in `.dbranch.lock`.
+    /// Failing to is only logged: the branch exists by now, and an error would
+    /// leave it registered behind a failed `create` that can't be retried.
+    async fn record_provenance(&self, name: &str, source: &str, read_only: bool) {
+        debug!("Recording provenance of {}", name);
+
+        if self
+            .state
+            .config
+            .skip_for_dry_run(&format!("record {} in {}", name, LOCK_FILE))
+        {
+            return;
+        }
+
+        let recorded = self
+            .provenance(name, source, read_only)
+            .await
+            .and_then(|entry| LockFile::record(name, entry));
+        if let Err(e) = recorded {
+            warn!(
+                "Branch {} was created, but not recorded in {}: {}",
+                name,
+                LOCK_FILE,
+                e.chain()
+            );
+        }
+    }
+
+    /// Lockfile entry of a freshly created branch. The content hash needs the
+    /// branch's container, so read-only branches are recorded without one.
+    async fn provenance(
+        &self,
+        name: &str,
+        source: &str,
+        read_only: bool,
+    ) -> Result<LockEntry, AppError> {
+        let content_hash = if read_only {
+            None
+        } else {
+            archive::wait_until_ready(&self.state.config, name, Duration::from_secs(60)).await?;
+            Some(archive::hash_branch(&self.state.config, name)?)
+        };
+
+        let image = self.state.config.image();
+        Ok(LockEntry {
+            source: source.to_string(),
+            created_at: Utc::now(),
+            image_digest: image_digest(&image).await,
+            image,
+            content_hash,
+        })
+    }
+
+    fn btrfs_operator(&self) -> BtrfsOperator {
+        btrfs_operator(&self.state.config)
+    }
+
+    async fn export_branch(&self, branch: &Branch, dest: &Path) -> Result<(), AppError> {
+        match self.state.config.approach {
+            Approach::NewDisk => self.btrfs_operator().send_subvolume(&branch.name, dest),
+            Approach::ExistingDisk => {
+                self.ensure_running(&branch.name).await?;
+                archive::dump_branch(&self.state.config, &branch.name, dest)
+            }
+        }
+    }
+
+    async fn import_branch(&mut self, branch: &ManifestBranch, dir: &Path) -> Result<(), AppError> {
+        if self.state.config.skip_for_dry_run(&format!(
+            "import {} from {:?}",
+            branch.name,
+            dir.join(&branch.artifact)
+        )) {
+            return Ok(());
+        }
+
+        let existing = self
+            .state
+            .config
+            .branches
+            .iter()
+            .find(|b| b.name == branch.name)
+            .cloned();
+
+        if existing.is_some() && !branch.is_main {
+            return Err(AppError::BranchAlreadyExists {
+                name: branch.name.clone(),
+            });
+        }
+
+        let artifact = dir.join(&branch.artifact);
+
+        if self.state.config.approach == Approach::NewDisk {
+            self.btrfs_operator()
+                .receive_subvolume(&branch.name, &artifact)?;
+        }
+
+        let port = match &existing {
+            Some(b) => b.port,
+            None => {
+                let reserved: Vec<u16> =
+                    self.state.config.branches.iter().map(|b| b.port).collect();
+                let port_range = self.port_range();
+                // Keep the exported port when it is still free
+                get_valid_port_excluding(branch.port, branch.port, &reserved)
+                    .or_else(|| get_valid_port_excluding(port_range.min, port_range.max, &reserved))
+                    .ok_or(AppError::NoPortAvailable {
+                        min: port_range.min,
+                        max: port_range.max,
+                    })?
+            }
+        };
+
+        let db_operator = self.database_operator();
+        let container_name = self.state.config.container_name(&branch.name);
+        if !db_operator.is_container_running(&container_name).await? {
+            db_operator
+                .create_database(self.state.config.clone(), port, &branch.name)
+                .await?;
+        }
+
+        if self.state.config.approach == Approach::ExistingDisk {
+            archive::wait_until_ready(&self.state.config, &branch.name, Duration::from_secs(60))
+                .await?;
+            archive::restore_branch(&self.state.config, &branch.name, &artifact)?;
+        }
+
+        if existing.is_none() {
+            self.state.config.branches.push(Branch {
+                name: branch.name.clone(),
+                port,
+                is_main: branch.is_main,
+                created_at: branch.created_at,
+                read_only: false,
+                postgres_config: None,
+            });
+            self.state.config.save_config()?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates an empty branch and restores the dump downloaded from `url`
+    /// into it. The branch is removed again if the restore fails.
+    async fn create_from_url(&mut self, name: &str, url: &str) -> Result<(), AppError> {
+        if self.state.config.branches.iter().any(|b| b.name == name) {
+            return Err(AppError::BranchAlreadyExists {
+                name: name.to_string(),
+            });
+        }
+
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(AppError::Config {
+                message: format!("Expected an http(s) URL, got '{}'", url),
+            });
+        }
+
+        if self.state.config.skip_for_dry_run(&format!(
+            "download {} and restore it into new branch {}",
+            url, name
+        )) {
+            return Ok(());
+        }
+
+        let download = std::env::temp_dir().join(format!("dbranch-{}.dump", uuid::Uuid::new_v4()));
+        let unpacked = download.with_extension("unpacked");
+
+        let result = self.restore_from_url(name, url, &download, &unpacked).await;
+
+        let _ = std::fs::remove_file(&download);
+        let _ = std::fs::remove_file(&unpacked);
+        result?;
+
+        self.record_provenance(name, url, false).await;
+        Ok(())
+    }
+
+    async fn restore_from_url(
+        &mut self,
+        name: &str,
+        url: &str,
+        download: &Path,
+        unpacked: &Path,
+    ) -> Result<(), AppError> {
+        println!("⬇️  Downloading {}", url);
+        archive::download(url, download)?;
+
+        let mut dump = download;
+        let mut format = archive::detect_format(dump)?;
+        if format == DumpFormat::Gzip {
+            archive::gunzip(download, unpacked)?;
+            dump = unpacked;
+            format = archive::detect_format(dump)?;
+        }
+        debug!("Detected {:?} dump", format);
+
+        let port = self.restore_into_new_branch(name, dump, format).await?;
+        println!("✅ Branch {} created from {} on port {}", name, url, port);
+        Ok(())
+    }
+
+    /// Creates an empty branch and restores the dump at `src` into it, in
+    /// `format` or as detected from the file. The branch is removed again if
+    /// the restore fails.
+    async fn create_from_file(
+        &mut self,
+        name: &str,
+        src: &Path,
+        format: Option<LogicalFormat>,
+    ) -> Result<(), AppError> {
+        if self.state.config.branches.iter().any(|b| b.name == name) {
+            return Err(AppError::BranchAlreadyExists {
+                name: name.to_string(),
+            });
+        }
+
+        if !src.is_file() {
+            return Err(AppError::FileNotFound {
+                path: src.to_string_lossy().to_string(),
+            });
+        }
+
+        if self
+            .state
+            .config
+            .skip_for_dry_run(&format!("restore {:?} into new branch {}", src, name))
+        {
+            return Ok(());
+        }
+
+        let unpacked =
+            std::env::temp_dir().join(format!("dbranch-{}.unpacked", uuid::Uuid::new_v4()));
+
+        let result = self.restore_from_file(name, src, format, &unpacked).await;
+
+        let _ = std::fs::remove_file(&unpacked);
+        let port = result?;
+
+        println!(
+            "✅ Branch {} restored from {:?} on port {}",
+            name, src, port
+        );
+        Ok(())
+    }
+
+    async fn restore_from_file(
+        &mut self,
+        name: &str,
+        src: &Path,
+        format: Option<LogicalFormat>,
+        unpacked: &Path,
+    ) -> Result<u16, AppError> {
+        let mut dump = src;
+        let mut detected = archive::detect_format(dump)?;
+        if detected == DumpFormat::Gzip {
+            archive::gunzip(src, unpacked)?;
+            dump = unpacked;
+            detected = archive::detect_format(dump)?;
+        }
+        let format = format.map_or(detected, LogicalFormat::dump_format);
+        debug!("Restoring {:?} as a {:?} dump", dump, format);
+
+        self.restore_into_new_branch(name, dump, format).await
+    }
+
+    /// Creates an empty branch on a free port and loads `dump` into it once
+    /// it accepts connections, removing the branch again if that fails.
+    /// Returns the port of the new branch.
+    async fn restore_into_new_branch(
+        &mut self,
+        name: &str,
+        dump: &Path,
+        format: DumpFormat,
+    ) -> Result<u16, AppError> {
+        let port = self.get_valid_port()?;
+
+        let db_operator = self.database_operator();
+        db_operator.ensure_available().await?;
+        db_operator
+            .create_database(self.state.config.clone(), port, name)
+            .await?;
+
+        println!("♻️  Restoring into {}", name);
+        let restored = async {
+            archive::wait_until_ready(&self.state.config, name, Duration::from_secs(60)).await?;
+            archive::restore_dump(&self.state.config, name, dump, format)
+        }
+        .await;
+
+        if let Err(e) = restored {
+            debug!("Restore failed, rolling back branch {}", name);
+            let _ = db_operator
+                .delete_database(self.state.config.clone(), name)
+                .await;
+            let branch_path = Path::new(&self.state.config.mount_point)
+                .join(&self.state.config.name)
+                .join(name);
+            if let Err(e) = std::fs::remove_dir_all(&branch_path) {
+                debug!("Failed to remove {:?}: {}", branch_path, e);
+            }
+            return Err(e);
+        }
+
+        self.state
+            .config
+            .create_branch(name.to_string(), port, false)?;
+        audit::record(&self.state.config, name, Operation::Created);
+
+        Ok(port)
+    }
+
+    fn database_operator(&self) -> EngineOperator {
+        EngineOperator::for_engine(&self.state.config.engine)
+    }
+
+    async fn create_postgres(
+        &mut self,
+        name: Option<String>,
+        valid_port: u16,
+    ) -> Result<(), AppError> {
+        debug!("Initializing PostgreSQL database creation");
+        let db_operator = self.database_operator();
+        debug!(
+            "Finding available port in range {:?}, {:?}",
+            self.port_range().min,
+            self.port_range().max
+        );
+        info!("Found available port: {}", valid_port);
+        let db_name = name.unwrap_or_else(|| "main".to_string());
+        debug!("Creating PostgreSQL database: {}", db_name);
+        db_operator
+            .create_database(self.state.config.clone(), valid_port, db_name.as_str())
+            .await?;
+        info!("PostgreSQL database created successfully");
+        Ok(())
+    }
+}
+
+fn btrfs_operator(config: &Config) -> BtrfsOperator {
+    let project_dir = config_path()
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    BtrfsOperator::new(
+        Project {
+            name: config.name.clone(),
+            path: project_dir,
+        },
+        config.clone(),
+    )
+}
+
+/// Copies `source` into the new branch `name` and, unless it is read-only,
+/// starts its database on `port`. The data copied is removed again when the
+/// container can't be created. Registering the branch is up to the caller.
+async fn create_one(
+    config: Config,
+    name: String,
+    source: String,
+    port: u16,
+    read_only: bool,
+) -> Result<(), AppError> {
+    let copy_config = config.clone();
+    let (copy_name, copy_source) = (name.clone(), source.clone());
+    tokio::task::spawn_blocking(move || {
+        copy_branch_data(&copy_config, &copy_source, &copy_name, read_only)
+    })
+    .await
+    .map_err(|e| AppError::Internal {
+        message: format!("Copying {} to {} failed: {}", source, name, e),
+    })??;
+
+    // Postgres can't start on a read-only data directory
+    if read_only {
+        return Ok(());
+    }
+
+    info!("Creating database of {} on port {}", name, port);
+    let created = EngineOperator::for_engine(&config.engine)
+        .create_database(config.clone(), port, &name)
+        .await;
+    if created.is_err()
+        && let Err(e) = btrfs_operator(&config).delete_snapshot(&name, false)
+    {
+        warn!("Failed to remove the data of {}: {}", name, e.chain());
+    }
+    created
+}
+
+/// Copies the data of branch `source` to the new branch `name`.
+fn copy_branch_data(
+    config: &Config,
+    source: &str,
+    name: &str,
+    read_only: bool,
+) -> Result<(), AppError> {
+    let project_path = Path::new(&config.mount_point).join(&config.name);
+    let src_path = project_path.join(source).join("data");
+    let dest_path = project_path.join(name).join("data");
+    info!("Copying data from {:?} to {:?}", src_path, dest_path);
+
+    // A subvolume snapshot is atomic and O(1), reflinking every file
+    // is only for sources that aren't subvolumes
+    let btrfs = btrfs_operator(config);
+    let is_subvolume = btrfs.subvolume_exists(source).unwrap_or_else(|e| {
+        debug!("Could not check for a {} subvolume: {}", source, e.chain());
+        false
+    });
+    if is_subvolume {
+        btrfs.create_snapshot(source, name, read_only)
+    } else if read_only {
+        Err(AppError::Btrfs {
+            message: format!("--read-only needs {} to be a btrfs subvolume", source),
+            source: None,
+        })
+    } else if config.skip_for_dry_run(&format!("snapshot {:?} to {:?}", src_path, dest_path)) {
+        Ok(())
+    } else {
+        snapshot::snapshot(&src_path, &dest_path)
+    }
+}
+
+/// Status of every branch of the project, main first.
+pub async fn project_status(config: &Config) -> Result<ProjectStatus, AppError> {
+    let mut branches = Vec::new();
+    let (main, others): (Vec<&Branch>, Vec<&Branch>) =
+        config.branches.iter().partition(|b| b.is_main);
+    for branch in main.into_iter().chain(others) {
+        branches.push(branch_status(config, branch).await?);
+    }
+
+    Ok(ProjectStatus {
+        project: config.name.clone(),
+        active_branch: config.active_branch.clone(),
+        proxy: RuntimeState::load(),
+        branches,
+    })
+}
+
+pub async fn branch_status(config: &Config, branch: &Branch) -> Result<BranchStatus, AppError> {
+    let health = container_health(&config.container_name(&branch.name)).await;
+
+    let path = Path::new(&config.mount_point)
+        .join(&config.name)
+        .join(&branch.name);
+    let ignore = config.ignore_in_sizing.clone();
+    let measured = path.clone();
+    // Walking the extents of a branch is blocking work
+    let info = tokio::task::spawn_blocking(move || get_folder_size_ignoring(&measured, &ignore))
+        .await
+        .map_err(|e| AppError::Internal {
+            message: format!("Failed to measure {:?}: {}", path, e),
+        })??;
+
+    let created_at = if branch.is_main {
+        config.created_at
+    } else {
+        branch.created_at
+    };
+    Ok(BranchStatus {
+        name: branch.name.clone(),
+        port: branch.port,
+        logical_size: info.logical_size,
+        allocated_size: info.allocated_size,
+        unique_size: info.unique_size(),
+        running: health.is_running(),
+        health,
+        age_seconds: (Utc::now() - created_at).num_seconds(),
+    })
+}
+
+/// Asks a yes/no question on the terminal; anything but `y`/`yes` is a no.
+/// Points stdout at stderr while a batch runs, so that the human output of
+/// its commands doesn't mix with the JSON result lines, which go to the
+/// original stdout kept here. Restored when dropped.
+struct StdoutToStderr {
+    original: std::fs::File,
+}
+
+impl StdoutToStderr {
+    fn redirect() -> Result<Self, AppError> {
+        use std::{io::Write, os::fd::AsFd};
+
+        let _ = std::io::stdout().flush();
+        let original = std::io::stdout()
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|e| AppError::FileSystem {
+                message: "Failed to duplicate stdout".to_string(),
+                source: Some(e.into()),
+            })?;
+
+        // SAFETY: both descriptors are open; dup2 only replaces stdout
+        if unsafe { nix::libc::dup2(nix::libc::STDERR_FILENO, nix::libc::STDOUT_FILENO) } == -1 {
+            return Err(AppError::FileSystem {
+                message: "Failed to redirect stdout to stderr".to_string(),
+                source: Some(std::io::Error::last_os_error().into()),
+            });
+        }
+        Ok(Self {
+            original: original.into(),
+        })
+    }
+
+    fn write_line(&mut self, value: &serde_json::Value) -> Result<(), AppError> {
+        use std::io::Write;
+
+        let _ = std::io::stdout().flush();
+        writeln!(self.original, "{}", value).map_err(|e| AppError::FileSystem {
+            message: "Failed to write a batch result".to_string(),
+            source: Some(e.into()),
+        })
+    }
+}
+
+impl Drop for StdoutToStderr {
+    fn drop(&mut self) {
+        use std::{io::Write, os::fd::AsRawFd};
+
+        let _ = std::io::stdout().flush();
+        // SAFETY: `original` is open until `self` is dropped
+        unsafe { nix::libc::dup2(self.original.as_raw_fd(), nix::libc::STDOUT_FILENO) };
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    use std::io::Write;
+
+    print!("❓ {} [y/N] ", question);
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Unified diff of the schemas of two running branches, `None` when equal.
+fn schema_diff(config: &Config, a: &str, b: &str) -> Result<Option<String>, AppError> {
+    let dir = std::env::temp_dir().join(format!("dbranch-diff-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to create directory {:?}", dir),
+        source: Some(e.into()),
+    })?;
+
+    let diff = (|| {
+        let (path_a, path_b) = (dir.join("a.sql"), dir.join("b.sql"));
+        for (branch, path) in [(a, &path_a), (b, &path_b)] {
+            let schema = archive::dump_schema(config, branch)?;
+            std::fs::write(path, schema).map_err(|e| AppError::FileSystem {
+                message: format!("Failed to write {:?}", path),
+                source: Some(e.into()),
+            })?;
+        }
+
+        let output = std::process::Command::new("diff")
+            .arg("-u")
+            .args(["--label", a, "--label", b])
+            .arg(&path_a)
+            .arg(&path_b)
+            .output()
+            .map_err(|e| AppError::Internal {
+                message: format!("Failed to run diff: {}", e),
+            })?;
+
+        // diff exits 0 when equal, 1 when different and 2 on trouble
+        match output.status.code() {
+            Some(0) => Ok(None),
+            Some(1) => Ok(Some(String::from_utf8_lossy(&output.stdout).to_string())),
+            _ => Err(AppError::Internal {
+                message: format!("diff failed: {}", String::from_utf8_lossy(&output.stderr)),
+            }),
+        }
+    })();
+
+    let _ = std::fs::remove_dir_all(&dir);
+    diff
+}
+
+/// `Status --live` columns; `-` when the branch is stopped or the query failed.
+fn activity_cells(activity: Option<Activity>) -> [Cell; 2] {
+    match activity {
+        Some(activity) => [
+            Cell::new(activity.connections.to_string().as_str()),
+            Cell::new(
+                Size::from_bytes(activity.database_size)
+                    .to_string()
+                    .as_str(),
+            ),
+        ],
+        None => [Cell::new("-"), Cell::new("-")],
+    }
+}
+
+/// Coarse age of a duration, e.g. `3d`, `5h` or `12m`.
+fn format_age(duration: chrono::Duration) -> String {
+    if duration.num_days() > 0 {
+        format!("{}d", duration.num_days())
+    } else if duration.num_hours() > 0 {
+        format!("{}h", duration.num_hours())
+    } else {
+        format!("{}m", duration.num_minutes())
+    }
+}
+
+/// A compression ratio like `1.8x`, or `-` for an empty branch.
+fn format_ratio(ratio: Option<f64>) -> String {
+    ratio.map_or_else(|| "-".to_string(), |r| format!("{:.1}x", r))
+}
+
+fn print_transfer_results(results: &[(String, Result<(), AppError>)], verb: &str) {
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Branch").with_style(Attr::Bold),
+        Cell::new("Result").with_style(Attr::Bold),
+    ]));
+    for (name, result) in results {
+        table.add_row(Row::new(vec![
+            Cell::new(name.as_str()),
+            Cell::new(
+                match result {
+                    Ok(()) => format!("✅ {}", verb),
+                    Err(e) => format!("❌ {}", e.chain()),
+                }
+                .as_str(),
+            ),
+        ]));
+    }
+    let _ = table.print_tty(true);
+}
+
+fn check_transfer_results(results: &[(String, Result<(), AppError>)]) -> Result<(), AppError> {
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    if failed > 0 {
+        return Err(AppError::Internal {
+            message: format!("{} of {} branches failed", failed, results.len()),
+        });
+    }
+    Ok(())
+}
+
+/// Copies the contents of `src` into the existing `dest`, preserving ownership
+/// and reflinking when both sides support it.
+fn copy_dir_contents(src: &Path, dest: &Path) -> Result<(), AppError> {
+    info!("Copying {:?} to {:?}", src, dest);
+
+    let output = std::process::Command::new("sudo")
+        .arg("cp")
+        .arg("-a")
+        .arg("--reflink=auto")
+        .arg(src.join("."))
+        .arg(dest)
+        .output()
+        .map_err(|e| AppError::FileSystem {
+            message: format!("Failed to copy {:?} to {:?}", src, dest),
+            source: Some(e.into()),
+        })?;
+
+    if !output.status.success() {
+        return Err(AppError::FileSystem {
+            message: format!(
+                "Failed to copy {:?} to {:?}: {}",
+                src,
+                dest,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            source: None,
+        });
+    }
+
+    Ok(())
+}
+
+fn transfer_dir(src: &Path, dest: &Path, mode: TransferMode) -> Result<(), AppError> {
+    match mode {
+        TransferMode::Reflink => {
+            info!("Reflinking {:?} to {:?}", src, dest);
+            snapshot::snapshot(src, dest)
+        }
+        TransferMode::Copy => {
+            info!("Copying {:?} to {:?}", src, dest);
+            snapshot::snapshot_with(src, dest, &CopyRefOperator::new().prefer_reflink(false))
+        }
+        TransferMode::Move => {
+            info!("Moving {:?} to {:?}", src, dest);
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| AppError::FileSystem {
+                    message: format!("Failed to create directory {:?}", parent),
+                    source: Some(e.into()),
+                })?;
+            }
+            std::fs::rename(src, dest).map_err(|e| AppError::FileSystem {
+                message: format!(
+                    "Failed to move {:?} to {:?} (use --mode reflink across filesystems)",
+                    src, dest
+                ),
+                source: Some(e.into()),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_commands_deserialize_with_cli_defaults() {
+        let cmd: Commands =
+            serde_json::from_str(r#"{"create-many": {"names": ["a", "b"]}}"#).unwrap();
+        let Commands::CreateMany(args) = cmd else {
+            panic!("expected create-many, got {:?}", cmd);
+        };
+        assert_eq!(args.names, vec!["a", "b"]);
+        assert_eq!(args.jobs, default_jobs());
+
+        let cmd: Commands = serde_json::from_str(r#"{"status": {"live": true}}"#).unwrap();
+        assert!(matches!(
+            cmd,
+            Commands::Status(StatusArgs {
+                live: true,
+                json: false
+            })
+        ));
+
+        let cmd: Commands = serde_json::from_str(r#""lock""#).unwrap();
+        assert!(matches!(cmd, Commands::Lock));
+
+        assert!(serde_json::from_str::<Commands>(r#"{"create": {}}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("7d"), Ok(chrono::Duration::days(7)));
+        assert_eq!(parse_age(" 12h "), Ok(chrono::Duration::hours(12)));
+        assert_eq!(parse_age("30m"), Ok(chrono::Duration::minutes(30)));
+        assert!(parse_age("7").is_err());
+        assert!(parse_age("-7d").is_err());
+        assert!(parse_age("7w").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("").is_err());
+
+        let cmd: Commands = serde_json::from_str(r#"{"prune": {"older_than": "2d"}}"#).unwrap();
+        let Commands::Prune(args) = cmd else {
+            panic!("expected prune, got {:?}", cmd);
+        };
+        assert_eq!(args.older_than, chrono::Duration::days(2));
+        assert!(serde_json::from_str::<Commands>(r#"{"prune": {}}"#).is_err());
+    }
+
+    fn batch_conflict(line: &str) -> Option<&'static str> {
+        serde_json::from_str::<Commands>(line)
+            .unwrap()
+            .batch_conflict()
+    }
+
+    #[test]
+    fn test_batch_rejects_psql() {
+        assert!(batch_conflict(r#"{"psql": {}}"#).is_some());
+        assert!(batch_conflict(r#"{"psql": {"branch": "feature"}}"#).is_some());
+    }
+
+    #[test]
+    fn test_batch_rejects_exec() {
+        assert!(batch_conflict(r#"{"exec": {"branch": "feature", "sql": "SELECT 1"}}"#).is_some());
+        assert!(batch_conflict(r#"{"exec": {"branch": "feature", "command": ["ls"]}}"#).is_some());
+    }
+
+    #[test]
+    fn test_batch_rejects_interactive_diff() {
+        assert!(batch_conflict(r#"{"diff": {"a": "main", "b": "feature"}}"#).is_some());
+        assert_eq!(
+            batch_conflict(r#"{"diff": {"a": "main", "b": "feature", "start": true}}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn test_batch_rejects_logs_follow() {
+        assert!(batch_conflict(r#"{"logs": {"branch": "feature", "follow": true}}"#).is_some());
+        assert_eq!(batch_conflict(r#"{"logs": {"branch": "feature"}}"#), None);
+        assert_eq!(batch_conflict(r#"{"status": {}}"#), None);
+    }
+
+    #[test]
+    fn test_log_level_flags() {
+        let level = |args: &[&str]| Cli::parse_from(args).log_level();
+
+        assert_eq!(level(&["dbranch", "list"]), None);
+        assert_eq!(level(&["dbranch", "-v", "list"]), Some("debug"));
+        assert_eq!(level(&["dbranch", "list", "-vv"]), Some("trace"));
+        assert_eq!(level(&["dbranch", "list", "-q"]), Some("warn"));
+        assert!(Cli::try_parse_from(["dbranch", "-q", "-v", "list"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_provenance_skips_hash() {
+        let handler = CliHandler::new(AppState {
+            config: Config::new("project".into()),
+            port_range: None,
+            dry_run: false,
+            restart: None,
+        });
+
+        // A read-only branch never gets a container, so waiting on it would
+        // only time out
+        let entry = tokio::time::timeout(
+            Duration::from_secs(5),
+            handler.provenance("refpoint", "main", true),
+        )
+        .await
+        .expect("read-only provenance must not wait for a container")
+        .unwrap();
+
+        assert_eq!(entry.source, "main");
+        assert_eq!(entry.content_hash, None);
     }
 }
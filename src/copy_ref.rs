@@ -1,68 +1,213 @@
 use crate::error;
 use std::{
     fs::File,
-    os::raw::{c_char, c_int},
+    io::{self, Read, Write},
+    path::Path,
 };
+use tracing::debug;
 
 pub trait CopyRef {
     fn copy_ref(&self, src: &File, dest: &File) -> Result<(), error::AppError>;
+
+    /// Like `copy_ref`, from and to paths. Needed where cloning works on
+    /// paths only: `clonefile` on macOS also requires `dest` not to exist.
+    fn copy_ref_path(&self, src: &Path, dest: &Path) -> Result<(), error::AppError> {
+        open_and_copy(self, src, dest)
+    }
 }
 
-pub struct CopyRefOperator {}
+fn open_and_copy<C: CopyRef + ?Sized>(
+    operator: &C,
+    src: &Path,
+    dest: &Path,
+) -> Result<(), error::AppError> {
+    let src_file = File::open(src).map_err(|e| error::AppError::FileSystem {
+        message: format!("Failed to open source file {:?}", src),
+        source: Some(e.into()),
+    })?;
+    let dest_file = File::create(dest).map_err(|e| error::AppError::FileSystem {
+        message: format!("Failed to create destination file {:?}", dest),
+        source: Some(e.into()),
+    })?;
+    operator.copy_ref(&src_file, &dest_file)
+}
+
+pub struct CopyRefOperator {
+    // Try a reflink first, copying the bytes only when the filesystem can't
+    // share extents; otherwise always copy the bytes
+    prefer_reflink: bool,
+}
 
 impl CopyRefOperator {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            prefer_reflink: true,
+        }
+    }
+
+    /// Turns reflinks off, e.g. to get independent copies on a CoW filesystem.
+    pub fn prefer_reflink(mut self, prefer_reflink: bool) -> Self {
+        self.prefer_reflink = prefer_reflink;
+        self
+    }
+}
+
+/// Whether a reflink failed because the filesystems can't share extents
+/// (not CoW, across filesystems, or no kernel support), rather than for a
+/// reason a plain copy would hit as well.
+fn reflink_unsupported(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use nix::libc::{ENOSYS, ENOTSUP, EOPNOTSUPP, EXDEV};
+        // ENOTSUP and EOPNOTSUPP are the same on Linux but not on macOS
+        err.raw_os_error()
+            .is_some_and(|code| [EXDEV, EOPNOTSUPP, ENOTSUP, ENOSYS].contains(&code))
+    }
+    #[cfg(not(unix))]
+    false
+}
+
+/// Copies the bytes of `src` into `dest`, from the start of both files.
+///
+/// Reads and writes through a buffer on purpose: `io::copy` specializes to
+/// `copy_file_range`, which shares extents on btrfs just like a reflink.
+fn copy_bytes(src: &File, dest: &File) -> Result<(), error::AppError> {
+    let copy_error = |e: io::Error| error::AppError::FileSystem {
+        message: format!("Failed to copy {:?} to {:?}", src, dest),
+        source: Some(e.into()),
+    };
+
+    let (mut reader, mut writer) = (src, dest);
+    let mut buffer = vec![0u8; 1 << 20];
+    let mut copied = 0u64;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(copy_error(e)),
+        };
+        writer.write_all(&buffer[..read]).map_err(copy_error)?;
+        copied += read as u64;
     }
+    debug!("Copied {} bytes without reflink", copied);
+    Ok(())
 }
 
+#[cfg(target_os = "macos")]
 unsafe extern "C" {
     // http://www.manpagez.com/man/2/clonefileat/
-    fn clonefile(src: *const c_char, dest: *const c_char, flags: c_int) -> c_int;
+    fn clonefile(
+        src: *const std::os::raw::c_char,
+        dest: *const std::os::raw::c_char,
+        flags: std::os::raw::c_int,
+    ) -> std::os::raw::c_int;
 }
 
-impl CopyRef for CopyRefOperator {
-    #[cfg(target_os = "linux")]
-    fn copy_ref(&self, src: &File, dest: &File) -> Result<(), error::AppError> {
-        let info = src.metadata().unwrap().len() as usize;
-        // https://man7.org/linux/man-pages/man2/copy_file_range.2.html
-        let ret = unsafe {
-            use std::os::fd::AsRawFd;
+/// `path` as a nul-terminated C string.
+#[cfg(target_os = "macos")]
+fn c_path(path: &Path) -> Result<std::ffi::CString, error::AppError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|e| error::AppError::FileSystem {
+        message: format!("Invalid path {:?}", path),
+        source: Some(e.into()),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &File, dest: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
 
-            nix::libc::copy_file_range(src.as_raw_fd(), &mut 0, dest.as_raw_fd(), &mut 0, info, 0)
+    let len = src.metadata()?.len();
+    let (mut off_in, mut off_out): (nix::libc::loff_t, nix::libc::loff_t) = (0, 0);
+    let mut copied = 0u64;
+
+    // https://man7.org/linux/man-pages/man2/copy_file_range.2.html
+    // A call may copy less than asked, e.g. at most 2 GiB at a time
+    while copied < len {
+        let remaining = usize::try_from(len - copied).unwrap_or(usize::MAX);
+        // SAFETY: both descriptors are open for the duration of the call and
+        // the offsets point to live locals
+        let ret = unsafe {
+            nix::libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut off_in,
+                dest.as_raw_fd(),
+                &mut off_out,
+                remaining,
+                0,
+            )
         };
 
-        if ret == -1 {
-            let err = std::io::Error::last_os_error();
-            return Err(error::AppError::FileSystem {
-                message: format!("Failed to copy ref from {:?} to {:?}: {}", src, dest, err),
-            });
+        match ret {
+            -1 => return Err(io::Error::last_os_error()),
+            // The source ended early, e.g. it was truncated meanwhile
+            0 => break,
+            n => copied += n as u64,
         }
-        Ok(())
     }
+    Ok(())
+}
 
-    #[cfg(target_os = "macos")]
-    fn copy_ref(&self, _src: &File, _dest: &File) -> Result<(), error::AppError> {
-        let r = unsafe { clonefile(_src, _dest) };
-        if r == -1 {
-            let err = io::Error::last_os_error();
-            return Err(error::AppError::FileSystem {
-                message: format!("Failed to copy ref from {:?} to {:?}: {}", _src, _dest, err),
-            });
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &File, _dest: &File) -> io::Result<()> {
+    // clonefile on macOS only works on paths, see `copy_ref_path`
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflink of open files is not supported on this platform",
+    ))
+}
+
+impl CopyRef for CopyRefOperator {
+    fn copy_ref(&self, src: &File, dest: &File) -> Result<(), error::AppError> {
+        if self.prefer_reflink {
+            match reflink(src, dest) {
+                Ok(()) => return Ok(()),
+                Err(e) if reflink_unsupported(&e) => {
+                    debug!("Reflink unsupported ({}), copying {:?}", e, src);
+                }
+                Err(e) => {
+                    return Err(error::AppError::FileSystem {
+                        message: format!("Failed to copy ref from {:?} to {:?}", src, dest),
+                        source: Some(e.into()),
+                    });
+                }
+            }
         }
-        Ok(())
+        copy_bytes(src, dest)
     }
 
-    #[cfg(target_os = "windows")]
-    fn copy_ref(&self, src: &File, dest: &File) -> Result<(), error::AppError> {
-        Err(error::AppError::FileSystem {
-            message: format!("copy_file_range not supported on this platform"),
-        })
+    #[cfg(target_os = "macos")]
+    fn copy_ref_path(&self, src: &Path, dest: &Path) -> Result<(), error::AppError> {
+        if self.prefer_reflink {
+            let (c_src, c_dest) = (c_path(src)?, c_path(dest)?);
+            // SAFETY: both pointers are valid nul-terminated strings that
+            // outlive the call
+            let r = unsafe { clonefile(c_src.as_ptr(), c_dest.as_ptr(), 0) };
+            if r == 0 {
+                return Ok(());
+            }
+
+            let err = io::Error::last_os_error();
+            if !reflink_unsupported(&err) {
+                return Err(error::AppError::FileSystem {
+                    message: format!("Failed to copy ref from {:?} to {:?}", src, dest),
+                    source: Some(err.into()),
+                });
+            }
+            debug!("clonefile unsupported ({}), copying {:?}", err, src);
+        }
+        open_and_copy(self, src, dest)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    #[cfg(target_os = "linux")]
     use crate::fiemap::{FiemapFlags, check_file};
 
     use super::*;
@@ -71,6 +216,79 @@ mod tests {
         io::{BufWriter, Write},
     };
 
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_copy_ref_path_macos() {
+        let operator = CopyRefOperator::new();
+
+        let dir = std::env::temp_dir().join(format!("dbranch-clone-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let src_path = dir.join("source.txt");
+        let dest_path = dir.join("dest.txt");
+        const MSG: &str = "Eu gosto de memes\n";
+        const FILE_SIZE: usize = 2 * 1024 * 1024; // 2MB in bytes
+
+        let mut writer = BufWriter::new(File::create(&src_path).unwrap());
+        for _ in 0..FILE_SIZE / MSG.len() {
+            writer.write_all(MSG.as_bytes()).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let result = operator.copy_ref_path(&src_path, &dest_path);
+        assert!(result.is_ok(), "Failed to clone file: {:?}", result);
+
+        assert_eq!(
+            fs::read_to_string(&src_path).unwrap(),
+            fs::read_to_string(&dest_path).unwrap(),
+            "Contents do not match after copy_ref_path"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_copy_ref_without_reflink() {
+        let operator = CopyRefOperator::new().prefer_reflink(false);
+
+        let dir = std::env::temp_dir().join(format!("dbranch-copy-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (src_path, dest_path) = (dir.join("source.txt"), dir.join("dest.txt"));
+        fs::write(&src_path, "Eu gosto de memes\n".repeat(1000)).unwrap();
+
+        operator.copy_ref_path(&src_path, &dest_path).unwrap();
+        assert_eq!(fs::read(&src_path).unwrap(), fs::read(&dest_path).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Past the 2 GiB a single copy_file_range call copies at most.
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "writes 3 GiB of zeros where the filesystem can't reflink"]
+    fn test_copy_ref_copies_whole_large_file() {
+        const LEN: u64 = 3 * 1024 * 1024 * 1024;
+        let operator = CopyRefOperator::new();
+
+        let dir = std::env::temp_dir().join(format!("dbranch-large-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (src_path, dest_path) = (dir.join("source"), dir.join("dest"));
+
+        let src = File::create(&src_path).unwrap();
+        src.set_len(LEN).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&src, b"tail", LEN - 4).unwrap();
+
+        operator.copy_ref_path(&src_path, &dest_path).unwrap();
+
+        let dest = File::open(&dest_path).unwrap();
+        assert_eq!(dest.metadata().unwrap().len(), LEN);
+        let mut tail = [0u8; 4];
+        std::os::unix::fs::FileExt::read_exact_at(&dest, &mut tail, LEN - 4).unwrap();
+        assert_eq!(&tail, b"tail");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
     #[test]
     fn test_copy_ref_basic() {
         let operator = CopyRefOperator::new();
@@ -1,23 +1,945 @@
-use std::path::Path;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
+use chrono::{DateTime, Utc};
 use docker_wrapper::{
-    DockerCommand, InspectCommand, NetworkCreateCommand, NetworkLsCommand, RmCommand, RunCommand,
-    StopCommand,
+    DockerCommand, ExecCommand, InspectCommand, KillCommand, LogsCommand, NetworkCreateCommand,
+    NetworkLsCommand, PsCommand, RmCommand, RunCommand, StartCommand, StopCommand, StreamHandler,
+    VolumeLsCommand, VolumeRmCommand,
 };
+use size::Size;
 use tracing::{debug, info};
 
 use crate::{
-    config::{Branch, Config},
+    archive,
+    config::{Branch, Config, Engine},
     error::AppError,
 };
 
+/// How long `create_database` waits for a new container to accept connections.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Waits for a freshly started branch container, so that callers can
+/// connect as soon as `create_database` returns.
+async fn wait_for_new_container(config: &Config, name: &str) -> Result<(), AppError> {
+    debug!(
+        "Waiting for {} to accept connections",
+        config.container_name(name)
+    );
+    archive::wait_until_ready(config, name, READY_TIMEOUT)
+        .await
+        .map_err(|e| AppError::Database {
+            message: format!(
+                "Container {} started but never accepted connections; check `docker logs {}`",
+                config.container_name(name),
+                config.container_name(name)
+            ),
+            source: Some(e.into()),
+        })
+}
+
 pub trait DatabaseOperator {
     async fn create_database(&self, config: Config, port: u16, name: &str) -> Result<(), AppError>;
     async fn delete_database(&self, config: Config, name: &str) -> Result<(), AppError>;
-    async fn stop_database(&self, config: Config, name: &str) -> Result<(), AppError>;
+    async fn stop_database(
+        &self,
+        config: Config,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<StopOutcome, AppError>;
     async fn list_databases(&self, config: Config) -> Result<Vec<Branch>, AppError>;
     async fn get_database_info(&self, config: Config, name: &str) -> Result<Branch, AppError>;
     async fn is_container_running(&self, name: &str) -> Result<bool, AppError>;
+
+    /// Fails with a clear message when Docker is missing or its daemon is
+    /// down, rather than deep inside the first container operation.
+    async fn ensure_available(&self) -> Result<(), AppError> {
+        docker_available().await
+    }
+}
+
+/// Set once the Docker daemon answered, so that later checks are free.
+static DOCKER_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+async fn docker_available() -> Result<(), AppError> {
+    if DOCKER_AVAILABLE.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    debug!("Checking that the Docker daemon is reachable");
+
+    let output = tokio::process::Command::new("docker")
+        .args(["version", "--format", "{{.Server.Version}}"])
+        .output()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: if e.kind() == std::io::ErrorKind::NotFound {
+                "docker was not found on PATH; install Docker".to_string()
+            } else {
+                "Failed to run docker".to_string()
+            },
+            source: Some(e.into()),
+        })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Docker {
+            message: format!(
+                "The Docker daemon is not reachable, start Docker and try again ({})",
+                stderr.lines().next().unwrap_or_default().trim()
+            ),
+            source: None,
+        });
+    }
+
+    debug!(
+        "Docker daemon {} is reachable",
+        String::from_utf8_lossy(&output.stdout).trim()
+    );
+    DOCKER_AVAILABLE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+async fn ensure_network() -> Result<(), AppError> {
+    debug!("Creating Docker network 'dbranch-network'");
+
+    let net = NetworkLsCommand::new()
+        .filter("name", "dbranch-network")
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: "Failed to list Docker networks".to_string(),
+            source: Some(e.into()),
+        })?;
+
+    if net.success && net.stdout.contains("dbranch-network") {
+        debug!("Docker network 'dbranch-network' already exists");
+    } else {
+        debug!("Docker network 'dbranch-network' does not exist, creating it");
+        let _ = NetworkCreateCommand::new("dbranch-network")
+            .execute()
+            .await
+            .map_err(|e| AppError::Docker {
+                message: "Failed to create Docker network".to_string(),
+                source: Some(e.into()),
+            })?;
+        debug!("Docker network created successfully");
+    }
+
+    Ok(())
+}
+
+const LABEL_PROJECT: &str = "dbranch.project";
+const LABEL_BRANCH: &str = "dbranch.branch";
+const LABEL_CREATED_AT: &str = "dbranch.created_at";
+
+/// Labels identifying a branch container to `docker ps --filter label=...`
+/// and other tooling.
+fn container_labels(config: &Config, name: &str) -> Vec<String> {
+    vec![
+        format!("{}={}", LABEL_PROJECT, config.name),
+        format!("{}={}", LABEL_BRANCH, name),
+        format!("{}={}", LABEL_CREATED_AT, Utc::now().to_rfc3339()),
+    ]
+}
+
+/// Branches of the project that have a container, running or not, as told
+/// by their labels.
+async fn list_branch_containers(config: &Config) -> Result<Vec<Branch>, AppError> {
+    let ps_output = PsCommand::new()
+        .all()
+        .filter(format!("label={}={}", LABEL_PROJECT, config.name))
+        .format_template(format!(
+            "{{{{.Label \"{}\"}}}}\t{{{{.Label \"{}\"}}}}\t{{{{.Ports}}}}",
+            LABEL_BRANCH, LABEL_CREATED_AT
+        ))
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: "Failed to list Docker containers".to_string(),
+            source: Some(e.into()),
+        })?;
+
+    // Unlabelled containers carry neither the branch nor its creation time
+    let prefix = config.container_name("");
+    let unlabelled = unlabelled_containers(config, "{{.Ports}}", false).await?;
+    let unlabelled = unlabelled.iter().filter_map(|line| {
+        let (name, ports) = line.split_once('\t')?;
+        Some(format!("{}\t\t{}", name.strip_prefix(&prefix)?, ports))
+    });
+
+    Ok(ps_output
+        .stdout
+        .lines()
+        .map(str::to_string)
+        .chain(unlabelled)
+        .filter_map(|line| parse_branch_container(&line, config))
+        .collect())
+}
+
+/// `{{.Names}}\t<template>` lines of the project's containers created before
+/// containers were labelled, recognised by their `{project}_` name.
+async fn unlabelled_containers(
+    config: &Config,
+    template: &str,
+    size: bool,
+) -> Result<Vec<String>, AppError> {
+    let prefix = config.container_name("");
+    let mut ps = PsCommand::new()
+        .all()
+        .filter(format!("name={}", prefix))
+        .format_template(format!(
+            "{{{{.Label \"{}\"}}}}\t{{{{.Names}}}}\t{}",
+            LABEL_PROJECT, template
+        ));
+    if size {
+        ps = ps.size();
+    }
+
+    let ps_output = ps.execute().await.map_err(|e| AppError::Docker {
+        message: "Failed to list Docker containers".to_string(),
+        source: Some(e.into()),
+    })?;
+
+    Ok(ps_output
+        .stdout
+        .lines()
+        .filter_map(|line| unlabelled_line(line, &prefix))
+        .collect())
+}
+
+/// The `name\t...` rest of a `project\tname\t...` line when the container has
+/// no project label and its name starts with `prefix`. `docker ps` matches
+/// names anywhere, not only at the start.
+fn unlabelled_line(line: &str, prefix: &str) -> Option<String> {
+    let (project, rest) = line.split_once('\t')?;
+    (project.is_empty() && rest.starts_with(prefix)).then(|| rest.to_string())
+}
+
+/// Parses a `branch\tcreated_at\tports` line of `list_branch_containers`.
+fn parse_branch_container(line: &str, config: &Config) -> Option<Branch> {
+    let mut parts = line.split('\t');
+    let name = parts.next().filter(|n| !n.is_empty())?.to_string();
+    let created_at = parts
+        .next()
+        .and_then(|c| DateTime::parse_from_rfc3339(c).ok())
+        .map(|c| c.with_timezone(&Utc))
+        .unwrap_or(config.created_at);
+    // Ports look like "0.0.0.0:7001->5432/tcp, [::]:7001->5432/tcp"; stopped
+    // containers have none, fall back to the configured port
+    let port = parts
+        .next()
+        .and_then(|ports| ports.split("->").next())
+        .and_then(|host| host.rsplit(':').next())
+        .and_then(|port| port.parse().ok())
+        .or_else(|| {
+            config
+                .branches
+                .iter()
+                .find(|b| b.name == name)
+                .map(|b| b.port)
+        })?;
+
+    Some(Branch {
+        is_main: name == "main",
+        name,
+        port,
+        created_at,
+        read_only: false,
+        postgres_config: None,
+    })
+}
+
+/// Caps the container at the `memory_limit` and `cpus` of the branch's
+/// `postgres_config`, which must have been validated.
+fn with_resource_limits(mut run: RunCommand, config: &Config) -> RunCommand {
+    if let Some(postgres) = &config.postgres_config {
+        if let Some(memory) = &postgres.memory_limit {
+            run = run.memory(memory);
+        }
+        if let Some(cpus) = &postgres.cpus {
+            run = run.cpus(cpus);
+        }
+    }
+    run
+}
+
+/// Lets `docker inspect` tell a database that accepts connections from a
+/// container that is merely running.
+fn with_healthcheck(run: RunCommand, engine: &Engine) -> RunCommand {
+    let cmd = match engine {
+        // TCP, so the temporary server of the init scripts doesn't count
+        Engine::Postgres => "pg_isready -q -h 127.0.0.1",
+        Engine::Mysql => "mysqladmin ping -h 127.0.0.1 --silent",
+    };
+    run.health_cmd(cmd)
+        .health_interval("5s")
+        .health_timeout("5s")
+        .health_retries(5)
+        .health_start_period("10s")
+}
+
+/// `--user` of the branch containers, matching the owner of their data.
+fn run_as_user(config: &Config) -> String {
+    let (uid, gid) = config.run_as();
+    format!("{}:{}", uid, gid)
+}
+
+fn prepare_volume(config: &Config, name: &str) -> Result<String, AppError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let volume_path = Path::new(config.mount_point.clone().as_str())
+        .join(&config.name)
+        .join(name)
+        .join("data")
+        .to_string_lossy()
+        .into_owned();
+
+    std::fs::create_dir_all(volume_path.clone()).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to create data directory {}", volume_path),
+        source: Some(e.into()),
+    })?;
+
+    // https://github.com/docker-library/docs/tree/master/postgres#arbitrary---user-notes
+    let (uid, gid) = config.run_as();
+    let owned = std::fs::metadata(&volume_path)
+        .map(|m| m.uid() == uid && m.gid() == gid)
+        .unwrap_or(false);
+    if owned {
+        debug!("{} is already owned by {}:{}", volume_path, uid, gid);
+    } else {
+        std::os::unix::fs::chown(volume_path.clone(), Some(uid), Some(gid)).map_err(|e| {
+            AppError::FileSystem {
+                message: format!("Failed to chown {} to {}:{}", volume_path, uid, gid),
+                source: Some(e.into()),
+            }
+        })?;
+    }
+
+    Ok(volume_path)
+}
+
+async fn remove_container(config: &Config, name: &str) -> Result<(), AppError> {
+    debug!("Stopping and removing container: {}", name);
+
+    if config.skip_for_dry_run(&format!("remove container {}", config.container_name(name))) {
+        return Ok(());
+    }
+
+    if !container_exists(&config.container_name(name)).await {
+        return Err(AppError::ContainerNotFound {
+            name: config.container_name(name),
+        });
+    }
+
+    let stop_output = StopCommand::new(config.container_name(name))
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: format!(
+                "Failed to stop Docker container {}",
+                config.container_name(name)
+            ),
+            source: Some(e.into()),
+        })?;
+
+    if !(stop_output.is_success()) {
+        debug!(
+            "Container {} might already be stopped: {}",
+            name, stop_output.stderr
+        );
+    } else {
+        info!("Container {} stopped successfully", name);
+    }
+
+    let rm_output = RmCommand::new(config.container_name(name))
+        .volumes()
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: format!(
+                "Failed to remove Docker container {}",
+                config.container_name(name)
+            ),
+            source: Some(e.into()),
+        })?;
+
+    if rm_output.removed_contexts().is_empty() {
+        debug!(
+            "Container {} might already be stopped: {}",
+            name, rm_output.stderr
+        );
+    } else {
+        info!("Container {} stopped successfully", name);
+    }
+
+    Ok(())
+}
+
+/// Exit code of a container whose main process was killed with SIGKILL.
+const SIGKILL_EXIT_CODE: i64 = 128 + 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopOutcome {
+    Graceful,
+    Forced,
+    NotRunning,
+    /// Running, but left alone because of `--dry-run`
+    Skipped,
+}
+
+async fn stop_container(
+    config: &Config,
+    name: &str,
+    timeout: Duration,
+) -> Result<StopOutcome, AppError> {
+    let container_name = config.container_name(name);
+
+    if !container_running(&container_name).await? {
+        debug!("Container {} is not running", container_name);
+        return Ok(StopOutcome::NotRunning);
+    }
+
+    if config.skip_for_dry_run(&format!("stop container {}", container_name)) {
+        return Ok(StopOutcome::Skipped);
+    }
+
+    debug!(
+        "Stopping container {} with a {}s timeout",
+        container_name,
+        timeout.as_secs()
+    );
+
+    // Give docker a little longer than the grace period before deciding it is wedged
+    let stop = StopCommand::new(container_name.clone()).timeout_duration(timeout);
+    match tokio::time::timeout(timeout + Duration::from_secs(5), stop.execute()).await {
+        Ok(Ok(stop_output)) if stop_output.is_success() => {
+            debug!("docker stop returned for {}", container_name);
+        }
+        Ok(Ok(stop_output)) => {
+            debug!(
+                "docker stop failed for {}: {}",
+                container_name, stop_output.stderr
+            );
+        }
+        Ok(Err(e)) => {
+            debug!("docker stop failed for {}: {}", container_name, e);
+        }
+        Err(_) => {
+            debug!("docker stop timed out for {}", container_name);
+        }
+    }
+
+    if !container_running(&container_name).await? {
+        // docker stop sends SIGKILL itself once the timeout expires
+        if exit_code(&container_name).await == Some(SIGKILL_EXIT_CODE) {
+            info!(
+                "Container {} did not stop within {}s and was killed",
+                container_name,
+                timeout.as_secs()
+            );
+            return Ok(StopOutcome::Forced);
+        }
+        info!("Container {} stopped successfully", container_name);
+        return Ok(StopOutcome::Graceful);
+    }
+
+    info!(
+        "Container {} did not stop within {}s, killing it",
+        container_name,
+        timeout.as_secs()
+    );
+
+    let kill_output = KillCommand::new(container_name.clone())
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: format!("Failed to kill Docker container {}", container_name),
+            source: Some(e.into()),
+        })?;
+
+    if !kill_output.success {
+        return Err(AppError::Docker {
+            message: format!(
+                "Failed to kill Docker container {}: {}",
+                container_name, kill_output.stderr
+            ),
+            source: None,
+        });
+    }
+
+    Ok(StopOutcome::Forced)
+}
+
+/// Starts the existing, stopped container of a branch.
+pub async fn start_container(config: &Config, name: &str) -> Result<(), AppError> {
+    let container_name = config.container_name(name);
+    debug!("Starting container {}", container_name);
+
+    if config.skip_for_dry_run(&format!("start container {}", container_name)) {
+        return Ok(());
+    }
+
+    if !container_exists(&container_name).await {
+        return Err(AppError::ContainerNotFound {
+            name: container_name,
+        });
+    }
+
+    StartCommand::new(container_name.as_str())
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: format!("Failed to start container {}", container_name),
+            source: Some(e.into()),
+        })?;
+
+    Ok(())
+}
+
+/// Arguments of `docker exec` running `command` in the container of
+/// `branch`, with a terminal allocated when `tty` is set. The variables in
+/// `env` are passed by name, docker taking their values from its own
+/// environment.
+pub fn exec_args(
+    config: &Config,
+    branch: &str,
+    tty: bool,
+    env: &[&str],
+    command: &[String],
+) -> Vec<String> {
+    let mut args = vec![String::from("exec"), String::from("-i")];
+    if tty {
+        args.push(String::from("-t"));
+    }
+    for name in env {
+        args.push(String::from("-e"));
+        args.push(name.to_string());
+    }
+    args.push(config.container_name(branch));
+    args.extend(command.iter().cloned());
+    args
+}
+
+/// Database client invocation running `sql` as the configured user.
+pub struct SqlCommand {
+    /// Variable the client reads the password from, and the password. Set it
+    /// in docker's environment and pass only the name, so that the password
+    /// never shows up in a process's arguments.
+    pub password_env: (&'static str, String),
+    pub command: Vec<String>,
+}
+
+pub fn sql_command(config: &Config, sql: &str) -> Result<SqlCommand, AppError> {
+    let (user, password) = config.credentials()?;
+    let database = config.database_name();
+    let (password_var, client) = match config.engine {
+        Engine::Postgres => (
+            "PGPASSWORD",
+            vec!["psql", "-U", &user, "-d", &database, "-c", sql],
+        ),
+        Engine::Mysql => (
+            "MYSQL_PWD",
+            vec!["mysql", "-u", &user, &database, "-e", sql],
+        ),
+    };
+
+    Ok(SqlCommand {
+        password_env: (password_var, password),
+        command: client.into_iter().map(String::from).collect(),
+    })
+}
+
+/// Whether docker knows a container called `name`, running or not.
+async fn container_exists(name: &str) -> bool {
+    InspectCommand::new(name)
+        .execute()
+        .await
+        .is_ok_and(|output| output.success)
+}
+
+/// State of a branch container, including its healthcheck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerHealth {
+    Healthy,
+    Starting,
+    Unhealthy,
+    /// Running without a healthcheck, e.g. created by an older dbranch
+    Running,
+    Stopped,
+}
+
+impl ContainerHealth {
+    pub fn is_running(&self) -> bool {
+        *self != ContainerHealth::Stopped
+    }
+
+    /// Label of the container column of `status`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            ContainerHealth::Healthy => "✅ Healthy",
+            ContainerHealth::Starting => "⏳ Starting",
+            ContainerHealth::Unhealthy => "⚠️ Unhealthy",
+            ContainerHealth::Running => "✅ Running",
+            ContainerHealth::Stopped => "❌ Stopped",
+        }
+    }
+}
+
+/// Reads the state of a container from `docker inspect`; missing containers
+/// are reported as stopped.
+pub async fn container_health(name: &str) -> ContainerHealth {
+    match InspectCommand::new(name).execute().await {
+        Ok(output) if output.success => parse_health(&output.stdout),
+        Ok(_) => ContainerHealth::Stopped,
+        Err(e) => {
+            debug!("Failed to inspect container '{}': {}", name, e);
+            ContainerHealth::Stopped
+        }
+    }
+}
+
+/// `State.ExitCode` of a container, `None` when it can't be inspected.
+async fn exit_code(name: &str) -> Option<i64> {
+    match InspectCommand::new(name).execute().await {
+        Ok(output) if output.success => parse_exit_code(&output.stdout),
+        Ok(_) => None,
+        Err(e) => {
+            debug!("Failed to inspect container '{}': {}", name, e);
+            None
+        }
+    }
+}
+
+fn parse_exit_code(inspect: &str) -> Option<i64> {
+    let value = serde_json::from_str::<serde_json::Value>(inspect).ok()?;
+    value[0]["State"]["ExitCode"].as_i64()
+}
+
+fn parse_health(inspect: &str) -> ContainerHealth {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(inspect) else {
+        return ContainerHealth::Stopped;
+    };
+    let state = &value[0]["State"];
+    if state["Running"].as_bool() != Some(true) {
+        return ContainerHealth::Stopped;
+    }
+    match state["Health"]["Status"].as_str() {
+        Some("healthy") => ContainerHealth::Healthy,
+        Some("starting") => ContainerHealth::Starting,
+        Some("unhealthy") => ContainerHealth::Unhealthy,
+        _ => ContainerHealth::Running,
+    }
+}
+
+async fn container_running(name: &str) -> Result<bool, AppError> {
+    debug!("Checking if container '{}' is running", name);
+
+    let inspect_output = InspectCommand::new(name).execute().await;
+
+    match inspect_output {
+        Ok(output) => {
+            if output.success && !output.stdout.is_empty() {
+                let is_running = output.stdout.contains("\"Running\":true")
+                    || output.stdout.contains("\"Running\": true");
+                debug!("Container '{}' running status: {}", name, is_running);
+                Ok(is_running)
+            } else {
+                debug!("Container '{}' not found or inspect failed", name);
+                Ok(false)
+            }
+        }
+        Err(e) => {
+            debug!("Failed to inspect container '{}': {}", name, e);
+            Ok(false)
+        }
+    }
+}
+
+/// Removes the project's containers that are not running, together with their
+/// anonymous volumes. Returns the removed container names and the size of their
+/// writable layers.
+pub async fn remove_stopped_containers(config: &Config) -> Result<Vec<(String, u64)>, AppError> {
+    debug!(
+        "Looking for stopped containers of project '{}'",
+        config.name
+    );
+
+    let ps_output = PsCommand::new()
+        .all()
+        .size()
+        .filter(format!("label={}={}", LABEL_PROJECT, config.name))
+        .format_template("{{.Names}}\t{{.State}}\t{{.Size}}")
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: "Failed to list Docker containers".to_string(),
+            source: Some(e.into()),
+        })?;
+    let unlabelled = unlabelled_containers(config, "{{.State}}\t{{.Size}}", true).await?;
+
+    let mut removed = Vec::new();
+
+    for line in ps_output
+        .stdout
+        .lines()
+        .chain(unlabelled.iter().map(String::as_str))
+    {
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() < 3 {
+            continue;
+        }
+
+        let (container_name, state) = (parts[0], parts[1]);
+        if !matches!(state, "exited" | "created" | "dead") {
+            debug!("Skipping container {} in state {}", container_name, state);
+            continue;
+        }
+
+        // Size is reported as e.g. "63B (virtual 275MB)"
+        let size = parts[2]
+            .split_whitespace()
+            .next()
+            .and_then(|s| Size::from_str(s).ok())
+            .map(|s| s.bytes() as u64)
+            .unwrap_or(0);
+
+        if config.skip_for_dry_run(&format!("remove container {}", container_name)) {
+            continue;
+        }
+
+        RmCommand::new(container_name)
+            .volumes()
+            .execute()
+            .await
+            .map_err(|e| AppError::Docker {
+                message: format!("Failed to remove Docker container {}", container_name),
+                source: Some(e.into()),
+            })?;
+
+        info!("Removed stopped container {}", container_name);
+        removed.push((container_name.to_string(), size));
+    }
+
+    Ok(removed)
+}
+
+/// Removes the project's volumes that no container uses anymore, e.g. those
+/// left behind by a container removed without `--volumes`. Returns their names.
+pub async fn remove_dangling_volumes(config: &Config) -> Result<Vec<String>, AppError> {
+    debug!("Looking for dangling volumes of project '{}'", config.name);
+
+    let ls_output = VolumeLsCommand::new()
+        .filter("dangling", "true")
+        .filter("label", format!("{}={}", LABEL_PROJECT, config.name))
+        .quiet()
+        .execute()
+        .await
+        .map_err(|e| AppError::Docker {
+            message: "Failed to list Docker volumes".to_string(),
+            source: Some(e.into()),
+        })?;
+
+    let mut removed = Vec::new();
+
+    for volume in ls_output
+        .stdout
+        .lines()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+    {
+        if config.skip_for_dry_run(&format!("remove volume {}", volume)) {
+            continue;
+        }
+
+        VolumeRmCommand::new(volume)
+            .execute()
+            .await
+            .map_err(|e| AppError::Docker {
+                message: format!("Failed to remove Docker volume {}", volume),
+                source: Some(e.into()),
+            })?;
+
+        info!("Removed dangling volume {}", volume);
+        removed.push(volume.to_string());
+    }
+
+    Ok(removed)
+}
+
+/// Prints the last `tail` lines of a branch container's logs, then keeps
+/// streaming new lines with `follow` until the container stops or Ctrl-C.
+pub async fn container_logs(
+    config: &Config,
+    name: &str,
+    follow: bool,
+    tail: usize,
+) -> Result<(), AppError> {
+    let container_name = config.container_name(name);
+
+    let exists = InspectCommand::new(&container_name)
+        .execute()
+        .await
+        .map(|output| output.success)
+        .unwrap_or(false);
+    if !exists {
+        return Err(AppError::Docker {
+            message: format!(
+                "Container {} of branch {} does not exist",
+                container_name, name
+            ),
+            source: None,
+        });
+    }
+
+    let mut logs = LogsCommand::new(&container_name).tail(tail.to_string());
+    if follow {
+        logs = logs.follow();
+    }
+
+    let result = tokio::select! {
+        result = logs.stream(StreamHandler::print()) => result.map_err(|e| AppError::Docker {
+            message: format!("Failed to read the logs of {}", container_name),
+            source: Some(e.into()),
+        })?,
+        _ = tokio::signal::ctrl_c() => {
+            debug!("Stopped following the logs of {}", container_name);
+            return Ok(());
+        }
+    };
+
+    if !result.success {
+        return Err(AppError::Docker {
+            message: format!(
+                "docker logs {} exited with code {}",
+                container_name, result.exit_code
+            ),
+            source: None,
+        });
+    }
+    Ok(())
+}
+
+/// Repository digest of a local image (e.g. `postgres@sha256:...`), if it has one.
+pub async fn image_digest(image: &str) -> Option<String> {
+    let output = InspectCommand::new(image)
+        .object_type("image")
+        .format("{{index .RepoDigests 0}}")
+        .execute()
+        .await
+        .ok()?;
+
+    let digest = output.stdout.trim();
+    if output.success && !digest.is_empty() {
+        Some(digest.to_string())
+    } else {
+        debug!("No repository digest for image {}", image);
+        None
+    }
+}
+
+/// Connects to a branch on `port` and checks that a database server answers:
+/// PostgreSQL must reply to an SSLRequest, MySQL must send its greeting.
+pub async fn ping(engine: &Engine, port: u16) -> bool {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let attempt = async {
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut reply = [0u8; 1];
+
+        match engine {
+            Engine::Postgres => {
+                // Length 8, SSLRequest code 80877103; the server answers 'S' or 'N'
+                stream
+                    .write_all(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f])
+                    .await?;
+                stream.read_exact(&mut reply).await?;
+                Ok::<bool, std::io::Error>(matches!(&reply, b"S" | b"N"))
+            }
+            Engine::Mysql => {
+                stream.read_exact(&mut reply).await?;
+                Ok(true)
+            }
+        }
+    };
+
+    match tokio::time::timeout(Duration::from_secs(2), attempt).await {
+        Ok(Ok(answered)) => answered,
+        Ok(Err(e)) => {
+            debug!("Ping of port {} failed: {}", port, e);
+            false
+        }
+        Err(_) => {
+            debug!("Ping of port {} timed out", port);
+            false
+        }
+    }
+}
+
+/// Activity of a running branch as reported by the database itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Activity {
+    pub connections: u64,
+    pub database_size: u64,
+}
+
+/// Queries `pg_stat_database` inside the branch container. Returns `None`
+/// when the query fails or the engine has no equivalent wired up.
+pub async fn database_activity(config: &Config, name: &str) -> Option<Activity> {
+    if config.engine != Engine::Postgres {
+        debug!("Live activity is only available for PostgreSQL");
+        return None;
+    }
+
+    let user = config.postgres_config.as_ref()?.user.clone();
+    let output = ExecCommand::new(
+        config.container_name(name),
+        vec![
+            "psql".into(),
+            "-U".into(),
+            user,
+            "-d".into(),
+            config.database_name(),
+            "-tAc".into(),
+            "SELECT numbackends, pg_database_size(datname) FROM pg_stat_database \
+             WHERE datname = current_database()"
+                .into(),
+        ],
+    )
+    .execute()
+    .await
+    .map_err(|e| debug!("Failed to query activity of {}: {}", name, e))
+    .ok()?;
+
+    parse_activity(&output.stdout)
+}
+
+/// Parses the `numbackends|size` line printed by `psql -tA`.
+fn parse_activity(output: &str) -> Option<Activity> {
+    let (connections, size) = output.trim().split_once('|')?;
+    Some(Activity {
+        connections: connections.trim().parse().ok()?,
+        database_size: size.trim().parse().ok()?,
+    })
+}
+
+/// Where the branch data directory is mounted in Postgres containers. PGDATA
+/// is set below it explicitly, so images with another default data directory
+/// keep using the branch data.
+const POSTGRES_VOLUME: &str = "/var/lib/postgresql/data";
+
+fn postgres_env(config: &Config) -> Result<Vec<(&'static str, String)>, AppError> {
+    let (user, password) = config.credentials()?;
+
+    Ok(vec![
+        ("POSTGRES_USER", user),
+        ("POSTGRES_PASSWORD", password),
+        ("POSTGRES_DB", config.database_name()),
+        ("PGDATA", format!("{}/pgdata", POSTGRES_VOLUME)),
+    ])
 }
 
 pub struct PostgresOperator {}
@@ -31,44 +953,26 @@ impl PostgresOperator {
 
 impl DatabaseOperator for PostgresOperator {
     async fn create_database(&self, config: Config, port: u16, name: &str) -> Result<(), AppError> {
+        let config = config.for_branch(name)?;
+        if let Some(postgres) = &config.postgres_config {
+            postgres.validate_resource_limits()?;
+        }
         info!(
             "Creating PostgreSQL database '{}' for project '{}' on port {}",
             name, config.name, port
         );
 
-        debug!("Creating Docker network 'dbranch-network'");
+        if config.skip_for_dry_run(&format!(
+            "create container {} on port {}",
+            config.container_name(name),
+            port
+        )) {
+            return Ok(());
+        }
 
-        let net = NetworkLsCommand::new()
-            .filter("name", "dbranch-network")
-            .execute()
-            .await
-            .map_err(|e| AppError::Docker {
-                message: format!("Failed to list Docker networks: {}", e),
-            })?;
+        ensure_network().await?;
 
-        if net.success && net.stdout.contains("dbranch-network") {
-            debug!("Docker network 'dbranch-network' already exists");
-        } else {
-            debug!("Docker network 'dbranch-network' does not exist, creating it");
-            let _ = NetworkCreateCommand::new("dbranch-network")
-                .execute()
-                .await
-                .map_err(|e| AppError::Docker {
-                    message: format!("Failed to create Docker network: {}", e),
-                })?;
-            debug!("Docker network created successfully");
-        }
-
-        let volume_path = Path::new(config.mount_point.clone().as_str())
-            .join(&config.name)
-            .join(&name)
-            .join("data")
-            .to_string_lossy()
-            .into_owned();
-
-        std::fs::create_dir_all(volume_path.clone()).unwrap();
-        // https://github.com/docker-library/docs/tree/master/postgres#arbitrary---user-notes
-        std::os::unix::fs::chown(volume_path.clone(), Some(1000), Some(1000)).unwrap();
+        let volume_path = prepare_volume(&config, name)?;
 
         debug!(
             "Setting up PostgreSQL container with volume: {}",
@@ -77,46 +981,37 @@ impl DatabaseOperator for PostgresOperator {
         debug!(
             "Container configuration: user={}, database={}",
             config.postgres_config.clone().unwrap().user,
-            config
-                .postgres_config
-                .clone()
-                .unwrap()
-                .database
-                .as_ref()
-                .unwrap_or(&"dbranch".to_string())
+            config.database_name()
         );
 
-        let _output = RunCommand::new("postgres:17-alpine")
-            .name(format!("{}_{}", config.name, name))
-            .port(port, 5432)
+        let mut run = RunCommand::new(config.image())
+            .name(config.container_name(name))
+            .port(port, Engine::Postgres.container_port())
             .network("dbranch-network")
-            .user("1000:1000") // This allow the container to run with the host user permissions
-            .volume(volume_path, "/var/lib/postgresql/data")
-            .env(
-                "POSTGRES_USER",
-                config.postgres_config.clone().unwrap().user.as_str(),
-            )
-            .env(
-                "POSTGRES_PASSWORD",
-                config.postgres_config.clone().unwrap().password.as_str(),
-            )
-            .env(
-                "POSTGRES_DB",
-                config
-                    .postgres_config
-                    .clone()
-                    .unwrap()
-                    .database
-                    .clone()
-                    .or(Some("dbranch".into()))
-                    .unwrap(),
-            )
-            .env("PGDATA", "/var/lib/postgresql/data/pgdata")
-            .restart("no")
+            .user(run_as_user(&config)) // This allow the container to run with the host user permissions
+            .volume(volume_path, POSTGRES_VOLUME);
+        run = with_resource_limits(run, &config);
+        run = with_healthcheck(run, &Engine::Postgres);
+
+        for (key, value) in postgres_env(&config)? {
+            run = run.env(key, value);
+        }
+        if let Some(postgres) = &config.postgres_config
+            && !postgres.postgres_conf_extra.is_empty()
+        {
+            run = run.cmd(postgres.server_args()?);
+        }
+        for label in container_labels(&config, name) {
+            run = run.label(label);
+        }
+
+        let _output = run
+            .restart(config.restart_policy().as_str())
             .detach()
             .execute()
             .await
             .unwrap();
+        wait_for_new_container(&config, name).await?;
 
         info!(
             "PostgreSQL container '{}' created successfully on port {}",
@@ -132,90 +1027,158 @@ impl DatabaseOperator for PostgresOperator {
             name, config.name
         );
 
-        debug!("Stopping and removing PostgreSQL container: {}", name);
+        remove_container(&config, name).await?;
 
-        let stop_output = StopCommand::new(format!("{}_{}", config.name, name))
-            .execute()
-            .await
-            .map_err(|e| AppError::Docker {
-                message: format!(
-                    "Failed to stop Docker container {}: {}",
-                    format!("{}_{}", config.name, name),
-                    e
-                ),
-            })?;
+        info!("PostgreSQL container '{}' deleted successfully", name);
+        Ok(())
+    }
 
-        if !(stop_output.is_success()) {
-            debug!(
-                "Container {} might already be stopped: {}",
-                name, stop_output.stderr
-            );
-        } else {
-            info!("Container {} stopped successfully", name);
+    async fn stop_database(
+        &self,
+        config: Config,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<StopOutcome, AppError> {
+        let container_name = config.container_name(name);
+
+        info!(
+            "Stopping PostgreSQL database '{}' for project '{}'",
+            container_name, config.name
+        );
+
+        let outcome = stop_container(&config, name, timeout).await?;
+
+        info!(
+            "PostgreSQL container '{}' stopped ({:?})",
+            container_name, outcome
+        );
+        Ok(outcome)
+    }
+
+    async fn list_databases(&self, config: Config) -> Result<Vec<Branch>, AppError> {
+        debug!("Listing PostgreSQL databases for project '{}'", config.name);
+        list_branch_containers(&config).await
+    }
+
+    async fn get_database_info(&self, config: Config, name: &str) -> Result<Branch, AppError> {
+        debug!(
+            "Getting database info for '{}' in project '{}'",
+            name, config.name
+        );
+        // TODO: Implement logic to get information about a specific PostgreSQL database here
+        Err(AppError::NotImplemented {
+            command: "get_database_info".into(),
+        })
+    }
+
+    async fn is_container_running(&self, name: &str) -> Result<bool, AppError> {
+        container_running(name).await
+    }
+}
+
+pub struct MysqlOperator {}
+
+impl MysqlOperator {
+    pub fn new() -> Self {
+        debug!("Creating new MysqlOperator instance");
+        Self {}
+    }
+}
+
+impl DatabaseOperator for MysqlOperator {
+    async fn create_database(&self, config: Config, port: u16, name: &str) -> Result<(), AppError> {
+        let config = config.for_branch(name)?;
+        if let Some(postgres) = &config.postgres_config {
+            postgres.validate_resource_limits()?;
         }
+        info!(
+            "Creating MySQL database '{}' for project '{}' on port {}",
+            name, config.name, port
+        );
 
-        let rm_output = RmCommand::new(format!("{}_{}", config.name, name))
-            .volumes()
+        if config.skip_for_dry_run(&format!(
+            "create container {} on port {}",
+            config.container_name(name),
+            port
+        )) {
+            return Ok(());
+        }
+
+        ensure_network().await?;
+
+        let volume_path = prepare_volume(&config, name)?;
+
+        debug!("Setting up MySQL container with volume: {}", volume_path);
+
+        let (user, password) = config.credentials()?;
+
+        let mut run = RunCommand::new(Engine::Mysql.image())
+            .name(config.container_name(name))
+            .port(port, Engine::Mysql.container_port())
+            .network("dbranch-network")
+            .user(run_as_user(&config)) // This allow the container to run with the host user permissions
+            .volume(volume_path, "/var/lib/mysql")
+            .env("MYSQL_USER", user.as_str())
+            .env("MYSQL_PASSWORD", password.as_str())
+            .env("MYSQL_ROOT_PASSWORD", password.as_str())
+            .env("MYSQL_DATABASE", config.database_name());
+        run = with_resource_limits(run, &config);
+        run = with_healthcheck(run, &Engine::Mysql);
+        for label in container_labels(&config, name) {
+            run = run.label(label);
+        }
+
+        run.restart(config.restart_policy().as_str())
+            .detach()
             .execute()
             .await
             .map_err(|e| AppError::Docker {
                 message: format!(
-                    "Failed to remove Docker container {}: {}",
-                    format!("{}_{}", config.name, name),
-                    e
+                    "Failed to start MySQL container {}",
+                    config.container_name(name)
                 ),
+                source: Some(e.into()),
             })?;
+        wait_for_new_container(&config, name).await?;
 
-        if !(rm_output.removed_contexts().len() > 0) {
-            debug!(
-                "Container {} might already be stopped: {}",
-                name, rm_output.stderr
-            );
-        } else {
-            info!("Container {} stopped successfully", name);
-        }
+        info!(
+            "MySQL container '{}' created successfully on port {}",
+            name, port
+        );
 
-        info!("PostgreSQL container '{}' deleted successfully", name);
         Ok(())
     }
 
-    async fn stop_database(&self, config: Config, name: &str) -> Result<(), AppError> {
-        let container_name = format!("{}_{}", config.name, name);
-
+    async fn delete_database(&self, config: Config, name: &str) -> Result<(), AppError> {
         info!(
-            "Stopping PostgreSQL database '{}' for project '{}'",
-            container_name, config.name
+            "Deleting MySQL database '{}' for project '{}'",
+            name, config.name
         );
 
-        debug!("Stopping PostgreSQL container: {}", container_name);
-
-        let stop_output = StopCommand::new(container_name.clone())
-            .execute()
-            .await
-            .map_err(|e| AppError::Docker {
-                message: format!("Failed to stop Docker container {}: {}", container_name, e),
-            })?;
+        remove_container(&config, name).await?;
 
-        if !stop_output.is_success() {
-            debug!(
-                "Container {} might already be stopped: {}",
-                container_name, stop_output.stderr
-            );
-        } else {
-            info!("Container {} stopped successfully", container_name);
-        }
+        info!("MySQL container '{}' deleted successfully", name);
+        Ok(())
+    }
 
+    async fn stop_database(
+        &self,
+        config: Config,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<StopOutcome, AppError> {
         info!(
-            "PostgreSQL container '{}' stopped successfully",
-            container_name
+            "Stopping MySQL database '{}' for project '{}'",
+            config.container_name(name),
+            config.name
         );
-        Ok(())
+
+        stop_container(&config, name, timeout).await
     }
 
     async fn list_databases(&self, config: Config) -> Result<Vec<Branch>, AppError> {
-        debug!("Listing PostgreSQL databases for project '{}'", config.name);
-        // TODO: Implement logic to list PostgreSQL databases here
-        Ok(vec![])
+        debug!("Listing MySQL databases for project '{}'", config.name);
+        list_branch_containers(&config).await
     }
 
     async fn get_database_info(&self, config: Config, name: &str) -> Result<Branch, AppError> {
@@ -223,33 +1186,249 @@ impl DatabaseOperator for PostgresOperator {
             "Getting database info for '{}' in project '{}'",
             name, config.name
         );
-        // TODO: Implement logic to get information about a specific PostgreSQL database here
         Err(AppError::NotImplemented {
             command: "get_database_info".into(),
         })
     }
 
     async fn is_container_running(&self, name: &str) -> Result<bool, AppError> {
-        debug!("Checking if container '{}' is running", name);
-
-        let inspect_output = InspectCommand::new(name).execute().await;
-
-        match inspect_output {
-            Ok(output) => {
-                if output.success && !output.stdout.is_empty() {
-                    let is_running = output.stdout.contains("\"Running\":true")
-                        || output.stdout.contains("\"Running\": true");
-                    debug!("Container '{}' running status: {}", name, is_running);
-                    Ok(is_running)
-                } else {
-                    debug!("Container '{}' not found or inspect failed", name);
-                    Ok(false)
-                }
-            }
-            Err(e) => {
-                debug!("Failed to inspect container '{}': {}", name, e);
-                Ok(false)
-            }
+        container_running(name).await
+    }
+}
+
+/// Dispatches to the operator matching the project's configured engine.
+pub enum EngineOperator {
+    Postgres(PostgresOperator),
+    Mysql(MysqlOperator),
+}
+
+impl EngineOperator {
+    pub fn for_engine(engine: &Engine) -> Self {
+        match engine {
+            Engine::Postgres => EngineOperator::Postgres(PostgresOperator::new()),
+            Engine::Mysql => EngineOperator::Mysql(MysqlOperator::new()),
+        }
+    }
+}
+
+impl DatabaseOperator for EngineOperator {
+    async fn create_database(&self, config: Config, port: u16, name: &str) -> Result<(), AppError> {
+        match self {
+            EngineOperator::Postgres(op) => op.create_database(config, port, name).await,
+            EngineOperator::Mysql(op) => op.create_database(config, port, name).await,
         }
     }
+
+    async fn delete_database(&self, config: Config, name: &str) -> Result<(), AppError> {
+        match self {
+            EngineOperator::Postgres(op) => op.delete_database(config, name).await,
+            EngineOperator::Mysql(op) => op.delete_database(config, name).await,
+        }
+    }
+
+    async fn stop_database(
+        &self,
+        config: Config,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<StopOutcome, AppError> {
+        match self {
+            EngineOperator::Postgres(op) => op.stop_database(config, name, timeout).await,
+            EngineOperator::Mysql(op) => op.stop_database(config, name, timeout).await,
+        }
+    }
+
+    async fn list_databases(&self, config: Config) -> Result<Vec<Branch>, AppError> {
+        match self {
+            EngineOperator::Postgres(op) => op.list_databases(config).await,
+            EngineOperator::Mysql(op) => op.list_databases(config).await,
+        }
+    }
+
+    async fn get_database_info(&self, config: Config, name: &str) -> Result<Branch, AppError> {
+        match self {
+            EngineOperator::Postgres(op) => op.get_database_info(config, name).await,
+            EngineOperator::Mysql(op) => op.get_database_info(config, name).await,
+        }
+    }
+
+    async fn is_container_running(&self, name: &str) -> Result<bool, AppError> {
+        match self {
+            EngineOperator::Postgres(op) => op.is_container_running(name).await,
+            EngineOperator::Mysql(op) => op.is_container_running(name).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_name_matches_connection_string() {
+        let mut config = Config::new("project".to_string());
+        config.postgres_config.as_mut().unwrap().password = "secret".to_string();
+
+        for database in [None, Some(String::from("app"))] {
+            config.postgres_config.as_mut().unwrap().database = database;
+
+            let created = postgres_env(&config)
+                .unwrap()
+                .into_iter()
+                .find(|(key, _)| *key == "POSTGRES_DB")
+                .map(|(_, value)| value)
+                .unwrap();
+
+            let dsn = config.connection_string("localhost", 7000, true).unwrap();
+            assert_eq!(dsn.rsplit('/').next().unwrap(), created);
+        }
+
+        assert_eq!(config.database_name(), "app");
+    }
+
+    #[test]
+    fn test_parse_activity() {
+        assert_eq!(
+            parse_activity("3|7901667\n"),
+            Some(Activity {
+                connections: 3,
+                database_size: 7901667,
+            })
+        );
+        assert_eq!(parse_activity(""), None);
+        assert_eq!(parse_activity("psql: error"), None);
+    }
+
+    #[test]
+    fn test_sql_exec_args() {
+        let mut config = Config::new("project".to_string());
+        config.postgres_config.as_mut().unwrap().password = "secret".to_string();
+
+        let sql = sql_command(&config, "SELECT 1").unwrap();
+        assert_eq!(sql.password_env, ("PGPASSWORD", "secret".to_string()));
+        assert_eq!(
+            exec_args(
+                &config,
+                "feature",
+                false,
+                &[sql.password_env.0],
+                &sql.command
+            ),
+            [
+                "exec",
+                "-i",
+                "-e",
+                "PGPASSWORD",
+                "project_feature",
+                "psql",
+                "-U",
+                "dbranch_user",
+                "-d",
+                "dbranch",
+                "-c",
+                "SELECT 1",
+            ]
+        );
+
+        config.engine = Engine::Mysql;
+        let sql = sql_command(&config, "SELECT 1").unwrap();
+        assert_eq!(sql.password_env, ("MYSQL_PWD", "secret".to_string()));
+        assert_eq!(sql.command[0], "mysql");
+    }
+
+    #[test]
+    fn test_parse_health() {
+        assert_eq!(
+            parse_health(r#"[{"State":{"Running":true,"Health":{"Status":"healthy"}}}]"#),
+            ContainerHealth::Healthy
+        );
+        assert_eq!(
+            parse_health(r#"[{"State":{"Running":true,"Health":{"Status":"starting"}}}]"#),
+            ContainerHealth::Starting
+        );
+        assert_eq!(
+            parse_health(r#"[{"State":{"Running":true}}]"#),
+            ContainerHealth::Running
+        );
+        assert_eq!(
+            parse_health(r#"[{"State":{"Running":false,"Health":{"Status":"unhealthy"}}}]"#),
+            ContainerHealth::Stopped
+        );
+        assert_eq!(parse_health("[]"), ContainerHealth::Stopped);
+    }
+
+    #[test]
+    fn test_parse_exit_code() {
+        assert_eq!(
+            parse_exit_code(r#"[{"State":{"Running":false,"ExitCode":137}}]"#),
+            Some(SIGKILL_EXIT_CODE)
+        );
+        assert_eq!(
+            parse_exit_code(r#"[{"State":{"Running":false,"ExitCode":0}}]"#),
+            Some(0)
+        );
+        assert_eq!(parse_exit_code("[]"), None);
+        assert_eq!(parse_exit_code("not json"), None);
+    }
+
+    #[tokio::test]
+    async fn test_ping_postgres_handshake() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 8];
+            socket.read_exact(&mut request).await.unwrap();
+            socket.write_all(b"N").await.unwrap();
+        });
+
+        assert!(ping(&Engine::Postgres, port).await);
+
+        // Nothing listens on the port once the listener is gone
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        assert!(!ping(&Engine::Postgres, closed_port).await);
+    }
+
+    #[test]
+    fn test_parse_branch_container() {
+        let config = Config::new("project".to_string());
+
+        let branch = parse_branch_container(
+            "feature\t2025-01-02T03:04:05+00:00\t0.0.0.0:7042->5432/tcp, [::]:7042->5432/tcp",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(branch.name, "feature");
+        assert_eq!(branch.port, 7042);
+        assert!(!branch.is_main);
+        assert_eq!(branch.created_at.to_rfc3339(), "2025-01-02T03:04:05+00:00");
+
+        // Stopped containers publish no ports
+        let main = parse_branch_container("main\t\t", &config).unwrap();
+        assert!(main.is_main);
+        assert_eq!(main.port, config.branches[0].port);
+
+        assert!(parse_branch_container("\t\t", &config).is_none());
+    }
+
+    #[test]
+    fn test_unlabelled_line() {
+        assert_eq!(
+            unlabelled_line("\tproject_feature\texited", "project_"),
+            Some("project_feature\texited".to_string())
+        );
+        // Labelled containers are listed by label already
+        assert_eq!(
+            unlabelled_line("project\tproject_feature\texited", "project_"),
+            None
+        );
+        assert_eq!(
+            unlabelled_line("\tother_project_x\texited", "project_"),
+            None
+        );
+    }
 }
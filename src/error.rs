@@ -1,5 +1,8 @@
 use thiserror::Error;
 
+/// Underlying cause of an `AppError`, e.g. the `std::io::Error` of a failed syscall.
+pub type Source = Box<dyn std::error::Error + Send + Sync>;
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Internal server error: {message}")]
@@ -12,9 +15,16 @@ pub enum AppError {
     #[error("Failed to parse configuration file: {message}")]
     ConfigParsing { message: String },
 
+    #[error("Project validation failed with {errors} error(s)")]
+    ValidationFailed { errors: usize },
+
     // File system errors
     #[error("File operation failed: {message}")]
-    FileSystem { message: String },
+    FileSystem {
+        message: String,
+        #[source]
+        source: Option<Source>,
+    },
 
     #[error("File not found: {path}")]
     FileNotFound { path: String },
@@ -32,11 +42,21 @@ pub enum AppError {
     #[error("Branch '{name}' not found")]
     BranchNotFound { name: String },
 
+    #[error("Invalid name '{name}': {reason}")]
+    InvalidName { name: String, reason: String },
+
     #[error("Default Project not found")]
     DefaultProjectNotFound,
 
+    #[error("Container '{name}' not found")]
+    ContainerNotFound { name: String },
+
     #[error("Database operation failed: {message}")]
-    Database { message: String },
+    Database {
+        message: String,
+        #[source]
+        source: Option<Source>,
+    },
 
     // Network and port errors
     #[error("No available ports found in range {min}-{max}")]
@@ -54,16 +74,65 @@ pub enum AppError {
 
     // BTRFS and disk operations
     #[error("BTRFS operation failed: {message}")]
-    Btrfs { message: String },
+    Btrfs {
+        message: String,
+        #[source]
+        source: Option<Source>,
+    },
 
     #[error("Disk mount operation failed: {message}")]
-    DiskMount { message: String },
+    DiskMount {
+        message: String,
+        #[source]
+        source: Option<Source>,
+    },
 
     // Docker operations
     #[error("Docker operation failed: {message}")]
-    Docker { message: String },
+    Docker {
+        message: String,
+        #[source]
+        source: Option<Source>,
+    },
 
     // Command not implemented
     #[error("Command '{command}' is not implemented")]
     NotImplemented { command: String },
 }
+
+impl AppError {
+    /// The error followed by each of its causes, separated by `: `.
+    pub fn chain(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_includes_source() {
+        let err = AppError::FileSystem {
+            message: "Failed to read config".to_string(),
+            source: Some(std::io::Error::other("disk on fire").into()),
+        };
+        assert_eq!(
+            err.chain(),
+            "File operation failed: Failed to read config: disk on fire"
+        );
+
+        let err = AppError::Docker {
+            message: "boom".to_string(),
+            source: None,
+        };
+        assert_eq!(err.chain(), err.to_string());
+    }
+}
@@ -3,6 +3,8 @@ use std::{
     path::Path,
 };
 
+use tracing::{debug, warn};
+
 use crate::error::AppError;
 // from https://github.com/torvalds/linux/blob/cbf658dd09419f1ef9de11b9604e950bdd5c170b/include/uapi/linux/fiemap.h
 
@@ -79,6 +81,7 @@ pub struct FiemapExtent {
     fe_reserved32: [u32; 3],
 }
 
+#[cfg(target_os = "linux")]
 #[repr(C)]
 #[derive(Debug, Default)]
 pub struct FiemapRequest {
@@ -96,13 +99,27 @@ pub struct FiemapRequest {
     fm_reserved: u32,
 }
 
+#[cfg(target_os = "linux")]
+/// Extents fetched per FIEMAP call; highly fragmented files have thousands.
+const EXTENTS_PER_CALL: usize = 512;
+
+#[cfg(target_os = "linux")]
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct FiemapRequestFull {
     pub request: FiemapRequest,
     /// array of mapped extents (out)
-    /// 32 is the most that `Default` gives us ootb.
-    pub fm_extents: [FiemapExtent; 32],
+    pub fm_extents: [FiemapExtent; EXTENTS_PER_CALL],
+}
+
+#[cfg(target_os = "linux")]
+impl Default for FiemapRequestFull {
+    fn default() -> Self {
+        Self {
+            request: FiemapRequest::default(),
+            fm_extents: [FiemapExtent::default(); EXTENTS_PER_CALL],
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -111,134 +128,728 @@ pub struct Fiemap {
     pub flags: Vec<FiemapFlags>,
 }
 
+/// Every extent of `f`, in logical order, each reported once.
+#[cfg(target_os = "linux")]
 pub fn check_file(f: File) -> Result<Vec<Fiemap>, AppError> {
     use std::os::fd::AsRawFd;
 
-    let file_size = f.metadata().unwrap().len();
+    let file_size = f
+        .metadata()
+        .map_err(|e| AppError::FileSystem {
+            message: "Failed to read file metadata".to_string(),
+            source: Some(e.into()),
+        })?
+        .len();
     const FS_IOC_FIEMAP: u64 = nix::libc::_IOWR::<FiemapRequest>(0x66, 11);
 
     let mut all_extents: Vec<Fiemap> = Vec::new();
     let mut current_offset: u64 = 0;
+    // Boxed, as the extents take 28 KiB
+    let mut fr = Box::<FiemapRequestFull>::default();
 
-    if file_size == 0 {
-        return Ok(all_extents);
-    }
-
-    loop {
-        let mut fr = Box::new(FiemapRequestFull::default());
-        fr.request.fm_start = current_offset;
-        fr.request.fm_length = file_size - current_offset;
-        fr.request.fm_flags = 0;
-        fr.request.fm_extent_count = 32;
+    while current_offset < file_size {
+        fr.request = FiemapRequest {
+            fm_start: current_offset,
+            fm_length: file_size - current_offset,
+            fm_extent_count: EXTENTS_PER_CALL as u32,
+            ..Default::default()
+        };
 
+        // SAFETY: `fr` is a FiemapRequest followed by room for
+        // `fm_extent_count` extents, as the ioctl expects
         let ret = unsafe { nix::libc::ioctl(f.as_raw_fd(), FS_IOC_FIEMAP, &mut *fr) };
 
         if ret == -1 {
             let errno = std::io::Error::last_os_error();
-            eprintln!(
-                "FIEMAP ioctl failed: {} (errno: {}) (file_path: {:?})",
-                errno,
-                errno.raw_os_error().unwrap(),
-                f.metadata().unwrap()
-            );
             return Err(AppError::FileSystem {
-                message: format!("FIEMAP ioctl failed: {}", errno),
+                message: "FIEMAP ioctl failed".to_string(),
+                source: Some(errno.into()),
             });
         }
 
-        if fr.request.fm_mapped_extents == 0 {
-            break;
-        }
-
+        let mapped = fr.request.fm_mapped_extents as usize;
         let mut found_last = false;
-        for i in 0..fr.request.fm_mapped_extents as usize {
-            let extent = fr.fm_extents[i];
+        let mut next_offset = current_offset;
+        for extent in &fr.fm_extents[..mapped] {
+            // Any extent overlapping `fm_start` is returned, so one that
+            // straddles the end of the previous call was already recorded
+            if extent.fe_logical < current_offset {
+                continue;
+            }
+
             all_extents.push(Fiemap {
-                extent,
+                extent: *extent,
                 flags: FiemapFlags::from_bits(extent.fe_flags),
             });
+            found_last |= extent.fe_flags & FiemapFlags::Last as u32 != 0;
+            next_offset = next_offset.max(extent.fe_logical + extent.fe_length);
+        }
 
-            if extent.fe_flags & FiemapFlags::Last as u32 != 0 {
-                found_last = true;
-                break;
-            }
+        // A short batch means the rest of the range has no extents, and not
+        // moving forward would loop forever
+        if found_last || mapped < EXTENTS_PER_CALL || next_offset == current_offset {
+            break;
+        }
+        current_offset = next_offset;
+    }
+
+    Ok(all_extents)
+}
+
+/// macOS has no FIEMAP, so the blocks allocated to `f` are reported as a
+/// single extent of unknown location. APFS doesn't tell which blocks a clone
+/// shares, hence nothing shows up as shared.
+#[cfg(target_os = "macos")]
+pub fn check_file(f: File) -> Result<Vec<Fiemap>, AppError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = f.metadata().map_err(|e| AppError::FileSystem {
+        message: "Failed to read file metadata".to_string(),
+        source: Some(e.into()),
+    })?;
+
+    // `st_blocks` counts 512-byte units whatever the filesystem block size
+    let allocated = metadata.blocks() * 512;
+    if allocated == 0 {
+        return Ok(Vec::new());
+    }
+
+    let flags = FiemapFlags::Unknown as u32 | FiemapFlags::Last as u32;
+    Ok(vec![Fiemap {
+        extent: FiemapExtent {
+            fe_length: allocated,
+            fe_flags: flags,
+            ..Default::default()
+        },
+        flags: FiemapFlags::from_bits(flags),
+    }])
+}
+
+/// Bytes `f` takes on disk, read from its btrfs file extent items the way
+/// `compsize` does: FIEMAP reports compressed extents with their uncompressed
+/// length. A compressed extent counts once, however many times the file
+/// refers to it. Like any tree search, this needs CAP_SYS_ADMIN.
+#[cfg(target_os = "linux")]
+pub fn encoded_disk_size(f: &File) -> Result<u64, AppError> {
+    use std::{collections::HashSet, os::fd::AsRawFd, os::unix::fs::MetadataExt};
+
+    const BTRFS_IOC_TREE_SEARCH: u64 = nix::libc::_IOWR::<BtrfsSearchArgs>(0x94, 17);
+
+    let inode = f
+        .metadata()
+        .map_err(|e| AppError::FileSystem {
+            message: "Failed to read file metadata".to_string(),
+            source: Some(e.into()),
+        })?
+        .ino();
+
+    let mut args = Box::new(BtrfsSearchArgs {
+        key: BtrfsSearchKey {
+            min_objectid: inode,
+            max_objectid: inode,
+            max_offset: u64::MAX,
+            max_transid: u64::MAX,
+            min_type: BTRFS_EXTENT_DATA_KEY,
+            max_type: BTRFS_EXTENT_DATA_KEY,
+            ..Default::default()
+        },
+        buf: [0; SEARCH_BUFFER],
+    });
+    let mut seen = HashSet::new();
+    let mut disk_size = 0u64;
+
+    loop {
+        args.key.nr_items = u32::MAX;
+        // SAFETY: `args` is the search key followed by the result buffer, as
+        // the ioctl expects
+        let ret = unsafe { nix::libc::ioctl(f.as_raw_fd(), BTRFS_IOC_TREE_SEARCH, &mut *args) };
+        if ret == -1 {
+            let errno = std::io::Error::last_os_error();
+            return Err(AppError::FileSystem {
+                message: "btrfs tree search failed".to_string(),
+                source: Some(errno.into()),
+            });
+        }
 
-            current_offset = extent.fe_logical + extent.fe_length;
+        let items = args.key.nr_items;
+        if items == 0 {
+            break;
         }
 
-        if found_last || fr.request.fm_mapped_extents < 32 {
+        let mut pos = 0;
+        let mut last_offset = 0;
+        for _ in 0..items {
+            // btrfs_ioctl_search_header: transid, objectid, offset, type, len
+            let header = &args.buf[pos..pos + 32];
+            last_offset = u64::from_le_bytes(header[16..24].try_into().unwrap());
+            let item_type = u32::from_le_bytes(header[24..28].try_into().unwrap());
+            let len = u32::from_le_bytes(header[28..32].try_into().unwrap()) as usize;
+
+            let item = &args.buf[pos + 32..pos + 32 + len];
+            if item_type == BTRFS_EXTENT_DATA_KEY {
+                disk_size += extent_disk_size(item, &mut seen);
+            }
+            pos += 32 + len;
+        }
+
+        if last_offset == u64::MAX {
             break;
         }
+        args.key.min_offset = last_offset + 1;
     }
 
-    Ok(all_extents)
+    Ok(disk_size)
+}
+
+/// APFS compression is transparent to `st_blocks`, which `check_file`
+/// already reports, so there is nothing more to read.
+#[cfg(target_os = "macos")]
+pub fn encoded_disk_size(_f: &File) -> Result<u64, AppError> {
+    Err(AppError::FileSystem {
+        message: "Compressed extents are only read on btrfs".to_string(),
+        source: None,
+    })
+}
+
+#[cfg(target_os = "linux")]
+const BTRFS_EXTENT_DATA_KEY: u32 = 108;
+
+// from https://github.com/torvalds/linux/blob/cbf658dd09419f1ef9de11b9604e950bdd5c170b/include/uapi/linux/btrfs.h
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct BtrfsSearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    // max items to return (in), items returned (out)
+    nr_items: u32,
+    unused: u32,
+    unused1: [u64; 4],
+}
+
+#[cfg(target_os = "linux")]
+const SEARCH_BUFFER: usize = 4096 - std::mem::size_of::<BtrfsSearchKey>();
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct BtrfsSearchArgs {
+    key: BtrfsSearchKey,
+    buf: [u8; SEARCH_BUFFER],
+}
+
+/// On-disk bytes of one `btrfs_file_extent_item`. Compressed extents count
+/// whole, and only the first time `seen` meets them; uncompressed ones count
+/// the part the file refers to, like FIEMAP does.
+fn extent_disk_size(item: &[u8], seen: &mut std::collections::HashSet<u64>) -> u64 {
+    // generation, ram_bytes, compression, encryption, other_encoding, type
+    const HEADER: usize = 21;
+    const INLINE: u8 = 0;
+
+    let read_u64 = |at: usize| u64::from_le_bytes(item[at..at + 8].try_into().unwrap());
+    if item.len() < HEADER {
+        return 0;
+    }
+    let compression = item[16];
+
+    // Inline data follows the header, compressed or not
+    if item[20] == INLINE {
+        return (item.len() - HEADER) as u64;
+    }
+    if item.len() < HEADER + 32 {
+        return 0;
+    }
+
+    // disk_bytenr, disk_num_bytes, offset, num_bytes
+    let disk_bytenr = read_u64(HEADER);
+    let disk_num_bytes = read_u64(HEADER + 8);
+    let num_bytes = read_u64(HEADER + 24);
+    if disk_bytenr == 0 {
+        // A hole
+        0
+    } else if compression == 0 {
+        num_bytes
+    } else if seen.insert(disk_bytenr) {
+        disk_num_bytes
+    } else {
+        0
+    }
 }
 
 pub struct FileInfo {
     pub real_size: u64,
     pub shared_size: u64,
+    /// Bytes actually allocated on disk: the mapped extents, so holes of a
+    /// sparse file take none
+    pub allocated_size: u64,
+    /// Like `allocated_size`, with compressed extents at their on-disk size
+    pub disk_size: u64,
     pub is_compressed: bool,
     pub name: String,
 }
 
+impl FileInfo {
+    /// How many times smaller the file is on disk, see
+    /// `FolderInfo::compression_ratio`.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        compression_ratio(self.real_size, self.disk_size)
+    }
+}
+
 pub struct FolderInfo {
     pub logical_size: u64,
     pub shared_size: u64,
+    /// Sum of the `allocated_size` of every file
+    pub allocated_size: u64,
+    /// Sum of the `disk_size` of every file
+    pub disk_size: u64,
+    /// Part of `logical_size` that lives in ignored directories
+    pub ignored_size: u64,
     pub files: Vec<FileInfo>,
 }
 
-pub fn get_folder_size(path: &Path) -> Option<FolderInfo> {
+impl FolderInfo {
+    /// Allocated bytes not shared with any other file. Unlike
+    /// `logical_size - shared_size`, holes don't count as unique data.
+    pub fn unique_size(&self) -> u64 {
+        self.allocated_size.saturating_sub(self.shared_size)
+    }
+
+    /// How many times smaller the folder is on disk, e.g. `1.8` when
+    /// compression saved 45%, `None` when it takes no space at all.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        compression_ratio(self.logical_size, self.disk_size)
+    }
+}
+
+fn compression_ratio(logical_size: u64, disk_size: u64) -> Option<f64> {
+    (disk_size > 0).then(|| logical_size as f64 / disk_size as f64)
+}
+
+pub fn get_folder_size(path: &Path) -> Result<FolderInfo, AppError> {
+    get_folder_size_ignoring(path, &[])
+}
+
+/// Like `get_folder_size`, additionally reporting in `ignored_size` how much
+/// of the total sits in directories named like one of `ignore` (e.g. `pg_wal`).
+///
+/// Only failing to read `path` itself is an error: entries below it that
+/// can't be read are logged and skipped, and files that can't be mapped
+/// still count with their logical size.
+pub fn get_folder_size_ignoring(path: &Path, ignore: &[String]) -> Result<FolderInfo, AppError> {
     let mut fi = FolderInfo {
         logical_size: 0u64,
         shared_size: 0u64,
+        allocated_size: 0u64,
+        disk_size: 0u64,
+        ignored_size: 0u64,
         files: Vec::new(),
     };
 
-    if path.is_dir() {
-        for entry in fs::read_dir(path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-
-            if path.is_dir() {
-                let subfolder_info = get_folder_size(&path);
-                if let Some(subfolder) = subfolder_info {
-                    fi.logical_size += subfolder.logical_size;
-                    fi.shared_size += subfolder.shared_size;
-                    fi.files.extend(subfolder.files);
-                } else {
+    if !path.is_dir() {
+        return Err(AppError::FileNotFound {
+            path: path.to_string_lossy().to_string(),
+        });
+    }
+
+    let entries = fs::read_dir(path).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to read directory {:?}", path),
+        source: Some(e.into()),
+    })?;
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping an entry of {:?}: {}", path, e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        // Doesn't follow symlinks
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                continue;
+            }
+        };
+
+        // Not followed: a symlink (e.g. a tablespace) may point out of
+        // the tree or back into it
+        if metadata.is_symlink() {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            let subfolder = match get_folder_size_ignoring(&path, ignore) {
+                Ok(subfolder) => subfolder,
+                Err(e) => {
+                    warn!("Skipping {:?}: {}", path, e.chain());
                     continue;
                 }
+            };
+            let ignored = ignore
+                .iter()
+                .any(|name| entry.file_name().to_string_lossy() == name.as_str());
+            fi.ignored_size += if ignored {
+                subfolder.logical_size
             } else {
-                let file_info = check_file(fs::File::open(&path).unwrap());
-                fi.logical_size += fs::metadata(&path).unwrap().len();
-                fi.shared_size += file_info
-                    .as_ref()
-                    .unwrap()
-                    .iter()
-                    .filter(|f| f.flags.contains(&FiemapFlags::Shared))
-                    .map(|f| f.extent.fe_length)
-                    .sum::<u64>();
-                fi.files.push(FileInfo {
-                    real_size: fs::metadata(&path).unwrap().len(),
-                    shared_size: file_info
-                        .as_ref()
-                        .unwrap()
-                        .iter()
-                        .filter(|f| f.flags.contains(&FiemapFlags::Shared))
-                        .map(|f| f.extent.fe_length)
-                        .sum::<u64>(),
-                    is_compressed: file_info
-                        .as_ref()
-                        .unwrap()
-                        .iter()
-                        .any(|f| f.flags.contains(&FiemapFlags::Encoded)),
-                    name: path.file_name().unwrap().to_string_lossy().to_string(),
-                });
+                subfolder.ignored_size
+            };
+            fi.logical_size += subfolder.logical_size;
+            fi.shared_size += subfolder.shared_size;
+            fi.allocated_size += subfolder.allocated_size;
+            fi.disk_size += subfolder.disk_size;
+            fi.files.extend(subfolder.files);
+            continue;
+        }
+
+        // Sockets, FIFOs and devices have no extents, and opening a FIFO
+        // would block
+        let extents = if metadata.is_file() {
+            match File::open(&path)
+                .map_err(|e| AppError::FileSystem {
+                    message: format!("Failed to open {:?}", path),
+                    source: Some(e.into()),
+                })
+                .and_then(check_file)
+            {
+                Ok(extents) => extents,
+                Err(e) => {
+                    warn!("Counting {:?} as unshared: {}", path, e.chain());
+                    Vec::new()
+                }
             }
+        } else {
+            Vec::new()
+        };
+
+        let shared_size = extents
+            .iter()
+            .filter(|f| f.flags.contains(&FiemapFlags::Shared))
+            .map(|f| f.extent.fe_length)
+            .sum::<u64>();
+        // FIEMAP only reports extents, so holes are never counted
+        let allocated_size = extents.iter().map(|f| f.extent.fe_length).sum::<u64>();
+        let is_compressed = extents
+            .iter()
+            .any(|f| f.flags.contains(&FiemapFlags::Encoded));
+        // Only compressed extents are smaller on disk than FIEMAP says
+        let disk_size = if is_compressed {
+            match File::open(&path)
+                .map_err(|e| AppError::FileSystem {
+                    message: format!("Failed to open {:?}", path),
+                    source: Some(e.into()),
+                })
+                .and_then(|f| encoded_disk_size(&f))
+            {
+                Ok(disk_size) => disk_size,
+                Err(e) => {
+                    debug!("Counting {:?} as uncompressed: {}", path, e.chain());
+                    allocated_size
+                }
+            }
+        } else {
+            allocated_size
+        };
+
+        let file = FileInfo {
+            real_size: metadata.len(),
+            shared_size,
+            allocated_size,
+            disk_size,
+            is_compressed,
+            name: entry.file_name().to_string_lossy().to_string(),
+        };
+        if file.is_compressed
+            && let Some(ratio) = file.compression_ratio()
+        {
+            debug!("{} is compressed {:.1}x", file.name, ratio);
         }
 
-        return Some(fi);
+        fi.logical_size += file.real_size;
+        fi.shared_size += file.shared_size;
+        fi.allocated_size += file.allocated_size;
+        fi.disk_size += file.disk_size;
+        fi.files.push(file);
     }
 
-    None
+    Ok(fi)
+}
+
+/// Physical extents `(start, end)` of every regular file under `path`.
+/// Extents without a stable disk location (unknown, delalloc, inline) are skipped.
+pub fn get_physical_extents(path: &Path) -> Vec<(u64, u64)> {
+    let mut extents = Vec::new();
+
+    let Ok(entries) = fs::read_dir(path) else {
+        return extents;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        if file_type.is_symlink() {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            extents.extend(get_physical_extents(&path));
+            continue;
+        }
+
+        let Ok(file) = fs::File::open(&path) else {
+            continue;
+        };
+
+        if let Ok(file_extents) = check_file(file) {
+            extents.extend(
+                file_extents
+                    .iter()
+                    .filter(|f| {
+                        !f.flags.contains(&FiemapFlags::Unknown)
+                            && !f.flags.contains(&FiemapFlags::Delalloc)
+                            && !f.flags.contains(&FiemapFlags::DataInline)
+                    })
+                    .map(|f| {
+                        (
+                            f.extent.fe_physical,
+                            f.extent.fe_physical + f.extent.fe_length,
+                        )
+                    }),
+            );
+        }
+    }
+
+    extents
+}
+
+fn merge_extents(extents: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut sorted = extents.to_vec();
+    sorted.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(sorted.len());
+    for (start, end) in sorted {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Number of bytes on disk referenced by both sets of physical extents.
+pub fn overlap_size(a: &[(u64, u64)], b: &[(u64, u64)]) -> u64 {
+    let a = merge_extents(a);
+    let b = merge_extents(b);
+
+    let (mut i, mut j) = (0, 0);
+    let mut total = 0u64;
+    while i < a.len() && j < b.len() {
+        let start = a[i].0.max(b[j].0);
+        let end = a[i].1.min(b[j].1);
+        if start < end {
+            total += end - start;
+        }
+
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    total
+}
+
+/// Number of bytes on disk referenced by `extents` but not by `others`, i.e.
+/// what removing the files behind `extents` would give back.
+pub fn exclusive_size(extents: &[(u64, u64)], others: &[(u64, u64)]) -> u64 {
+    let total: u64 = merge_extents(extents)
+        .iter()
+        .map(|(start, end)| end - start)
+        .sum();
+    total - overlap_size(extents, others)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlap_size() {
+        let main = vec![(0, 100), (200, 300), (50, 150)];
+        let branch = vec![(100, 250), (280, 400)];
+
+        // [100,150) + [200,250) + [280,300)
+        assert_eq!(overlap_size(&main, &branch), 120);
+        assert_eq!(overlap_size(&main, &[]), 0);
+        assert_eq!(overlap_size(&main, &main), 250);
+
+        assert_eq!(exclusive_size(&branch, &main), 270 - 120);
+        assert_eq!(exclusive_size(&main, &main), 0);
+        assert_eq!(exclusive_size(&main, &[]), 250);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_check_file_fragmented() {
+        use std::os::unix::fs::FileExt;
+
+        const BLOCK: u64 = 4096;
+        const EXTENTS: u64 = 2000;
+
+        let path = std::env::temp_dir().join(format!("dbranch-{}", uuid::Uuid::new_v4()));
+        let file = File::create(&path).unwrap();
+        // Every other block written, so no two extents can be merged
+        for i in 0..EXTENTS {
+            file.write_all_at(&[1u8; BLOCK as usize], i * 2 * BLOCK)
+                .unwrap();
+        }
+        file.sync_all().unwrap();
+
+        let extents = check_file(File::open(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        let extents = extents.unwrap();
+
+        assert_eq!(extents.len(), EXTENTS as usize);
+        assert!(
+            extents
+                .windows(2)
+                .all(|w| w[0].extent.fe_logical < w[1].extent.fe_logical)
+        );
+        assert_eq!(
+            extents.iter().map(|e| e.extent.fe_length).sum::<u64>(),
+            EXTENTS * BLOCK
+        );
+        assert!(extents.last().unwrap().flags.contains(&FiemapFlags::Last));
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        let folder = FolderInfo {
+            logical_size: 180,
+            shared_size: 0,
+            allocated_size: 180,
+            disk_size: 100,
+            ignored_size: 0,
+            files: Vec::new(),
+        };
+        assert_eq!(folder.compression_ratio(), Some(1.8));
+        assert_eq!(compression_ratio(0, 0), None);
+    }
+
+    #[test]
+    fn test_extent_disk_size() {
+        let item = |compression: u8, kind: u8, disk_bytenr: u64, disk_num_bytes: u64| {
+            let mut item = vec![0u8; 53];
+            item[8..16].copy_from_slice(&131072u64.to_le_bytes());
+            item[16] = compression;
+            item[20] = kind;
+            item[21..29].copy_from_slice(&disk_bytenr.to_le_bytes());
+            item[29..37].copy_from_slice(&disk_num_bytes.to_le_bytes());
+            item[45..53].copy_from_slice(&131072u64.to_le_bytes());
+            item
+        };
+        let mut seen = std::collections::HashSet::new();
+
+        // zstd extent, on disk at 4096 bytes, referenced twice
+        assert_eq!(
+            extent_disk_size(&item(3, 1, 1 << 20, 4096), &mut seen),
+            4096
+        );
+        assert_eq!(extent_disk_size(&item(3, 1, 1 << 20, 4096), &mut seen), 0);
+        // Uncompressed extent and hole
+        assert_eq!(
+            extent_disk_size(&item(0, 1, 2 << 20, 131072), &mut seen),
+            131072
+        );
+        assert_eq!(extent_disk_size(&item(0, 1, 0, 0), &mut seen), 0);
+        // Inline data right after the header
+        assert_eq!(extent_disk_size(&[0u8; 21 + 100], &mut seen), 100);
+    }
+
+    #[test]
+    fn test_get_folder_size_ignoring() {
+        let dir = std::env::temp_dir().join(format!("dbranch-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("base")).unwrap();
+        fs::create_dir_all(dir.join("pg_wal").join("archive_status")).unwrap();
+        fs::write(dir.join("base").join("1"), [0u8; 10]).unwrap();
+        fs::write(dir.join("pg_wal").join("000001"), [0u8; 20]).unwrap();
+        fs::write(
+            dir.join("pg_wal").join("archive_status").join("x"),
+            [0u8; 5],
+        )
+        .unwrap();
+
+        let info = get_folder_size_ignoring(&dir, &[String::from("pg_wal")]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(info.logical_size, 35);
+        assert_eq!(info.ignored_size, 25);
+    }
+
+    #[test]
+    fn test_get_folder_size_counts_unreadable_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("dbranch-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("restricted"), [0u8; 10]).unwrap();
+        fs::set_permissions(dir.join("restricted"), fs::Permissions::from_mode(0o000)).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(dir.join("socket")).unwrap();
+
+        let info = get_folder_size(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let info = info.unwrap();
+        assert_eq!(info.logical_size, 10);
+        assert_eq!(info.files.len(), 2);
+        assert!(get_folder_size(&dir).is_err());
+    }
+
+    #[test]
+    fn test_get_folder_size_sparse_file() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = std::env::temp_dir().join(format!("dbranch-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = File::create(dir.join("sparse")).unwrap();
+        file.set_len(1024 * 1024 * 1024).unwrap();
+        file.write_all_at(&[1u8; 4096], 512 * 1024 * 1024).unwrap();
+        file.sync_all().unwrap();
+
+        let info = get_folder_size(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let info = info.unwrap();
+
+        assert_eq!(info.logical_size, 1024 * 1024 * 1024);
+        assert_eq!(info.allocated_size, 4096);
+        assert_eq!(info.unique_size(), 4096);
+    }
+
+    #[test]
+    fn test_get_folder_size_skips_symlinks() {
+        let dir = std::env::temp_dir().join(format!("dbranch-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("base")).unwrap();
+        fs::write(dir.join("base").join("1"), [0u8; 10]).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("base").join("loop")).unwrap();
+
+        let info = get_folder_size(&dir).unwrap();
+        let extents = get_physical_extents(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(info.logical_size, 10);
+        assert_eq!(info.files.len(), 1);
+        assert!(extents.len() <= 1);
+    }
 }
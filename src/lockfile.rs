@@ -0,0 +1,121 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{config::config_path, error::AppError};
+
+pub const LOCK_FILE: &str = ".dbranch.lock";
+
+/// Provenance of a branch, recorded when it is created.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LockEntry {
+    /// Branch the data was copied from
+    pub source: String,
+    pub created_at: DateTime<Utc>,
+    pub image: String,
+    /// Repository digest of `image` at creation, when docker knows it
+    pub image_digest: Option<String>,
+    /// Hash of the logical dump of the branch right after creation; `None`
+    /// for read-only branches, which have no container to dump
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LockFile {
+    pub branches: BTreeMap<String, LockEntry>,
+}
+
+/// The lockfile lives next to the config file.
+pub fn lock_path() -> PathBuf {
+    config_path()
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(LOCK_FILE)
+}
+
+impl LockFile {
+    pub fn load() -> Result<Self, AppError> {
+        let path = lock_path();
+        debug!("Loading lockfile from {:?}", path);
+
+        match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| AppError::ConfigParsing {
+                message: format!("Failed to parse lockfile {:?}: {}", path, e),
+            }),
+            Err(_) => {
+                debug!("Lockfile doesn't exist, starting empty");
+                Ok(LockFile::default())
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), AppError> {
+        let path = lock_path();
+        debug!("Saving lockfile to {:?}", path);
+
+        let file = File::create(&path).map_err(|e| AppError::FileSystem {
+            message: format!("Failed to create lockfile {:?}", path),
+            source: Some(e.into()),
+        })?;
+
+        serde_json::to_writer_pretty(file, self).map_err(|e| AppError::FileSystem {
+            message: format!("Failed to write lockfile {:?}", path),
+            source: Some(e.into()),
+        })
+    }
+
+    pub fn record(name: &str, entry: LockEntry) -> Result<(), AppError> {
+        let mut lock = LockFile::load()?;
+        lock.branches.insert(name.to_string(), entry);
+        lock.save()
+    }
+}
+
+/// 64-bit FNV-1a. Not cryptographic, but stable across builds, which is all
+/// change detection needs.
+pub struct ContentHasher(u64);
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hasher_matches_fnv1a() {
+        assert_eq!(ContentHasher::new().finish(), "cbf29ce484222325");
+
+        let mut hasher = ContentHasher::new();
+        hasher.update(b"a");
+        assert_eq!(hasher.finish(), "af63dc4c8601ec8c");
+
+        let mut split = ContentHasher::new();
+        split.update(b"foo");
+        split.update(b"bar");
+        let mut whole = ContentHasher::new();
+        whole.update(b"foobar");
+        assert_eq!(split.finish(), whole.finish());
+    }
+}
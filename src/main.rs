@@ -1,46 +1,101 @@
+mod api;
+mod archive;
+mod audit;
+mod btrfs;
 mod cli;
 mod config;
 mod copy_ref;
 mod database_operator;
 mod error;
 mod fiemap;
+mod lockfile;
+mod metrics;
+mod pg_startup;
+mod proxy_auth;
+mod runtime;
 mod snapshot;
+#[cfg(target_os = "linux")]
+mod splice;
+mod throttle;
+mod validate;
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
     cli::{AppState, Commands},
-    config::Config,
-    error::AppError,
+    config::{Config, ConfigLock, ProxyAuth},
+    metrics::ProxyMetrics,
+    runtime::RuntimeState,
+    throttle::Throttled,
 };
 use anyhow::Result;
 use clap::Parser;
 use cli::Cli;
 use tokio::{
-    io,
-    net::{TcpListener, TcpStream},
-    sync::RwLock,
+    io::{self, AsyncWriteExt, BufReader},
+    net::{
+        TcpListener, TcpSocket, TcpStream,
+        tcp::{ReadHalf, WriteHalf},
+    },
+    signal::unix::SignalKind,
+    sync::{RwLock, Semaphore, mpsc},
+    task::{self, JoinSet},
 };
-use tracing::{debug, info};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
-    debug!("CLI arguments parsed: {:?}", cli.command);
+    if let Some(path) = &cli.config {
+        config::set_config_path(path.clone());
+    }
 
+    let filter = match cli.log_level() {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new("INFO"))
-        .with(tracing_subscriber::fmt::layer())
+        .with(filter)
+        // Logs go to stderr, keeping stdout for output such as `dump`
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
-    debug!("Tracing subscriber initialized with debug level");
+    debug!("CLI arguments parsed: {:?}", cli.command);
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("❌ {}", e.chain());
+            ExitCode::FAILURE
+        }
+    }
+}
 
+async fn run(cli: Cli) -> Result<(), error::AppError> {
     info!("🌿 dBranch - PostgreSQL Database Branching System");
 
+    // Held until the command returns, across its load and saves of the config
+    let lock = if cli.command.writes_config() {
+        Some(ConfigLock::acquire()?)
+    } else {
+        None
+    };
+
     debug!("Loading configuration from file...");
 
-    let config = Arc::new(RwLock::new(Config::from_file().unwrap()));
+    let config = match &lock {
+        Some(lock) => Config::from_file_locked(lock)?,
+        None => Config::from_file()?,
+    };
+    let config = Arc::new(RwLock::new(config));
 
     tokio::spawn(sync_config(config.clone()));
 
@@ -49,6 +104,9 @@ async fn main() {
     debug!("Creating CLI handler with initial state");
     let mut cli_handler = cli::CliHandler::new(AppState {
         config: config.read().await.clone(),
+        port_range: cli.port_range,
+        dry_run: cli.dry_run,
+        restart: cli.restart,
     });
     debug!("CLI handler initialized");
 
@@ -57,86 +115,520 @@ async fn main() {
         Commands::Start => {
             info!("Starting dBranch service...");
             debug!("Initializing server components");
-            run_server(config).await.unwrap();
-            info!("dBranch service started successfully");
+            run_server(config).await?;
+            info!("dBranch service stopped");
         }
         cmd => {
             debug!("Delegating command to CLI handler");
-            cli_handler.handle_command(cmd).await.unwrap();
+            cli_handler.handle_command(cmd).await?;
             debug!("Command processed successfully");
         }
     }
+    Ok(())
 }
 
+/// Quiet period after a config change before reloading, so that bursts of
+/// writes (a save followed by an editor's own write) cause a single reload.
+const CONFIG_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Keeps `config` in sync with the config file: reloads on change where the
+/// file can be watched, polls every 2 seconds otherwise.
 async fn sync_config(config: Arc<RwLock<Config>>) {
-    loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-        match Config::from_file() {
-            Ok(new_config) => {
-                config.write().await.clone_from(&new_config);
-            }
-            Err(e) => {
-                AppError::Internal {
-                    message: format!("Failed to reload configuration: {}", e),
-                };
+    #[cfg(target_os = "linux")]
+    match watch_config_files() {
+        Ok(mut changes) => {
+            while changes.recv().await.is_some() {
+                tokio::time::sleep(CONFIG_DEBOUNCE).await;
+                while changes.try_recv().is_ok() {}
+                debug!("Config file changed, reloading");
+                reload_config(&config).await;
             }
+            debug!("Config watcher stopped, falling back to polling");
         }
+        Err(e) => debug!("Failed to watch the config file ({}), polling instead", e),
     }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        reload_config(&config).await;
+    }
+}
+
+async fn reload_config(config: &Arc<RwLock<Config>>) {
+    match Config::from_file() {
+        Ok(new_config) => {
+            config.write().await.clone_from(&new_config);
+        }
+        Err(e) => error!("Failed to reload configuration: {}", e.chain()),
+    }
+}
+
+/// Watches the directories of the project and global config files with
+/// inotify, as saving replaces the file rather than writing to it. Sends on
+/// the channel whenever one of the files is written or replaced.
+#[cfg(target_os = "linux")]
+fn watch_config_files() -> io::Result<mpsc::UnboundedReceiver<()>> {
+    use rustix::fs::inotify::{self, CreateFlags, Reader, WatchFlags};
+    use std::{mem::MaybeUninit, os::unix::ffi::OsStrExt, path::PathBuf};
+
+    let fd = inotify::init(CreateFlags::CLOEXEC)?;
+    let mut watched = Vec::new();
+    let paths = [Some(config::config_path()), config::global_config_path()];
+    for path in paths.into_iter().flatten() {
+        let Some(name) = path.file_name().map(|n| n.to_os_string()) else {
+            continue;
+        };
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        let flags = WatchFlags::CLOSE_WRITE | WatchFlags::MOVED_TO | WatchFlags::CREATE;
+        match inotify::add_watch(&fd, &dir, flags) {
+            Ok(wd) => watched.push((wd, name)),
+            // The project config must be watched, the global one is optional
+            Err(e) if watched.is_empty() => return Err(e.into()),
+            Err(e) => debug!("Not watching {:?}: {}", dir, e),
+        }
+    }
+
+    let (changes, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut buffer = [MaybeUninit::<u8>::uninit(); 4096];
+        let mut events = Reader::new(&fd, &mut buffer);
+        loop {
+            let event = match events.next() {
+                Ok(event) => event,
+                Err(rustix::io::Errno::INTR) => continue,
+                Err(e) => {
+                    error!("Failed to read config file events: {}", e);
+                    return;
+                }
+            };
+
+            let is_config = watched.iter().any(|(wd, name)| {
+                *wd == event.wd()
+                    && event
+                        .file_name()
+                        .is_some_and(|file| file.to_bytes() == name.as_bytes())
+            });
+            if is_config && changes.send(()).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(receiver)
 }
 
 async fn run_server(config: Arc<RwLock<Config>>) -> Result<(), error::AppError> {
     debug!("Server startup initiated");
-    let bind_addr = format!("0.0.0.0:{}", config.read().await.proxy_port);
-    info!("📡 Listening on: {}", bind_addr);
+    let (bind_addr, dual_stack) = {
+        let config = config.read().await;
+        (config.proxy_addr()?, config.dual_stack)
+    };
+    info!(
+        "📡 Listening on: {}{}",
+        bind_addr,
+        if dual_stack { " (dual-stack)" } else { "" }
+    );
+
+    let listener =
+        bind_listener(bind_addr, dual_stack)
+            .await
+            .map_err(|e| error::AppError::Network {
+                message: format!("Failed to listen on {}: {}", bind_addr, e),
+            })?;
 
-    let listener = TcpListener::bind(&bind_addr).await.unwrap();
+    // Read once: resizing the limit of a running proxy would strand permits
+    let max_connections = config.read().await.max_connections;
+    let permits = Arc::new(Semaphore::new(max_connections));
+    let metrics = Arc::new(ProxyMetrics::default());
+
+    let api = api::serve(config.clone(), metrics.clone());
+    tokio::spawn(async move {
+        if let Err(e) = api.await {
+            error!("{}", e.chain());
+        }
+    });
+
+    // Installed before the state file announces the pid, as the default
+    // action of the signal would kill the proxy
+    let mut bounce =
+        tokio::signal::unix::signal(SignalKind::from_raw(runtime::BOUNCE_SIGNAL.as_raw()))
+            .map_err(|e| error::AppError::Internal {
+                message: format!("Failed to listen for {:?}: {}", runtime::BOUNCE_SIGNAL, e),
+            })?;
+    let runtime = RuntimeState {
+        pid: std::process::id(),
+        proxy_addr: listener.local_addr().unwrap_or(bind_addr),
+        active_branch: target_branch(&*config.read().await).map(|(name, _)| name),
+        started_at: chrono::Utc::now(),
+    };
+    if let Err(e) = runtime.save() {
+        warn!("{}", e.chain());
+    }
+    let tracker = tokio::spawn(track_active_branch(config.clone(), runtime.clone()));
+
+    let mut connections = JoinSet::new();
+    let mut clients: HashMap<task::Id, SocketAddr> = HashMap::new();
+
+    loop {
+        let (client, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    break;
+                }
+            },
+            Some(joined) = connections.join_next_with_id() => {
+                report_connection(joined, &mut clients);
+                continue;
+            }
+            Some(()) = bounce.recv() => {
+                info!(
+                    "Closing {} connection(s) so that clients reconnect to the active branch",
+                    metrics.active_connections()
+                );
+                connections.abort_all();
+                continue;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!(
+                    "Shutting down, waiting for {} connection(s)",
+                    metrics.active_connections()
+                );
+                break;
+            }
+        };
+
+        let Ok(permit) = permits.clone().try_acquire_owned() else {
+            warn!(
+                "Refusing connection from {}: max_connections ({}) reached",
+                addr, max_connections
+            );
+            continue;
+        };
 
-    while let Ok((client, addr)) = listener.accept().await {
         println!("🔗 New connection from: {}", addr);
 
-        let target_port = &config
-            .read()
-            .await
-            .clone()
-            .active_branch
-            .or(Some(String::from("main")))
-            .map(async |branch_name| {
-                config
-                    .read()
-                    .await
-                    .clone()
-                    .branches
-                    .iter()
-                    .find(|b| b.name == branch_name)
-                    .map(|b| b.port)
-                    .unwrap()
-            })
-            .unwrap()
-            .await;
-
-        let target = format!("localhost:{}", target_port);
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(client, &target).await {
-                println!("❌ Connection error {}: {}", addr, e);
-            } else {
-                println!("✅ Connection {} finished - (target: {})", addr, target);
+        let (target, options) = {
+            let config = config.read().await;
+            (target_branch(&config), ProxyOptions::from(&*config))
+        };
+        let Some(target) = target else {
+            error!(
+                "Refusing connection from {}: neither the active branch nor main exists",
+                addr
+            );
+            continue;
+        };
+
+        let metrics = metrics.clone();
+        let handle = connections.spawn(async move {
+            let _permit = permit;
+            let _active = metrics.connection();
+            let started = Instant::now();
+            match handle_connection(client, target, options).await {
+                Ok(proxied) => {
+                    metrics.record(
+                        &proxied.branch,
+                        proxied.client_to_server,
+                        proxied.server_to_client,
+                    );
+                    info!(
+                        "Connection {} to branch {} ({}) closed after {:.1?}: {} bytes sent, {} bytes received",
+                        addr,
+                        proxied.branch,
+                        proxied.target,
+                        started.elapsed(),
+                        proxied.client_to_server,
+                        proxied.server_to_client
+                    );
+                    println!("✅ Connection {} finished - (target: {})", addr, proxied.target)
+                }
+                Err(e) => println!("❌ Connection error {}: {}", addr, e),
             }
         });
+        clients.insert(handle.id(), addr);
+    }
+
+    while let Some(joined) = connections.join_next_with_id().await {
+        report_connection(joined, &mut clients);
     }
+    tracker.abort();
+    runtime.remove();
+
+    let (sent, received) = metrics.bytes();
+    info!(
+        "Proxied {} connection(s): {} bytes sent, {} bytes received",
+        metrics.total_connections(),
+        sent,
+        received
+    );
 
     Ok(())
 }
 
-async fn handle_connection(mut client: TcpStream, target_addr: &str) -> io::Result<()> {
-    let mut server = TcpStream::connect(target_addr).await?;
+/// Keeps the branch recorded in the runtime state in step with the config, so
+/// that other commands see where new connections go.
+async fn track_active_branch(config: Arc<RwLock<Config>>, mut runtime: RuntimeState) {
+    let mut seen = config.read().await.active_branch.clone();
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        {
+            let config = config.read().await;
+            if config.active_branch == seen {
+                continue;
+            }
+            seen = config.active_branch.clone();
+            runtime.active_branch = target_branch(&config).map(|(name, _)| name);
+        }
+
+        info!(
+            "New connections now go to {}",
+            runtime.active_branch.as_deref().unwrap_or("no branch")
+        );
+        if let Err(e) = runtime.save() {
+            warn!("{}", e.chain());
+        }
+    }
+}
+
+/// Name and port of the active branch, falling back to main when no branch
+/// is active or the active one is gone (e.g. deleted while the proxy runs).
+fn target_branch(config: &Config) -> Option<(String, u16)> {
+    let find = |name: &str| {
+        config
+            .branches
+            .iter()
+            .find(|b| b.name == name)
+            .map(|b| (b.name.clone(), b.port))
+    };
+
+    match config.active_branch.as_deref() {
+        Some(active) => find(active).or_else(|| {
+            warn!("Active branch {} not found, falling back to main", active);
+            find("main")
+        }),
+        None => find("main"),
+    }
+}
 
-    let (mut client_read, mut client_write) = client.split();
-    let (mut server_read, mut server_write) = server.split();
+/// Binds the proxy listener. With `dual_stack`, an IPv6 socket is bound with
+/// `IPV6_V6ONLY` off so that it also accepts IPv4 clients.
+async fn bind_listener(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    if !dual_stack {
+        return TcpListener::bind(addr).await;
+    }
 
-    let client_to_server = io::copy(&mut client_read, &mut server_write);
-    let server_to_client = io::copy(&mut server_read, &mut client_write);
+    let socket = TcpSocket::new_v6()?;
+    let v6_only: nix::libc::c_int = 0;
+    // SAFETY: the descriptor is open for the lifetime of `socket` and the
+    // option value is a c_int as IPV6_V6ONLY expects
+    let ret = unsafe {
+        nix::libc::setsockopt(
+            socket.as_raw_fd(),
+            nix::libc::IPPROTO_IPV6,
+            nix::libc::IPV6_V6ONLY,
+            &v6_only as *const nix::libc::c_int as *const nix::libc::c_void,
+            std::mem::size_of::<nix::libc::c_int>() as nix::libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
 
-    Ok(())
+/// Logs connection handlers that panicked instead of letting the panic vanish
+/// with the task.
+fn report_connection(
+    joined: Result<(task::Id, ()), task::JoinError>,
+    clients: &mut HashMap<task::Id, SocketAddr>,
+) {
+    match joined {
+        Ok((id, ())) => {
+            clients.remove(&id);
+        }
+        Err(e) => {
+            let addr = clients.remove(&e.id());
+            if e.is_panic() {
+                error!("Connection handler for {:?} panicked: {}", addr, e);
+            } else {
+                debug!("Connection handler for {:?} was cancelled", addr);
+            }
+        }
+    }
+}
+
+/// Per-connection proxy settings, read from the config when a client connects.
+#[derive(Debug, Clone)]
+struct ProxyOptions {
+    buffer_size: usize,
+    use_splice: bool,
+    max_bytes_per_sec: Option<u64>,
+    auth: Option<ProxyAuth>,
+    /// Branch ports by name and the database inside each branch, when
+    /// routing by database name
+    routes: Option<(HashMap<String, u16>, String)>,
+}
+
+impl From<&Config> for ProxyOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            buffer_size: config.proxy_buffer_size,
+            use_splice: config.proxy_splice,
+            max_bytes_per_sec: config.max_bytes_per_sec,
+            auth: config.proxy_auth.clone(),
+            routes: config.proxy_route_by_database.then(|| {
+                (
+                    config
+                        .branches
+                        .iter()
+                        .map(|b| (b.name.clone(), b.port))
+                        .collect(),
+                    config.database_name(),
+                )
+            }),
+        }
+    }
+}
+
+/// A proxied connection, once closed.
+struct Proxied {
+    branch: String,
+    target: String,
+    client_to_server: u64,
+    server_to_client: u64,
+}
+
+/// Proxies `client` to a branch: the branch named by the database when
+/// routing by database name, the `default` branch and port otherwise.
+async fn handle_connection(
+    mut client: TcpStream,
+    default: (String, u16),
+    options: ProxyOptions,
+) -> io::Result<Proxied> {
+    let (mut branch, mut port) = default;
+
+    // Authenticate and route before touching the backend, then replay the
+    // startup packet without the proxy credentials
+    let startup = if options.auth.is_some() || options.routes.is_some() {
+        match pg_startup::read(&mut client).await? {
+            pg_startup::Startup::Cancel(packet) => {
+                // A cancel request carries no database, so there is no telling
+                // which branch a routed session went to: send it to all of them
+                if let Some((branches, _)) = &options.routes {
+                    return Ok(broadcast_cancel(&packet, branches, branch).await);
+                }
+                Some(packet)
+            }
+            pg_startup::Startup::Parameters(mut params) => {
+                if let Some(auth) = &options.auth {
+                    proxy_auth::check(&mut client, auth, &mut params).await?;
+                }
+                if let Some((branches, database)) = &options.routes
+                    && let Some(routed) =
+                        pg_startup::route_by_database(&mut params, branches, database)
+                    && let Some((name, _)) = branches.iter().find(|(_, p)| **p == routed)
+                {
+                    branch = name.clone();
+                    port = routed;
+                }
+                Some(pg_startup::startup_message(&params))
+            }
+        }
+    } else {
+        None
+    };
+
+    let target_addr = format!("localhost:{}", port);
+    let mut server = TcpStream::connect(&target_addr).await?;
+    if let Some(startup) = startup {
+        server.write_all(&startup).await?;
+    }
+
+    let (client_read, mut client_write) = client.split();
+    let (server_read, mut server_write) = server.split();
+
+    let client_to_server = pump(client_read, &mut server_write, &options);
+    let server_to_client = pump(server_read, &mut client_write, &options);
+
+    let (client_to_server, server_to_client) =
+        tokio::try_join!(client_to_server, server_to_client)?;
+
+    Ok(Proxied {
+        branch,
+        target: target_addr,
+        client_to_server,
+        server_to_client,
+    })
+}
+
+/// Sends a CancelRequest to every branch port. Backends ignore a key they did
+/// not issue, so only the branch running the session acts on it.
+async fn broadcast_cancel(
+    packet: &[u8],
+    branches: &HashMap<String, u16>,
+    branch: String,
+) -> Proxied {
+    let mut sent = 0;
+    for port in branches.values() {
+        let result = async {
+            let mut server = TcpStream::connect(format!("localhost:{}", port)).await?;
+            server.write_all(packet).await
+        }
+        .await;
+        match result {
+            Ok(()) => sent += 1,
+            Err(e) => debug!("Could not forward cancel request to port {}: {}", port, e),
+        }
+    }
+
+    Proxied {
+        branch,
+        target: format!("{} of {} branch ports", sent, branches.len()),
+        client_to_server: (packet.len() * sent) as u64,
+        server_to_client: 0,
+    }
+}
+
+/// Copies one direction of a proxied connection, through `splice(2)` when
+/// enabled and supported, through a `buffer_size` buffer otherwise. A rate
+/// limit always goes through the buffer, as spliced bytes can't be metered.
+async fn pump(
+    read: ReadHalf<'_>,
+    write: &mut WriteHalf<'_>,
+    options: &ProxyOptions,
+) -> io::Result<u64> {
+    let &ProxyOptions {
+        buffer_size,
+        use_splice,
+        max_bytes_per_sec,
+        ..
+    } = options;
+
+    if let Some(rate) = max_bytes_per_sec {
+        let throttled = Throttled::new(read, rate);
+        return io::copy_buf(&mut BufReader::with_capacity(buffer_size, throttled), write).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    if use_splice {
+        match splice::copy(read.as_ref(), write.as_ref(), buffer_size).await {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                debug!("splice not supported ({}), falling back to copy", e);
+            }
+            result => return result,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = use_splice;
+
+    io::copy_buf(&mut BufReader::with_capacity(buffer_size, read), write).await
 }
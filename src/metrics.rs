@@ -0,0 +1,101 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Running totals of the proxy, shared by every connection.
+#[derive(Debug, Default)]
+pub struct ProxyMetrics {
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    bytes_client_to_server: AtomicU64,
+    bytes_server_to_client: AtomicU64,
+    /// `(client_to_server, server_to_client)` bytes by branch name
+    branch_bytes: Mutex<BTreeMap<String, (u64, u64)>>,
+}
+
+impl ProxyMetrics {
+    /// Counts a new connection as active until the returned guard is dropped.
+    pub fn connection(self: &Arc<Self>) -> ActiveConnection {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone())
+    }
+
+    /// Adds the bytes of a closed connection to `branch` to the totals.
+    pub fn record(&self, branch: &str, client_to_server: u64, server_to_client: u64) {
+        self.bytes_client_to_server
+            .fetch_add(client_to_server, Ordering::Relaxed);
+        self.bytes_server_to_client
+            .fetch_add(server_to_client, Ordering::Relaxed);
+
+        let mut branch_bytes = self.branch_bytes.lock().unwrap_or_else(|e| e.into_inner());
+        let bytes = branch_bytes.entry(branch.to_string()).or_default();
+        bytes.0 += client_to_server;
+        bytes.1 += server_to_client;
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    /// Bytes proxied so far as `(client_to_server, server_to_client)`.
+    pub fn bytes(&self) -> (u64, u64) {
+        (
+            self.bytes_client_to_server.load(Ordering::Relaxed),
+            self.bytes_server_to_client.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Bytes proxied so far by branch, sorted by branch name.
+    pub fn branch_bytes(&self) -> Vec<(String, u64, u64)> {
+        self.branch_bytes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(branch, (sent, received))| (branch.clone(), *sent, *received))
+            .collect()
+    }
+}
+
+/// Keeps a connection counted as active, even if its task panics.
+pub struct ActiveConnection(Arc<ProxyMetrics>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_counts_and_bytes() {
+        let metrics = Arc::new(ProxyMetrics::default());
+
+        let first = metrics.connection();
+        let second = metrics.connection();
+        assert_eq!(metrics.active_connections(), 2);
+        drop(first);
+        metrics.record("main", 10, 200);
+        drop(second);
+        metrics.record("feature", 5, 0);
+
+        assert_eq!(metrics.active_connections(), 0);
+        assert_eq!(metrics.total_connections(), 2);
+        assert_eq!(metrics.bytes(), (15, 200));
+        assert_eq!(
+            metrics.branch_bytes(),
+            vec![("feature".to_string(), 5, 0), ("main".to_string(), 10, 200)]
+        );
+    }
+}
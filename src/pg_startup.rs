@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+// https://www.postgresql.org/docs/current/protocol-message-formats.html
+const PROTOCOL_V3: i32 = 196608;
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+const CANCEL_REQUEST: i32 = 80877102;
+/// Same limit as the server's MAX_STARTUP_PACKET_LENGTH
+const MAX_STARTUP_LENGTH: usize = 10_000;
+
+/// First message of a client connection, once encryption requests are out
+/// of the way.
+pub enum Startup {
+    /// `name`/`value` parameters of a StartupMessage
+    Parameters(Vec<(String, String)>),
+    /// CancelRequest packet, forwarded as is
+    Cancel(Vec<u8>),
+}
+
+/// Reads the client's startup sequence. SSL and GSS encryption requests are
+/// declined so that the StartupMessage can be read in clear.
+pub async fn read<S>(client: &mut S) -> io::Result<Startup>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let packet = read_packet(client).await?;
+        let code = i32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+
+        match code {
+            SSL_REQUEST | GSSENC_REQUEST => {
+                debug!("Declining encryption request to read the startup message");
+                client.write_all(b"N").await?;
+            }
+            CANCEL_REQUEST => return Ok(Startup::Cancel(packet)),
+            PROTOCOL_V3 => return Ok(Startup::Parameters(parse_parameters(&packet[8..])?)),
+            other => {
+                reject(
+                    client,
+                    "0A000",
+                    &format!("unsupported frontend protocol {}", other),
+                )
+                .await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported startup code {}", other),
+                ));
+            }
+        }
+    }
+}
+
+async fn read_packet<S: AsyncRead + Unpin>(client: &mut S) -> io::Result<Vec<u8>> {
+    let length = client.read_i32().await?;
+    if length < 8 || length as usize > MAX_STARTUP_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid startup packet length {}", length),
+        ));
+    }
+
+    let mut packet = vec![0u8; length as usize];
+    packet[..4].copy_from_slice(&length.to_be_bytes());
+    client.read_exact(&mut packet[4..]).await?;
+    Ok(packet)
+}
+
+/// Parses the `name\0value\0...\0` list of a startup message.
+fn parse_parameters(body: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed startup parameters");
+
+    let mut fields = body
+        .split(|b| *b == 0)
+        .map(|field| String::from_utf8(field.to_vec()).map_err(|_| invalid()));
+    let mut params = Vec::new();
+    loop {
+        let name = fields.next().ok_or_else(invalid)??;
+        if name.is_empty() {
+            return Ok(params);
+        }
+        let value = fields.next().ok_or_else(invalid)??;
+        params.push((name, value));
+    }
+}
+
+/// Encodes a StartupMessage to replay to the backend.
+pub fn startup_message(params: &[(String, String)]) -> Vec<u8> {
+    let mut body = PROTOCOL_V3.to_be_bytes().to_vec();
+    for (name, value) in params {
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+
+    let mut packet = ((body.len() + 4) as i32).to_be_bytes().to_vec();
+    packet.extend_from_slice(&body);
+    packet
+}
+
+/// Sends a FATAL `ErrorResponse` with the given SQLSTATE code.
+pub async fn reject<S: AsyncWrite + Unpin>(
+    client: &mut S,
+    code: &str,
+    message: &str,
+) -> io::Result<()> {
+    let mut fields = Vec::new();
+    for (tag, value) in [
+        (b'S', "FATAL"),
+        (b'V', "FATAL"),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        fields.push(tag);
+        fields.extend_from_slice(value.as_bytes());
+        fields.push(0);
+    }
+    fields.push(0);
+
+    let mut packet = vec![b'E'];
+    packet.extend_from_slice(&((fields.len() + 4) as i32).to_be_bytes());
+    packet.extend_from_slice(&fields);
+    client.write_all(&packet).await?;
+    client.flush().await
+}
+
+/// Port of the branch named by the `database` parameter, if any. The
+/// parameter is then rewritten to `database`, the name of the database
+/// inside every branch container.
+pub fn route_by_database(
+    params: &mut [(String, String)],
+    branches: &HashMap<String, u16>,
+    database: &str,
+) -> Option<u16> {
+    let (_, value) = params.iter_mut().find(|(name, _)| name == "database")?;
+    let port = *branches.get(value.as_str())?;
+    debug!("Routing database {} to port {}", value, port);
+    *value = database.to_string();
+    Some(port)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// SSLRequest followed by a StartupMessage with `params`.
+    pub(crate) fn ssl_then_startup(params: &[(String, String)]) -> Vec<u8> {
+        let mut packets = 8i32.to_be_bytes().to_vec();
+        packets.extend_from_slice(&SSL_REQUEST.to_be_bytes());
+        packets.extend_from_slice(&startup_message(params));
+        packets
+    }
+
+    #[tokio::test]
+    async fn test_read_declines_ssl_and_parses_parameters() {
+        let sent = params(&[("user", "postgres"), ("database", "feature_x")]);
+
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        client.write_all(&ssl_then_startup(&sent)).await.unwrap();
+
+        let Startup::Parameters(received) = read(&mut proxy).await.unwrap() else {
+            panic!("expected a startup message");
+        };
+        assert_eq!(client.read_u8().await.unwrap(), b'N');
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn test_route_by_database() {
+        let branches = HashMap::from([("feature_x".to_string(), 7001)]);
+
+        let mut routed = params(&[("user", "postgres"), ("database", "feature_x")]);
+        assert_eq!(
+            route_by_database(&mut routed, &branches, "dbranch"),
+            Some(7001)
+        );
+        assert_eq!(
+            routed,
+            params(&[("user", "postgres"), ("database", "dbranch")])
+        );
+
+        let mut unknown = params(&[("user", "postgres"), ("database", "other")]);
+        assert_eq!(route_by_database(&mut unknown, &branches, "dbranch"), None);
+        assert_eq!(unknown[1].1, "other");
+    }
+}
@@ -0,0 +1,117 @@
+use tokio::io::{self, AsyncWrite};
+
+use crate::{config::ProxyAuth, pg_startup};
+
+/// Custom setting carrying the proxy token in the `options` startup parameter,
+/// e.g. `PGOPTIONS="-c dbranch.token=secret"`.
+pub const TOKEN_SETTING: &str = "dbranch.token";
+
+/// Checks the StartupMessage `params` against `auth` and removes the token
+/// from them. A client without a valid token gets a Postgres `ErrorResponse`
+/// and a `PermissionDenied` error is returned.
+pub async fn check<S>(
+    client: &mut S,
+    auth: &ProxyAuth,
+    params: &mut Vec<(String, String)>,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let token = take_token(params);
+    if auth.accepts(token.as_deref()) {
+        return Ok(());
+    }
+
+    pg_startup::reject(client, "28P01", "dbranch proxy authentication failed").await?;
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "proxy authentication failed",
+    ))
+}
+
+/// Removes the token setting from the `options` parameter, dropping the
+/// parameter when nothing else is left in it.
+fn take_token(params: &mut Vec<(String, String)>) -> Option<String> {
+    let index = params.iter().position(|(name, _)| name == "options")?;
+
+    let mut token = None;
+    let mut kept = Vec::new();
+    let mut words = params[index].1.split_whitespace().peekable();
+    while let Some(word) = words.next() {
+        let setting = match word {
+            "-c" => match words.peek().and_then(|next| setting_value(next)) {
+                Some(value) => {
+                    words.next();
+                    Some(value)
+                }
+                None => None,
+            },
+            _ => word
+                .strip_prefix("-c")
+                .or_else(|| word.strip_prefix("--"))
+                .and_then(setting_value),
+        };
+
+        match setting {
+            Some(value) => token = Some(value.to_string()),
+            None => kept.push(word),
+        }
+    }
+
+    if kept.is_empty() {
+        params.remove(index);
+    } else {
+        params[index].1 = kept.join(" ");
+    }
+    token
+}
+
+fn setting_value(setting: &str) -> Option<&str> {
+    setting
+        .strip_prefix(TOKEN_SETTING)
+        .and_then(|rest| rest.strip_prefix('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pg_startup::{
+        Startup,
+        tests::{params, ssl_then_startup},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_check_strips_token_and_rejects_bad_ones() {
+        let auth = ProxyAuth::Token {
+            token: "secret".to_string(),
+        };
+
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        client
+            .write_all(&ssl_then_startup(&params(&[
+                ("user", "postgres"),
+                ("options", "-c dbranch.token=secret -c search_path=app"),
+            ])))
+            .await
+            .unwrap();
+
+        let Startup::Parameters(mut received) = pg_startup::read(&mut proxy).await.unwrap() else {
+            panic!("expected a startup message");
+        };
+        check(&mut proxy, &auth, &mut received).await.unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), b'N');
+        assert_eq!(
+            received,
+            params(&[("user", "postgres"), ("options", "-c search_path=app")])
+        );
+
+        let mut wrong = params(&[("user", "postgres"), ("options", "--dbranch.token=nope")]);
+        let err = check(&mut proxy, &auth, &mut wrong).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(client.read_u8().await.unwrap(), b'E');
+
+        let mut missing = params(&[("user", "postgres")]);
+        assert!(check(&mut proxy, &auth, &mut missing).await.is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    fs::{self, File},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use rustix::process::{Pid, Signal, kill_process, test_kill_process};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{config::config_path, error::AppError};
+
+pub const RUNTIME_FILE: &str = ".dbranch.runtime.json";
+
+/// Signal asking a running proxy to close its client connections.
+pub const BOUNCE_SIGNAL: Signal = Signal::USR1;
+
+/// What a running `dbranch start` serves, written next to the config so that
+/// other commands can tell whether a proxy is up.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RuntimeState {
+    pub pid: u32,
+    pub proxy_addr: SocketAddr,
+    /// Branch new connections are routed to; open connections stay where
+    /// they were routed when they were accepted
+    pub active_branch: Option<String>,
+    pub started_at: DateTime<Utc>,
+}
+
+pub fn runtime_path() -> PathBuf {
+    config_path()
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(RUNTIME_FILE)
+}
+
+impl RuntimeState {
+    /// State of the proxy serving the project, `None` when none is running.
+    /// A file left behind by a proxy that died is ignored.
+    pub fn load() -> Option<Self> {
+        read(&runtime_path())
+    }
+
+    /// Writes the state to a temporary file renamed over the target, so that
+    /// `load` never sees a truncated file while the proxy is running.
+    pub fn save(&self) -> Result<(), AppError> {
+        let path = runtime_path();
+        debug!("Saving runtime state to {:?}", path);
+        let tmp_path = path.with_file_name(format!("{}.tmp", RUNTIME_FILE));
+
+        let written = File::create(&tmp_path)
+            .map_err(|e| AppError::FileSystem {
+                message: format!("Failed to create runtime state {:?}", tmp_path),
+                source: Some(e.into()),
+            })
+            .and_then(|file| {
+                serde_json::to_writer_pretty(file, self).map_err(|e| AppError::FileSystem {
+                    message: format!("Failed to write runtime state {:?}", tmp_path),
+                    source: Some(e.into()),
+                })
+            });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        fs::rename(&tmp_path, &path).map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            AppError::FileSystem {
+                message: format!("Failed to replace runtime state {:?}", path),
+                source: Some(e.into()),
+            }
+        })
+    }
+
+    /// Asks the proxy to close its open client connections, so that clients
+    /// reconnect and are routed to the active branch.
+    pub fn bounce(&self) -> Result<(), AppError> {
+        debug!("Sending {:?} to proxy {}", BOUNCE_SIGNAL, self.pid);
+        let pid = to_pid(self.pid).ok_or(AppError::Internal {
+            message: format!("Invalid proxy pid {}", self.pid),
+        })?;
+
+        kill_process(pid, BOUNCE_SIGNAL).map_err(|e| AppError::Internal {
+            message: format!("Failed to signal proxy {}: {}", self.pid, e),
+        })
+    }
+
+    /// Removes the state file, if it still belongs to this process.
+    pub fn remove(&self) {
+        let path = runtime_path();
+        if read(&path).is_some_and(|state| state.pid == self.pid)
+            && let Err(e) = fs::remove_file(&path)
+        {
+            debug!("Failed to remove runtime state {:?}: {}", path, e);
+        }
+    }
+}
+
+fn read(path: &Path) -> Option<RuntimeState> {
+    let content = fs::read_to_string(path).ok()?;
+    let state: RuntimeState = serde_json::from_str(&content)
+        .inspect_err(|e| debug!("Ignoring invalid runtime state {:?}: {}", path, e))
+        .ok()?;
+
+    if is_alive(state.pid) {
+        Some(state)
+    } else {
+        debug!("Ignoring runtime state of exited process {}", state.pid);
+        None
+    }
+}
+
+/// Whether process `pid` exists, by sending it signal 0.
+fn is_alive(pid: u32) -> bool {
+    let Some(pid) = to_pid(pid) else {
+        return false;
+    };
+    match test_kill_process(pid) {
+        Ok(()) => true,
+        // Owned by another user, but there
+        Err(e) => e == rustix::io::Errno::PERM,
+    }
+}
+
+fn to_pid(pid: u32) -> Option<Pid> {
+    i32::try_from(pid).ok().and_then(Pid::from_raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_ignores_exited_process() {
+        let dir = std::env::temp_dir().join(format!("dbranch-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(RUNTIME_FILE);
+
+        let mut state = RuntimeState {
+            pid: std::process::id(),
+            proxy_addr: "127.0.0.1:5432".parse().unwrap(),
+            active_branch: Some("feature".to_string()),
+            started_at: Utc::now(),
+        };
+        fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(read(&path), Some(state.clone()));
+
+        // Beyond the default pid_max, so never a live process
+        state.pid = i32::MAX as u32;
+        fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(read(&path), None);
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(read(&path), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
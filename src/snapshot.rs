@@ -1,66 +1,235 @@
 use tracing::debug;
 
-use std::{fs, path::Path};
+use rustix::fs::{AtFlags, CWD, Gid, Timespec, Timestamps, Uid, chownat, utimensat};
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
 
 use crate::{
     copy_ref::{CopyRef, CopyRefOperator},
     error::AppError,
+    fiemap::{exclusive_size, get_physical_extents},
 };
 
+/// Removes a directory of reflinked data and returns the bytes actually
+/// freed: extents still referenced from one of `keep` stay on disk.
+pub fn remove(path: &Path, keep: &[PathBuf]) -> Result<u64, AppError> {
+    if !path.exists() {
+        return Err(AppError::FileNotFound {
+            path: path.to_string_lossy().to_string(),
+        });
+    }
+
+    let extents = get_physical_extents(path);
+    let kept: Vec<(u64, u64)> = keep.iter().flat_map(|p| get_physical_extents(p)).collect();
+    let reclaimed = exclusive_size(&extents, &kept);
+    debug!("Removing {:?}, {} exclusive bytes", path, reclaimed);
+
+    fs::remove_dir_all(path).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to remove directory {:?}", path),
+        source: Some(e.into()),
+    })?;
+
+    Ok(reclaimed)
+}
+
 pub fn snapshot(src: &Path, dst: &Path) -> Result<(), AppError> {
+    snapshot_with(src, dst, &CopyRefOperator::new())
+}
+
+/// Like `snapshot`, copying each file with `operator`.
+pub fn snapshot_with(src: &Path, dst: &Path, operator: &CopyRefOperator) -> Result<(), AppError> {
     debug!("Creating snapshot from {:?} to {:?}", src, dst);
     let src = src.to_path_buf();
     let dst = dst.to_path_buf();
 
+    if !src.is_dir() {
+        return Err(AppError::FileNotFound {
+            path: src.to_string_lossy().to_string(),
+        });
+    }
+
     if !dst.exists() {
         fs::create_dir_all(&dst).map_err(|e| AppError::FileSystem {
-            message: format!("Failed to create directory {:?}: {}", dst, e),
+            message: format!("Failed to create directory {:?}", dst),
+            source: Some(e.into()),
         })?;
     }
 
-    for entry in fs::read_dir(src.clone())
-        .map_err(|e| AppError::FileSystem {
-            message: format!("Failed to read directory {:?}: {}", src, e),
-        })
-        .unwrap()
-    {
+    for entry in fs::read_dir(src.clone()).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to read directory {:?}", src),
+        source: Some(e.into()),
+    })? {
         match entry {
             Ok(entry) => {
-                if entry.path().is_dir() {
+                let file_type = entry.file_type().map_err(|e| AppError::FileSystem {
+                    message: format!("Failed to read the type of {:?}", entry.path()),
+                    source: Some(e.into()),
+                })?;
+
+                if file_type.is_symlink() {
+                    // Recreated as is rather than followed, which could copy
+                    // data from outside the tree or never end on a loop
+                    let new_dst = dst.join(entry.file_name());
+                    let target = fs::read_link(entry.path()).map_err(|e| AppError::FileSystem {
+                        message: format!("Failed to read symlink {:?}", entry.path()),
+                        source: Some(e.into()),
+                    })?;
+                    std::os::unix::fs::symlink(&target, &new_dst).map_err(|e| {
+                        AppError::FileSystem {
+                            message: format!("Failed to create symlink {:?}", new_dst),
+                            source: Some(e.into()),
+                        }
+                    })?;
+                    copy_metadata(&entry.path(), &new_dst)?;
+                } else if file_type.is_dir() {
                     let new_dst = dst.join(entry.file_name());
                     fs::create_dir_all(&new_dst).map_err(|e| AppError::FileSystem {
-                        message: format!("Failed to create directory {:?}: {}", new_dst, e),
+                        message: format!("Failed to create directory {:?}", new_dst),
+                        source: Some(e.into()),
                     })?;
-                    snapshot(&entry.path(), &new_dst)?;
+                    snapshot_with(&entry.path(), &new_dst, operator)?;
                 } else {
-                    let src_file =
-                        fs::File::open(entry.path()).map_err(|e| AppError::FileSystem {
-                            message: format!(
-                                "Failed to open source file {:?}: {}",
-                                entry.path(),
-                                e
-                            ),
-                        })?;
-                    let dst_file_path = dst.join(entry.file_name());
-                    let dst_file =
-                        fs::File::create(&dst_file_path).map_err(|e| AppError::FileSystem {
-                            message: format!(
-                                "Failed to create destination file {:?}: {}",
-                                dst_file_path, e
-                            ),
-                        })?;
-
-                    let operator = CopyRefOperator::new();
-                    operator.copy_ref(&src_file, &dst_file)?;
+                    let new_dst = dst.join(entry.file_name());
+                    operator.copy_ref_path(&entry.path(), &new_dst)?;
+                    copy_metadata(&entry.path(), &new_dst)?;
                 }
             }
             Err(err) => {
                 return Err(AppError::FileSystem {
-                    message: format!("Failed to read directory entry: {}", err),
+                    message: "Failed to read directory entry".to_string(),
+                    source: Some(err.into()),
                 });
             }
         }
     }
 
-    Ok(())
+    // Last, as creating the entries above bumps the directory's mtime
+    copy_metadata(&src, &dst)
+}
+
+/// Copies the ownership, mode and timestamps of `src` onto `dst`.
+///
+/// Postgres refuses to start on a data directory it doesn't own or whose
+/// mode is too permissive, so a snapshot must keep them as they were.
+fn copy_metadata(src: &Path, dst: &Path) -> Result<(), AppError> {
+    let fs_error = |action: &str, e: std::io::Error| AppError::FileSystem {
+        message: format!("Failed to {} of {:?}", action, dst),
+        source: Some(e.into()),
+    };
+
+    let meta = fs::symlink_metadata(src).map_err(|e| AppError::FileSystem {
+        message: format!("Failed to read metadata of {:?}", src),
+        source: Some(e.into()),
+    })?;
+
+    // Before the mode, as changing the owner clears setuid/setgid bits.
+    // Skipped when unchanged, so unprivileged runs work on their own files
+    let current = fs::symlink_metadata(dst).map_err(|e| fs_error("read metadata", e))?;
+    if (current.uid(), current.gid()) != (meta.uid(), meta.gid()) {
+        chownat(
+            CWD,
+            dst,
+            Some(Uid::from_raw(meta.uid())),
+            Some(Gid::from_raw(meta.gid())),
+            AtFlags::SYMLINK_NOFOLLOW,
+        )
+        .map_err(|e| fs_error("change the owner", e.into()))?;
+    }
+
+    // Would apply to the target, and a symlink's own mode is unused anyway
+    if !meta.file_type().is_symlink() {
+        fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode()))
+            .map_err(|e| fs_error("set the mode", e))?;
+    }
+
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: meta.atime(),
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        last_modification: Timespec {
+            tv_sec: meta.mtime(),
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    };
+    utimensat(CWD, dst, &times, AtFlags::SYMLINK_NOFOLLOW)
+        .map_err(|e| fs_error("set the timestamps", e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_preserves_modes_and_mtime() {
+        let root = std::env::temp_dir().join(format!("dbranch-snapshot-{}", uuid::Uuid::new_v4()));
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(src.join("global")).unwrap();
+        fs::write(src.join("global/pg_control"), b"control").unwrap();
+        fs::write(src.join("postgresql.conf"), b"port = 5432").unwrap();
+
+        fs::set_permissions(
+            src.join("global/pg_control"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        fs::set_permissions(
+            src.join("postgresql.conf"),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+        fs::set_permissions(src.join("global"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o750)).unwrap();
+
+        snapshot(&src, &dst).unwrap();
+
+        for path in ["", "global", "global/pg_control", "postgresql.conf"] {
+            let (expected, actual) = (
+                fs::metadata(src.join(path)).unwrap(),
+                fs::metadata(dst.join(path)).unwrap(),
+            );
+            assert_eq!(expected.mode(), actual.mode(), "mode of {:?}", path);
+            assert_eq!(
+                (expected.uid(), expected.gid()),
+                (actual.uid(), actual.gid()),
+                "owner of {:?}",
+                path
+            );
+            assert_eq!(
+                (expected.mtime(), expected.mtime_nsec()),
+                (actual.mtime(), actual.mtime_nsec()),
+                "mtime of {:?}",
+                path
+            );
+        }
+        assert_eq!(fs::read(dst.join("global/pg_control")).unwrap(), b"control");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_recreates_symlinks() {
+        let root = std::env::temp_dir().join(format!("dbranch-snapshot-{}", uuid::Uuid::new_v4()));
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(src.join("pg_tblspc")).unwrap();
+        fs::write(root.join("outside"), b"outside").unwrap();
+        std::os::unix::fs::symlink("..", src.join("pg_tblspc/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("outside"), src.join("pg_tblspc/16384")).unwrap();
+
+        snapshot(&src, &dst).unwrap();
+
+        assert_eq!(
+            fs::read_link(dst.join("pg_tblspc/loop")).unwrap(),
+            Path::new("..")
+        );
+        assert_eq!(
+            fs::read_link(dst.join("pg_tblspc/16384")).unwrap(),
+            root.join("outside")
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,129 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use tokio::{io::Interest, net::TcpStream};
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0 as RawFd; 2];
+    let ret = unsafe {
+        nix::libc::pipe2(
+            fds.as_mut_ptr(),
+            nix::libc::O_NONBLOCK | nix::libc::O_CLOEXEC,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 succeeded, so both descriptors are open and owned by us
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // https://man7.org/linux/man-pages/man2/splice.2.html
+    let ret = unsafe {
+        nix::libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            nix::libc::SPLICE_F_MOVE | nix::libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Moves bytes from `from` to `to` until EOF without copying them through
+/// userspace, going socket -> pipe -> socket. Returns `ErrorKind::Unsupported`
+/// when the kernel refuses to splice these sockets before anything was moved,
+/// so the caller can fall back to a regular copy.
+pub async fn copy(from: &TcpStream, to: &TcpStream, chunk_size: usize) -> io::Result<u64> {
+    let (pipe_read, pipe_write) = pipe()?;
+    let mut total = 0u64;
+
+    loop {
+        from.readable().await?;
+        let read = match from.try_io(Interest::READABLE, || {
+            splice(from.as_raw_fd(), pipe_write.as_raw_fd(), chunk_size)
+        }) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) if total == 0 && is_unsupported(&e) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, e));
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Drain the pipe completely so the next read always has room
+        let mut pending = read;
+        while pending > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice(pipe_read.as_raw_fd(), to.as_raw_fd(), pending)
+            }) {
+                Ok(written) => pending -= written,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        total += read as u64;
+    }
+
+    Ok(total)
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(nix::libc::EINVAL) | Some(nix::libc::ENOSYS) | Some(nix::libc::EOPNOTSUPP)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_splice_copy_between_sockets() {
+        let (mut source, from) = socket_pair().await;
+        let (to, mut sink) = socket_pair().await;
+
+        let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+
+        let writer = tokio::spawn(async move {
+            source.write_all(&payload).await.unwrap();
+            // Closing the source is the EOF that ends the pump
+        });
+        let pump = tokio::spawn(async move { copy(&from, &to, 16 * 1024).await.unwrap() });
+
+        let mut received = Vec::new();
+        let reader = tokio::spawn(async move {
+            sink.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        writer.await.unwrap();
+        assert_eq!(pump.await.unwrap(), expected.len() as u64);
+        // The pump dropped its sockets, closing the sink side
+        assert_eq!(reader.await.unwrap(), expected);
+    }
+}
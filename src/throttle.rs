@@ -0,0 +1,114 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncRead, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Token bucket refilled at `rate` bytes per second, holding at most one
+/// second worth of tokens.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Time until at least one whole byte can be read.
+    fn wait_time(&self) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// Reader that yields at most `rate` bytes per second from `inner`.
+pub struct Throttled<R> {
+    inner: R,
+    bucket: TokenBucket,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, bytes_per_sec: u64) -> Self {
+        Self {
+            inner,
+            bucket: TokenBucket::new(bytes_per_sec),
+            sleep: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(sleep) = this.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+
+            this.bucket.refill();
+            if this.bucket.tokens >= 1.0 {
+                break;
+            }
+            this.sleep = Some(Box::pin(tokio::time::sleep(this.bucket.wait_time())));
+        }
+
+        let allowed = (this.bucket.tokens as usize).min(buf.remaining());
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+
+        // SAFETY: `limited` views the unfilled part of `buf`, and the inner
+        // reader initialized the first `read` bytes of it
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        this.bucket.tokens -= read as f64;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_throttled_read_respects_rate() {
+        let payload = vec![7u8; 1500];
+        let mut reader = Throttled::new(payload.as_slice(), 1000);
+
+        let started = std::time::Instant::now();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+
+        // The first second worth is available right away, the rest is paced
+        assert_eq!(received, payload);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::HashMap, path::Path};
+
+use regex::Regex;
+use tracing::debug;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub severity: Severity,
+    pub subject: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    fn error(&mut self, subject: &str, message: String) {
+        self.problems.push(Problem {
+            severity: Severity::Error,
+            subject: subject.to_string(),
+            message,
+        });
+    }
+
+    fn warning(&mut self, subject: &str, message: String) {
+        self.problems.push(Problem {
+            severity: Severity::Warning,
+            subject: subject.to_string(),
+            message,
+        });
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error)
+            .count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.problems
+            .iter()
+            .filter(|p| p.severity == Severity::Warning)
+            .count()
+    }
+}
+
+pub fn validate_project(config: &Config) -> ValidationReport {
+    debug!("Validating project '{}'", config.name);
+    let mut report = ValidationReport::default();
+
+    if let Err(e) = config.validate() {
+        report.error("config", e.to_string());
+    }
+
+    if !config.branches.iter().any(|b| b.is_main) {
+        report.error("config", "No main branch is defined".to_string());
+    }
+
+    if let Some(active) = &config.active_branch
+        && !config.branches.iter().any(|b| &b.name == active)
+    {
+        report.error(
+            "config",
+            format!("Active branch '{}' does not exist", active),
+        );
+    }
+
+    // Docker accepts [a-zA-Z0-9][a-zA-Z0-9_.-]* as container names
+    let container_re = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_.-]*$").unwrap();
+
+    let mut ports: HashMap<u16, Vec<&str>> = HashMap::new();
+    for branch in &config.branches {
+        ports.entry(branch.port).or_default().push(&branch.name);
+
+        if branch.port == config.proxy_port || branch.port == config.api_port {
+            report.error(
+                &branch.name,
+                format!("Port {} collides with the proxy/api port", branch.port),
+            );
+        }
+
+        if branch.port < config.port_min || branch.port > config.port_max {
+            report.warning(
+                &branch.name,
+                format!(
+                    "Port {} is outside the configured range {}-{}",
+                    branch.port, config.port_min, config.port_max
+                ),
+            );
+        }
+
+        let container_name = config.container_name(&branch.name);
+        if !container_re.is_match(&container_name) {
+            report.error(
+                &branch.name,
+                format!(
+                    "Container name '{}' is not valid for Docker",
+                    container_name
+                ),
+            );
+        }
+
+        if branch.is_main {
+            continue;
+        }
+
+        let data_dir = Path::new(&config.mount_point)
+            .join(&config.name)
+            .join(&branch.name)
+            .join("data");
+
+        if !data_dir.is_dir() {
+            report.error(
+                &branch.name,
+                format!("Data directory {:?} is missing", data_dir),
+            );
+        } else if !data_dir.join("pgdata").join("PG_VERSION").is_file() {
+            report.error(
+                &branch.name,
+                format!("Data directory {:?} has no PG_VERSION", data_dir),
+            );
+        }
+    }
+
+    let mut collisions: Vec<(&u16, &Vec<&str>)> =
+        ports.iter().filter(|(_, names)| names.len() > 1).collect();
+    collisions.sort_by_key(|(port, _)| **port);
+    for (port, names) in collisions {
+        report.error(
+            "config",
+            format!("Port {} is shared by branches {}", port, names.join(", ")),
+        );
+    }
+
+    report
+}